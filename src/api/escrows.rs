@@ -0,0 +1,358 @@
+use std::{io::Error, str::FromStr, sync::Arc};
+
+use actix_web::{get, post, web, HttpResponse, Responder};
+use bitcoin::{consensus::encode::serialize_hex, Amount, FeeRate, OutPoint, TxOut, Txid};
+use log::info;
+use serde_json::json;
+use tokio::{join, sync::Mutex, task::spawn_blocking};
+
+use crate::{
+    blockchain::{
+        milestone::{self, MilestoneEscrow},
+        secret, validation,
+    },
+    settings::get_settings,
+    storage::{
+        cache::CacheClient,
+        escrow::{self, Escrow},
+    },
+};
+
+use super::{
+    guardian::{stored_guardian, NewGuardian},
+    schemas::{
+        FundMilestoneRequest, MilestoneMnemonicRequest, NewEscrowRequest, ReleaseMilestoneRequest,
+    },
+};
+
+/// Load a stored escrow, or the error response if it cannot be loaded
+async fn stored_escrow(cache: &CacheClient, escrow_id: &str) -> Result<Escrow, HttpResponse> {
+    match escrow::load_escrow(cache, escrow_id).await {
+        Ok(Some(escrow)) => Ok(escrow),
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "error": "Escrow not found"
+        }))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error loading escrow: {}", e)
+        }))),
+    }
+}
+
+/// Escrow record with its progress, as returned by the endpoints
+fn escrow_response(escrow: &Escrow) -> HttpResponse {
+    let (total_amount, released_amount) = match (
+        escrow.escrow.total_amount(),
+        escrow.escrow.released_amount(),
+    ) {
+        (Ok(total), Ok(released)) => (total, released),
+        (Err(e), _) | (_, Err(e)) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "error": format!("Invalid escrow: {}", e)
+            }))
+        }
+    };
+
+    let mut escrow_data = json!(escrow);
+    escrow_data["total_amount"] = json!(total_amount.to_sat());
+    escrow_data["released_amount"] = json!(released_amount.to_sat());
+    escrow_data["next_milestone"] = json!(escrow
+        .escrow
+        .next_milestone()
+        .map(|milestone| milestone.index));
+
+    HttpResponse::Ok().json(escrow_data)
+}
+
+/// Create a milestone escrow guarded like a new locker. The master mnemonic
+/// is only returned here, the milestone mnemonics are derived from it.
+#[post("/escrows/new/")]
+async fn new_escrow(
+    request: web::Json<NewEscrowRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let hash_algorithm = request.hash_algorithm.unwrap_or_default();
+    let amounts = request
+        .amounts
+        .iter()
+        .map(|amount| Amount::from_sat(*amount))
+        .collect::<Vec<Amount>>();
+    let network = get_settings().network;
+    let entropy = secret::token_bytes::<32>();
+
+    let (mnemonic_result, guardian_result) =
+        join!(secret::generate_secret(&entropy), NewGuardian::create());
+    let mnemonic = match mnemonic_result {
+        Ok(mnemonic) => mnemonic,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "error": format!("Error generating escrow: {}", e)
+            }))
+        }
+    };
+    let guardian = match guardian_result {
+        Ok(guardian) => guardian,
+        Err(response) => return response,
+    };
+
+    let milestone_escrow = match MilestoneEscrow::new(
+        mnemonic.clone(),
+        hash_algorithm,
+        guardian.signer().public_key_commitment(),
+        None,
+        &amounts,
+        network,
+    )
+    .await
+    {
+        Ok(milestone_escrow) => milestone_escrow,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid escrow: {}", e)
+            }))
+        }
+    };
+    let escrow = Escrow::new(milestone_escrow, guardian.signer(), guardian.key_origin());
+
+    let cache_val = cache.lock().await;
+    if let Err(e) = escrow::save_escrow(&cache_val, &escrow).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error saving escrow: {}", e)
+        }));
+    }
+    info!("Escrow saved: {}", escrow.escrow_id);
+
+    HttpResponse::Ok().json(json!({
+        "mnemonic": mnemonic.join(" "),
+        "escrow_id": escrow.escrow_id,
+        "guardian_key_origin": escrow.guardian_key_origin,
+        "milestones": escrow.escrow.milestones,
+    }))
+}
+
+/// Return the stored escrow with its progress
+#[get("/escrows/{escrow_id}/")]
+async fn get_escrow(
+    escrow_id: web::Path<String>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let cache_val = cache.lock().await;
+    match stored_escrow(&cache_val, &escrow_id).await {
+        Ok(escrow) => escrow_response(&escrow),
+        Err(response) => response,
+    }
+}
+
+/// Record the output funding a milestone
+#[post("/escrows/{escrow_id}/milestones/{index}/fund/")]
+async fn fund_milestone(
+    path: web::Path<(String, u32)>,
+    request: web::Json<FundMilestoneRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let (escrow_id, index) = path.into_inner();
+    let outpoint = match Txid::from_str(&request.tx_id) {
+        Ok(txid) => OutPoint::new(txid, request.vout),
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid transaction id: {}", e)
+            }))
+        }
+    };
+
+    // Keep the cache locked so concurrent updates of the escrow are not lost
+    let cache_val = cache.lock().await;
+    let mut escrow = match stored_escrow(&cache_val, &escrow_id).await {
+        Ok(escrow) => escrow,
+        Err(response) => return response,
+    };
+    let Some(milestone) = escrow.escrow.milestones.get(index as usize) else {
+        return HttpResponse::NotFound().json(json!({
+            "error": "Milestone not found"
+        }));
+    };
+    let txout = TxOut {
+        value: Amount::from_sat(request.amount),
+        script_pubkey: milestone.address.script_pubkey(),
+    };
+    if let Err(e) = escrow.escrow.fund(index, outpoint, &txout) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Invalid funding: {}", e)
+        }));
+    }
+
+    if let Err(e) = escrow::save_escrow(&cache_val, &escrow).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error saving escrow: {}", e)
+        }));
+    }
+    info!("Escrow milestone {} funded: {}", index, escrow.escrow_id);
+
+    escrow_response(&escrow)
+}
+
+/// Derive the mnemonic of a milestone from the master mnemonic, for the
+/// buyer to hand to the recipient when the milestone is due
+#[post("/escrows/{escrow_id}/milestones/{index}/mnemonic/")]
+async fn milestone_mnemonic(
+    path: web::Path<(String, u32)>,
+    request: web::Json<MilestoneMnemonicRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let (escrow_id, index) = path.into_inner();
+    let master_mnemonic = request
+        .mnemonic
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+    let escrow = {
+        let cache_val = cache.lock().await;
+        match stored_escrow(&cache_val, &escrow_id).await {
+            Ok(escrow) => escrow,
+            Err(response) => return response,
+        }
+    };
+    let Some(milestone) = escrow.escrow.milestones.get(index as usize) else {
+        return HttpResponse::NotFound().json(json!({
+            "error": "Milestone not found"
+        }));
+    };
+
+    let mnemonic = match milestone::milestone_mnemonic(master_mnemonic, index).await {
+        Ok(mnemonic) => mnemonic,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid mnemonic: {}", e)
+            }))
+        }
+    };
+    // Only the master mnemonic of this escrow derives the milestone secret
+    match secret::mnemonic_to_entropy(mnemonic.clone()).await {
+        Ok(secret) if milestone.hash_lock.is_unlocked_by(&secret) => {}
+        _ => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Mnemonic is not the master mnemonic of the escrow"
+            }))
+        }
+    }
+
+    HttpResponse::Ok().json(json!({
+        "escrow_id": escrow.escrow_id,
+        "index": index,
+        "mnemonic": mnemonic.join(" "),
+    }))
+}
+
+/// Build the claim of a funded milestone with its mnemonic, signed by the
+/// guardian, and record the milestone as released. A released milestone is
+/// claimed again, e.g. with a higher fee, until its claim confirms
+#[post("/escrows/{escrow_id}/milestones/{index}/release/")]
+async fn release_milestone(
+    path: web::Path<(String, u32)>,
+    request: web::Json<ReleaseMilestoneRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let (escrow_id, index) = path.into_inner();
+    let network = get_settings().network;
+    let destination = match validation::parse_address(&request.destination, network) {
+        Ok(address) => address,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid destination address: {}", e)
+            }))
+        }
+    };
+    let Some(fee_rate) = FeeRate::from_sat_per_vb(request.fee_rate) else {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Invalid fee rate"
+        }));
+    };
+    let mnemonic = request
+        .mnemonic
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<String>>();
+
+    // The cache is not held while the guardian signs, the escrow is loaded
+    // again before the release is recorded
+    let escrow = match stored_escrow(&*cache.lock().await, &escrow_id).await {
+        Ok(escrow) => escrow,
+        Err(response) => return response,
+    };
+    let guardian = match stored_guardian(
+        escrow.guardian_key_origin.as_deref(),
+        &escrow.guardian_public_key,
+    )
+    .await
+    {
+        Ok(guardian) => guardian,
+        Err(response) => return response,
+    };
+
+    let secret = match secret::mnemonic_to_entropy(mnemonic).await {
+        Ok(secret) => secret,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid mnemonic: {}", e)
+            }))
+        }
+    };
+
+    // The external signer blocks, the claim is signed on the blocking pool
+    let milestone_escrow = escrow.escrow.clone();
+    let tx = spawn_blocking(move || {
+        milestone_escrow.build_secret_release_transaction(
+            index,
+            &secret,
+            &destination,
+            fee_rate,
+            guardian.as_ref(),
+        )
+    })
+    .await
+    .map_err(Error::other)
+    .and_then(|tx| tx);
+    let tx = match tx {
+        Ok(tx) => tx,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Error building release: {}", e)
+            }))
+        }
+    };
+    let outpoint = tx.input[0].previous_output;
+
+    let cache_val = cache.lock().await;
+    let mut escrow = match stored_escrow(&cache_val, &escrow_id).await {
+        Ok(escrow) => escrow,
+        Err(response) => return response,
+    };
+    let funding_outpoint = escrow
+        .escrow
+        .milestones
+        .get(index as usize)
+        .and_then(|milestone| milestone.funding_outpoint());
+    if funding_outpoint != Some(outpoint) {
+        return HttpResponse::Conflict().json(json!({
+            "error": "Milestone funding changed while the release was signed"
+        }));
+    }
+    if let Err(e) = escrow.escrow.release(index, tx.compute_txid()) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Error releasing milestone: {}", e)
+        }));
+    }
+
+    if let Err(e) = escrow::save_escrow(&cache_val, &escrow).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error saving escrow: {}", e)
+        }));
+    }
+    info!("Escrow milestone {} released: {}", index, escrow.escrow_id);
+
+    HttpResponse::Ok().json(json!({
+        "escrow_id": escrow.escrow_id,
+        "index": index,
+        "transaction": serialize_hex(&tx),
+    }))
+}
@@ -0,0 +1,265 @@
+use std::{io::Error, sync::Arc};
+
+use actix_web::{get, post, web, HttpResponse, Responder};
+use log::info;
+use secp256k1::PublicKey;
+use serde_json::{json, Value};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    blockchain::{
+        address::GuardianWallet,
+        signer::{ExternalSigner, Signer},
+    },
+    settings::get_settings,
+    storage::keystore::Keystore,
+};
+
+use super::schemas::{NewGuardianRequest, RestoreGuardianRequest};
+
+fn internal_error(message: String) -> HttpResponse {
+    HttpResponse::InternalServerError().json(json!({
+        "error": message
+    }))
+}
+
+/// Open the configured keystore, or the error response if there is none
+fn settings_keystore() -> Result<Keystore, HttpResponse> {
+    match Keystore::from_settings() {
+        Ok(Some(keystore)) => Ok(keystore),
+        Ok(None) => Err(internal_error(
+            "No keystore is configured for guardian keys".to_string(),
+        )),
+        Err(e) => Err(internal_error(format!("Error opening keystore: {}", e))),
+    }
+}
+
+/// Public details of the guardian master, lockers are guarded by the keys
+/// of its account
+fn guardian_response(wallet: &GuardianWallet, next_index: u32) -> Result<Value, HttpResponse> {
+    let account_xpub = wallet
+        .account_xpub()
+        .map_err(|e| internal_error(format!("Error deriving account key: {}", e)))?;
+
+    Ok(json!({
+        "fingerprint": wallet.fingerprint().to_string(),
+        "account_xpub": account_xpub.to_string(),
+        "next_index": next_index,
+    }))
+}
+
+/// BIP39 passphrase of a guardian seed, the one of the request or else the
+/// one of the settings
+fn guardian_passphrase(passphrase: Option<&str>) -> Option<String> {
+    passphrase
+        .map(String::from)
+        .or_else(|| get_settings().guardian_passphrase.clone())
+}
+
+/// Check that the keystore has no other guardian master, or the error
+/// response. Restoring the current master again is allowed.
+async fn check_no_guardian(
+    keystore: &Keystore,
+    wallet: Option<&GuardianWallet>,
+) -> Result<(), HttpResponse> {
+    match keystore.guardian().await {
+        Ok(None) => Ok(()),
+        Ok(Some((guardian, _)))
+            if wallet.is_some_and(|wallet| wallet.fingerprint() == guardian.fingerprint()) =>
+        {
+            Ok(())
+        }
+        Ok(Some(_)) => Err(HttpResponse::Conflict().json(json!({
+            "error": "Guardian key already exists"
+        }))),
+        Err(e) => Err(internal_error(format!("Error loading guardian key: {}", e))),
+    }
+}
+
+/// Create the guardian master in the keystore, with an optional BIP39
+/// passphrase. Its mnemonic is only returned here, it is the backup of
+/// every locker guarded by the keystore.
+#[post("/guardian/new/")]
+async fn new_guardian(request: web::Json<NewGuardianRequest>) -> impl Responder {
+    let keystore = match settings_keystore() {
+        Ok(keystore) => keystore,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_no_guardian(&keystore, None).await {
+        return response;
+    }
+
+    let passphrase = guardian_passphrase(request.passphrase.as_deref());
+    let mut wallet = match GuardianWallet::generate_with_passphrase(passphrase.as_deref()).await {
+        Ok(wallet) => wallet,
+        Err(e) => return internal_error(format!("Error generating guardian wallet: {}", e)),
+    };
+    let key_id = match keystore.set_guardian(&wallet).await {
+        Ok(key_id) => key_id,
+        Err(e) => return internal_error(format!("Error saving guardian key: {}", e)),
+    };
+    info!("Guardian key saved: {}", key_id);
+
+    let mut response = match guardian_response(&wallet, 0) {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+    response["mnemonic"] = json!(wallet.take_mnemonic().map(|mnemonic| mnemonic.join(" ")));
+
+    HttpResponse::Ok().json(response)
+}
+
+/// Restore the guardian master of a mnemonic and its BIP39 passphrase, e.g.
+/// into a new keystore, so the lockers it guards can be spent again
+#[post("/guardian/restore/")]
+async fn restore_guardian(request: web::Json<RestoreGuardianRequest>) -> impl Responder {
+    let keystore = match settings_keystore() {
+        Ok(keystore) => keystore,
+        Err(response) => return response,
+    };
+    let mnemonic = request
+        .mnemonic
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<String>>();
+    let passphrase = guardian_passphrase(request.passphrase.as_deref());
+    let wallet = match GuardianWallet::restore(mnemonic, passphrase.as_deref()).await {
+        Ok(wallet) => wallet,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid mnemonic: {}", e)
+            }))
+        }
+    };
+    if let Err(response) = check_no_guardian(&keystore, Some(&wallet)).await {
+        return response;
+    }
+
+    let key_id = match keystore.set_guardian(&wallet).await {
+        Ok(key_id) => key_id,
+        Err(e) => return internal_error(format!("Error saving guardian key: {}", e)),
+    };
+    info!("Guardian key restored: {}", key_id);
+
+    match keystore.guardian().await {
+        Ok(Some((wallet, next_index))) => match guardian_response(&wallet, next_index) {
+            Ok(response) => HttpResponse::Ok().json(response),
+            Err(response) => response,
+        },
+        Ok(None) => internal_error("Guardian key was not saved".to_string()),
+        Err(e) => internal_error(format!("Error loading guardian key: {}", e)),
+    }
+}
+
+/// Public details of the guardian master
+#[get("/guardian/")]
+async fn get_guardian() -> impl Responder {
+    let keystore = match settings_keystore() {
+        Ok(keystore) => keystore,
+        Err(response) => return response,
+    };
+
+    match keystore.guardian().await {
+        Ok(Some((wallet, next_index))) => match guardian_response(&wallet, next_index) {
+            Ok(response) => HttpResponse::Ok().json(response),
+            Err(response) => response,
+        },
+        Ok(None) => HttpResponse::NotFound().json(json!({
+            "error": "Guardian key not found"
+        })),
+        Err(e) => internal_error(format!("Error loading guardian key: {}", e)),
+    }
+}
+
+/// Guardian key of a new record, the external signer when one is configured
+/// or the next key of the guardian master
+pub(super) enum NewGuardian {
+    External(Arc<ExternalSigner>),
+    Wallet(Box<GuardianWallet>),
+}
+
+impl NewGuardian {
+    /// Pick the guardian of a new record, or the error response if no
+    /// signer or guardian master is configured. The key index is reserved in
+    /// the keystore before the record is handed out.
+    pub(super) async fn create() -> Result<Self, HttpResponse> {
+        match settings_signer().await {
+            Ok(Some(signer)) => return Ok(Self::External(signer)),
+            Ok(None) => {}
+            Err(e) => return Err(internal_error(format!("Error connecting to signer: {}", e))),
+        }
+        let keystore = match Keystore::from_settings() {
+            Ok(Some(keystore)) => keystore,
+            Ok(None) => {
+                return Err(internal_error(
+                    "No keystore or signer is configured for guardian keys".to_string(),
+                ))
+            }
+            Err(e) => return Err(internal_error(format!("Error opening keystore: {}", e))),
+        };
+
+        match keystore.next_guardian().await {
+            Ok(Some(wallet)) => Ok(Self::Wallet(Box::new(wallet))),
+            Ok(None) => Err(internal_error(
+                "No guardian key in the keystore, create one first".to_string(),
+            )),
+            Err(e) => Err(internal_error(format!(
+                "Error deriving guardian key: {}",
+                e
+            ))),
+        }
+    }
+
+    pub(super) fn signer(&self) -> &dyn Signer {
+        match self {
+            Self::External(signer) => signer.as_ref(),
+            Self::Wallet(wallet) => wallet.as_ref(),
+        }
+    }
+
+    /// BIP32 origin to record, `None` for the external signer
+    pub(super) fn key_origin(&self) -> Option<String> {
+        match self {
+            Self::External(_) => None,
+            Self::Wallet(wallet) => Some(wallet.key_origin()),
+        }
+    }
+}
+
+/// Signer of the settings, connected on the blocking thread pool
+async fn settings_signer() -> Result<Option<Arc<ExternalSigner>>, Error> {
+    spawn_blocking(ExternalSigner::from_settings)
+        .await
+        .map_err(Error::other)?
+}
+
+/// Signer of a stored guardian key, the external signer when it holds the
+/// key or else the keystore, or the error response if it is not available.
+/// Signing can block on the external signer, it is run with
+/// [`spawn_blocking`].
+pub(super) async fn stored_guardian(
+    key_origin: Option<&str>,
+    public_key: &PublicKey,
+) -> Result<Arc<dyn Signer + Send + Sync>, HttpResponse> {
+    let unavailable =
+        |reason: String| internal_error(format!("Guardian key is not available: {}", reason));
+    match settings_signer().await {
+        Ok(Some(signer)) if signer.public_key() == *public_key => return Ok(signer),
+        Ok(_) => {}
+        Err(e) => return Err(unavailable(e.to_string())),
+    }
+    let Some(key_origin) = key_origin else {
+        return Err(unavailable("record has no key origin".to_string()));
+    };
+    let keystore = match Keystore::from_settings() {
+        Ok(Some(keystore)) => keystore,
+        Ok(None) => return Err(unavailable("no keystore is configured".to_string())),
+        Err(e) => return Err(unavailable(e.to_string())),
+    };
+
+    match keystore.load_guardian(key_origin, public_key).await {
+        Ok(Some(guardian)) => Ok(Arc::new(guardian)),
+        Ok(None) => Err(unavailable("key is not in the keystore".to_string())),
+        Err(e) => Err(unavailable(e.to_string())),
+    }
+}
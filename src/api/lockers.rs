@@ -1,27 +1,80 @@
-use std::sync::Arc;
+use std::{io::Error, str::FromStr, sync::Arc};
 
 use actix_web::{get, post, web, HttpResponse, Responder};
+use bitcoin::{
+    consensus::encode::deserialize_hex, sighash::Prevouts, Amount, FeeRate, OutPoint, Transaction,
+    TxOut, Txid,
+};
 use log::info;
 use serde_json::json;
-use tokio::{join, sync::Mutex};
+use tokio::{join, sync::Mutex, task::spawn_blocking};
 
 use crate::{
-    blockchain::{address, secret, transactions},
-    storage::cache::CacheClient,
+    blockchain::{
+        interpreter,
+        payout::{self, Payout},
+        psbt, secret,
+        transactions::{self, HashLock},
+        validation,
+    },
+    settings::get_settings,
+    storage::{
+        cache::CacheClient,
+        locker::{self, Locker},
+    },
 };
 
-use super::schemas::SaveLockerRequest;
+use super::{
+    guardian::{stored_guardian, NewGuardian},
+    schemas::{
+        ClaimPsbtRequest, NewLockerQuery, SaveLockerRequest, SetPayoutsRequest, VerifyLockerRequest,
+    },
+};
 
-/// Generate a new locker with a new guardian wallet
+/// Load a stored locker, or the error response if it cannot be loaded
+async fn stored_locker(cache: &CacheClient, locker_id: &str) -> Result<Locker, HttpResponse> {
+    match locker::load_locker(cache, locker_id).await {
+        Ok(Some(locker)) => Ok(locker),
+        Ok(None) => Err(HttpResponse::NotFound().json(json!({
+            "error": "Locker not found"
+        }))),
+        Err(e) => Err(HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error loading locker: {}", e)
+        }))),
+    }
+}
+
+/// Generate a new locker guarded by the external signer, or by the next key
+/// of the keystore guardian. Creation fails when neither is configured.
 #[get("/lockers/new/")]
-async fn new_locker(cache: web::Data<Arc<Mutex<CacheClient>>>) -> impl Responder {
+async fn new_locker(
+    query: web::Query<NewLockerQuery>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let hash_algorithm = query.hash_algorithm.unwrap_or_default();
+    let address_type = query.address_type.unwrap_or_default();
+    let amount = Amount::from_sat(query.amount);
+    let (network, min_amount, max_amount) = {
+        let settings = get_settings();
+        (
+            settings.network,
+            Amount::from_sat(settings.min_locker_amount),
+            Amount::from_sat(settings.max_locker_amount),
+        )
+    };
+    // Checked before a guardian key index is used up, a rejected request
+    // must not leave a gap in the guardian receive chain
+    let script_pubkey = address_type.template_script_pubkey();
+    if let Err(e) = validation::validate_amount(amount, &script_pubkey, min_amount, max_amount) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Invalid amount: {}", e)
+        }));
+    }
     // Generate a new locker password which is a mnemonic key
     let entropy = secret::token_bytes::<32>();
 
-    let (mnemonic_result, guardian_wallet_result) = join!(
-        secret::generate_secret(&entropy),
-        address::GuardianWallet::generate_new()
-    );
+    let (mnemonic_result, guardian_result) =
+        join!(secret::generate_secret(&entropy), NewGuardian::create());
     let mnemonic = match mnemonic_result {
         Ok(mnemonic) => mnemonic,
         Err(e) => {
@@ -31,36 +84,111 @@ async fn new_locker(cache: web::Data<Arc<Mutex<CacheClient>>>) -> impl Responder
         }
     };
 
-    // Generate a new guardian wallet
-    let guardian_wallet = match guardian_wallet_result {
-        Ok(wallet) => wallet,
+    let guardian = match guardian_result {
+        Ok(guardian) => guardian,
+        Err(response) => return response,
+    };
+
+    // Generate a new locker address
+    let witness_script = match transactions::generate_witness_script(
+        &entropy,
+        hash_algorithm,
+        guardian.signer().public_key_commitment(),
+        None,
+    ) {
+        Ok(witness_script) => witness_script,
         Err(e) => {
             return HttpResponse::InternalServerError().json(json!({
-                "error": format!("Error generating guardian wallet: {}", e)
+                "error": format!("Error generating locker: {}", e)
             }))
         }
     };
+    let locker = Locker::new(
+        network,
+        address_type,
+        amount,
+        &HashLock::new(hash_algorithm, &entropy),
+        guardian.signer(),
+        guardian.key_origin(),
+        witness_script,
+    );
 
-    // Generate a new locker address
-    let address =
-        transactions::generate_p2wsh_address(&entropy, guardian_wallet.public_key_commitment());
+    let cache_val = cache.lock().await;
+    if let Err(e) = locker::save_locker(&cache_val, &locker).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error saving locker: {}", e)
+        }));
+    }
+    info!("Locker saved: {}", locker.locker_id);
 
-    let locker_id = secret::hash_id(address.to_string());
-    let locker_data = json!({
-        "address": address.to_string(),
-        "locker_id": locker_id,
-    });
+    HttpResponse::Ok().json(json!({
+        "mnemonic": mnemonic.join(" "),
+        "address": locker.address().to_string(),
+        "locker_id": locker.locker_id,
+        "hash_algorithm": locker.hash_algorithm,
+        "address_type": locker.address_type,
+        "amount": locker.amount.to_sat(),
+        "descriptor": locker.descriptor,
+        "guardian_key_origin": locker.guardian_key_origin,
+    }))
+}
 
+/// Return the stored locker with its address
+#[get("/lockers/{locker_id}/")]
+async fn get_locker(
+    locker_id: web::Path<String>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let locker = {
+        let cache_val = cache.lock().await;
+        match stored_locker(&cache_val, &locker_id).await {
+            Ok(locker) => locker,
+            Err(response) => return response,
+        }
+    };
+
+    let mut locker_data = json!(locker);
+    locker_data["address"] = json!(locker.address().to_string());
+
+    HttpResponse::Ok().json(locker_data)
+}
+
+/// Record the outputs the claim of a locker pays. Payouts are set once, so
+/// they cannot be changed after the locker was handed out.
+#[post("/lockers/{locker_id}/payouts/")]
+async fn set_payouts(
+    locker_id: web::Path<String>,
+    request: web::Json<SetPayoutsRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    // Keep the cache locked so two requests cannot both set the payouts
     let cache_val = cache.lock().await;
-    let locker_data_serialized = locker_data.to_string();
-    let locker_data_key = format!("locker:{}", locker_id);
-    cache_val.set(&locker_data_key, locker_data_serialized.as_str()).await.unwrap();
-    info!("Locker saved in cache: {}", locker_id);
+    let mut locker = match stored_locker(&cache_val, &locker_id).await {
+        Ok(locker) => locker,
+        Err(response) => return response,
+    };
+    if !locker.payouts.is_empty() {
+        return HttpResponse::Conflict().json(json!({
+            "error": "Locker payouts are already set"
+        }));
+    }
+    if let Err(e) = payout::validate_payouts(&request.payouts, locker.amount, locker.network) {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Invalid payouts: {}", e)
+        }));
+    }
+
+    locker.payouts = request.into_inner().payouts;
+    if let Err(e) = locker::save_locker(&cache_val, &locker).await {
+        return HttpResponse::InternalServerError().json(json!({
+            "error": format!("Error saving locker: {}", e)
+        }));
+    }
+    info!("Locker payouts saved: {}", locker.locker_id);
 
     HttpResponse::Ok().json(json!({
-        "mnemonic": mnemonic.join(" "),
-        "address": address.to_string(),
-        "locker_id": secret::hash_id(address.to_string()),
+        "locker_id": locker.locker_id,
+        "payouts": locker.payouts,
     }))
 }
 
@@ -71,3 +199,162 @@ async fn save_locker(request: web::Json<SaveLockerRequest>) -> impl Responder {
         "message": "Locker saved successfully"
     }))
 }
+
+/// Create a base64 PSBT that claims a funded locker with its mnemonic, signed
+/// by the guardian
+#[post("/lockers/{locker_id}/psbt/")]
+async fn claim_psbt(
+    locker_id: web::Path<String>,
+    request: web::Json<ClaimPsbtRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let network = get_settings().network;
+    let outpoint = match Txid::from_str(&request.tx_id) {
+        Ok(txid) => OutPoint::new(txid, request.vout),
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid transaction id: {}", e)
+            }))
+        }
+    };
+    let Some(fee_rate) = FeeRate::from_sat_per_vb(request.fee_rate) else {
+        return HttpResponse::BadRequest().json(json!({
+            "error": "Invalid fee rate"
+        }));
+    };
+
+    let locker = {
+        let cache_val = cache.lock().await;
+        match stored_locker(&cache_val, &locker_id).await {
+            Ok(locker) => locker,
+            Err(response) => return response,
+        }
+    };
+    // The guardian signature commits to the spent amount
+    let amount = locker.amount;
+    if amount.to_sat() != request.amount {
+        return HttpResponse::BadRequest().json(json!({
+            "error": format!("Locker amount is {} sat", amount.to_sat())
+        }));
+    }
+    let guardian = match stored_guardian(
+        locker.guardian_key_origin.as_deref(),
+        &locker.guardian_public_key,
+    )
+    .await
+    {
+        Ok(guardian) => guardian,
+        Err(response) => return response,
+    };
+    // Lockers with payouts always pay them, the others pay the destination
+    let payouts = match (&request.destination, locker.payouts.is_empty()) {
+        (Some(destination), true) => match validation::parse_address(destination, network) {
+            Ok(address) => vec![Payout::to(&address)],
+            Err(e) => {
+                return HttpResponse::BadRequest().json(json!({
+                    "error": format!("Invalid destination address: {}", e)
+                }))
+            }
+        },
+        (None, true) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Missing destination address"
+            }))
+        }
+        (Some(_), false) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": "Locker pays its recorded payouts"
+            }))
+        }
+        (None, false) => locker.payouts,
+    };
+
+    let mnemonic = request
+        .mnemonic
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<String>>();
+    let secret = match secret::mnemonic_to_entropy(mnemonic).await {
+        Ok(secret) => secret,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid mnemonic: {}", e)
+            }))
+        }
+    };
+
+    let mut psbt = match psbt::create_claim_psbt(
+        outpoint,
+        amount,
+        &payouts,
+        fee_rate,
+        &secret,
+        locker.witness_script,
+        locker.address_type,
+    ) {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Error creating PSBT: {}", e)
+            }))
+        }
+    };
+    // The external signer blocks, it signs on the blocking thread pool
+    let signed =
+        spawn_blocking(move || psbt::sign_psbt(&mut psbt, guardian.as_ref()).map(|()| psbt))
+            .await
+            .map_err(Error::other)
+            .and_then(|signed| signed);
+    let psbt = match signed {
+        Ok(psbt) => psbt,
+        Err(e) => {
+            return HttpResponse::InternalServerError().json(json!({
+                "error": format!("Error signing PSBT: {}", e)
+            }))
+        }
+    };
+
+    HttpResponse::Ok().json(json!({
+        "locker_id": locker_id.into_inner(),
+        "psbt": psbt.to_string(),
+    }))
+}
+
+/// Execute the witness of a transaction spending the locker and return the
+/// trace of the script execution
+#[post("/lockers/{locker_id}/verify/")]
+async fn verify_spend(
+    locker_id: web::Path<String>,
+    request: web::Json<VerifyLockerRequest>,
+    cache: web::Data<Arc<Mutex<CacheClient>>>,
+) -> impl Responder {
+    let tx = match deserialize_hex::<Transaction>(&request.transaction) {
+        Ok(tx) => tx,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(json!({
+                "error": format!("Invalid transaction: {}", e)
+            }))
+        }
+    };
+
+    let locker = {
+        let cache_val = cache.lock().await;
+        match stored_locker(&cache_val, &locker_id).await {
+            Ok(locker) => locker,
+            Err(response) => return response,
+        }
+    };
+
+    let input_index = request.input_index.unwrap_or_default();
+    let prevout = TxOut {
+        value: Amount::from_sat(request.amount),
+        script_pubkey: locker.address().script_pubkey(),
+    };
+    let trace = interpreter::trace_spend(&tx, input_index, &Prevouts::One(input_index, prevout));
+
+    HttpResponse::Ok().json(json!({
+        "locker_id": locker_id.into_inner(),
+        "valid": trace.is_valid(),
+        "trace": trace,
+    }))
+}
@@ -1,4 +1,6 @@
+pub mod escrows;
+pub mod guardian;
 pub mod lockers;
 pub mod probes;
 
-mod schemas;
\ No newline at end of file
+mod schemas;
@@ -1,7 +1,81 @@
 use serde::{Deserialize, Serialize};
 
+use crate::blockchain::{crypto::HashAlgorithm, payout::Payout, transactions::AddressType};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct NewLockerQuery {
+    /// Expected amount in satoshis
+    pub amount: u64,
+    pub hash_algorithm: Option<HashAlgorithm>,
+    pub address_type: Option<AddressType>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub(super) struct SaveLockerRequest {
     tx_id: String,
-}
\ No newline at end of file
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct SetPayoutsRequest {
+    pub payouts: Vec<Payout>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ClaimPsbtRequest {
+    pub tx_id: String,
+    pub vout: u32,
+    pub amount: u64,
+    /// Address paid the whole claim, only for lockers without payouts
+    pub destination: Option<String>,
+    pub fee_rate: u64,
+    pub mnemonic: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct VerifyLockerRequest {
+    /// Hex of the transaction spending the locker
+    pub transaction: String,
+    pub input_index: Option<usize>,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct NewEscrowRequest {
+    /// Amount of each milestone in satoshis, in release order
+    pub amounts: Vec<u64>,
+    pub hash_algorithm: Option<HashAlgorithm>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct FundMilestoneRequest {
+    pub tx_id: String,
+    pub vout: u32,
+    pub amount: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct MilestoneMnemonicRequest {
+    /// Master mnemonic of the escrow
+    pub mnemonic: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct ReleaseMilestoneRequest {
+    /// Mnemonic of the milestone, not the master mnemonic of the escrow
+    pub mnemonic: String,
+    pub destination: String,
+    pub fee_rate: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct NewGuardianRequest {
+    /// BIP39 passphrase of the seed, the settings one when not given
+    pub passphrase: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) struct RestoreGuardianRequest {
+    pub mnemonic: String,
+    /// BIP39 passphrase of the seed, the settings one when not given
+    pub passphrase: Option<String>,
+}
@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 use actix_web::{middleware, web, App, HttpServer};
-use coinslock_rust::api::{lockers, probes};
+use coinslock_rust::api::{escrows, guardian, lockers, probes};
 use coinslock_rust::storage::cache;
 use dotenv::dotenv;
 use log::info;
@@ -23,7 +23,19 @@ async fn main() -> std::io::Result<()> {
                 web::scope("/api/v1")
                     .service(probes::health)
                     .service(lockers::new_locker)
-                    .service(lockers::save_locker),
+                    .service(lockers::get_locker)
+                    .service(lockers::set_payouts)
+                    .service(lockers::save_locker)
+                    .service(lockers::claim_psbt)
+                    .service(lockers::verify_spend)
+                    .service(escrows::new_escrow)
+                    .service(escrows::get_escrow)
+                    .service(escrows::fund_milestone)
+                    .service(escrows::milestone_mnemonic)
+                    .service(escrows::release_milestone)
+                    .service(guardian::new_guardian)
+                    .service(guardian::restore_guardian)
+                    .service(guardian::get_guardian),
             )
     })
     .bind("127.0.0.1:8080")?
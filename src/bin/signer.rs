@@ -0,0 +1,57 @@
+use std::{
+    env,
+    io::{self, BufReader, Error, ErrorKind},
+    os::unix::net::UnixListener,
+    sync::Arc,
+    thread,
+};
+
+use coinslock_rust::blockchain::{address::GuardianWallet, signer};
+use dotenv::dotenv;
+use log::{error, info};
+
+/// Stand-in external signer holding the guardian key of `SIGNER_MNEMONIC`.
+///
+/// Serves the signer protocol over standard input and output, or over a Unix
+/// socket with `--socket <path>`, one thread per connection.
+fn main() -> io::Result<()> {
+    dotenv().ok();
+    env_logger::init();
+
+    let mnemonic = env::var("SIGNER_MNEMONIC")
+        .map_err(|_| Error::new(ErrorKind::InvalidInput, "SIGNER_MNEMONIC is not set"))?
+        .split_whitespace()
+        .map(String::from)
+        .collect::<Vec<String>>();
+    let passphrase = env::var("SIGNER_PASSPHRASE").ok();
+    // Signing is blocking, the runtime is only needed to restore the wallet
+    let wallet = tokio::runtime::Builder::new_current_thread()
+        .build()?
+        .block_on(GuardianWallet::restore(mnemonic, passphrase.as_deref()))?;
+    info!("Signing with {}", wallet.key_origin());
+
+    let args = env::args().collect::<Vec<String>>();
+    match args.iter().position(|arg| arg == "--socket") {
+        Some(index) => {
+            let path = args
+                .get(index + 1)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Missing socket path"))?;
+            let listener = UnixListener::bind(path)?;
+            info!("Listening on {}", path);
+            let wallet = Arc::new(wallet);
+            for stream in listener.incoming() {
+                let stream = stream?;
+                let reader = BufReader::new(stream.try_clone()?);
+                let wallet = wallet.clone();
+                thread::spawn(move || {
+                    if let Err(e) = signer::serve(wallet.as_ref(), reader, stream) {
+                        error!("Signer connection failed: {}", e);
+                    }
+                });
+            }
+
+            Ok(())
+        }
+        None => signer::serve(&wallet, io::stdin().lock(), io::stdout().lock()),
+    }
+}
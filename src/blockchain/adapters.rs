@@ -1,14 +1,49 @@
+use std::{
+    collections::HashMap,
+    io::{Error, ErrorKind},
+    sync::Mutex,
+};
+
+use bitcoin::{
+    consensus::encode::{deserialize_hex, serialize_hex},
+    Address, Amount, Block, FeeRate, OutPoint, ScriptBuf, Transaction, TxOut, Txid,
+};
 use serde_json::{json, Value};
 
+use crate::settings::get_settings;
+
+use super::{fees, funding::Utxo};
 
-trait BitcoinAdapter {
+/// Blocks scanned again for a spend on every search, so a spend moved to
+/// another block by a reorganisation is still found.
+const RESCAN_DEPTH: u64 = 6;
 
+#[allow(async_fn_in_trait)]
+pub trait BitcoinAdapter {
     /// Get the current block height
-    async fn get_height(&self) -> u64;
+    async fn get_height(&self) -> Result<u64, Error>;
 
     /// Get the transaction details for a given transaction ID
     async fn get_tx(&self, tx_id: &str) -> Option<String>;
 
+    /// Find the transaction that spends the outpoint, looking in the mempool
+    /// and in the blocks mined from the given height
+    async fn find_spending_tx(
+        &self,
+        outpoint: &OutPoint,
+        from_height: u64,
+    ) -> Result<Option<Transaction>, Error>;
+
+    /// Estimate the fee rate for confirmation within the target number of
+    /// blocks, falling back to fixed rates when the node has no estimate
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate, Error>;
+
+    /// List the unspent outputs paying to the address
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, Error>;
+
+    /// Broadcast a signed transaction to the network
+    async fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error>;
+
     fn call_request(&self, method: &str, params: Vec<Value>) -> serde_json::Value {
         json!({
             "jsonrpc": "1.0",
@@ -19,5 +54,206 @@ trait BitcoinAdapter {
     }
 }
 
+/// Adapter backed by the JSON-RPC interface of a bitcoind node.
+pub struct BitcoinRegtestAdapter {
+    client: reqwest::Client,
+    url: String,
+    username: String,
+    password: String,
+    /// Last block height searched for the spend of each outpoint
+    scanned: Mutex<HashMap<OutPoint, u64>>,
+}
+
+impl BitcoinRegtestAdapter {
+    pub fn new() -> Self {
+        let settings = get_settings();
+
+        Self {
+            client: reqwest::Client::new(),
+            url: format!("http://{}:{}", settings.rpc_hostname, settings.rpc_port),
+            username: settings.rpc_username.clone(),
+            password: settings.rpc_password.clone(),
+            scanned: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn call(&self, method: &str, params: Vec<Value>) -> Result<Value, Error> {
+        let response = self
+            .client
+            .post(&self.url)
+            .basic_auth(&self.username, Some(&self.password))
+            .body(self.call_request(method, params).to_string())
+            .send()
+            .await
+            .map_err(|e| Error::new(ErrorKind::ConnectionRefused, e))?
+            .text()
+            .await
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        let mut response = serde_json::from_str::<Value>(&response)?;
+        if !response["error"].is_null() {
+            return Err(Error::other(format!(
+                "RPC {} failed: {}",
+                method, response["error"]
+            )));
+        }
+
+        Ok(response["result"].take())
+    }
+
+    async fn get_raw_tx(&self, tx_id: &str) -> Result<Transaction, Error> {
+        let hex = self.call("getrawtransaction", vec![json!(tx_id)]).await?;
+        let hex = hex
+            .as_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Transaction is not hex"))?;
+
+        deserialize_hex(hex).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    async fn get_block(&self, height: u64) -> Result<Block, Error> {
+        let hash = self.call("getblockhash", vec![json!(height)]).await?;
+        let hex = self.call("getblock", vec![hash, json!(0)]).await?;
+        let hex = hex
+            .as_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Block is not hex"))?;
+
+        deserialize_hex(hex).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+impl Default for BitcoinRegtestAdapter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn spends(tx: &Transaction, outpoint: &OutPoint) -> bool {
+    tx.input
+        .iter()
+        .any(|input| input.previous_output == *outpoint)
+}
+
+impl BitcoinAdapter for BitcoinRegtestAdapter {
+    async fn get_height(&self) -> Result<u64, Error> {
+        let height = self.call("getblockcount", vec![]).await?;
+
+        height
+            .as_u64()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Block count is not a number"))
+    }
+
+    async fn get_tx(&self, tx_id: &str) -> Option<String> {
+        let hex = self
+            .call("getrawtransaction", vec![json!(tx_id)])
+            .await
+            .ok()?;
+
+        hex.as_str().map(String::from)
+    }
+
+    async fn find_spending_tx(
+        &self,
+        outpoint: &OutPoint,
+        from_height: u64,
+    ) -> Result<Option<Transaction>, Error> {
+        let prevout = json!([{ "txid": outpoint.txid.to_string(), "vout": outpoint.vout }]);
+        let spending = self.call("gettxspendingprevout", vec![prevout]).await?;
+        let spending_tx_id = spending
+            .as_array()
+            .and_then(|spending| spending.first())
+            .and_then(|spending| spending["spendingtxid"].as_str());
+        if let Some(tx_id) = spending_tx_id {
+            // The spend can be mined or evicted since, the blocks are
+            // searched next
+            if let Ok(tx) = self.get_raw_tx(tx_id).await {
+                if spends(&tx, outpoint) {
+                    return Ok(Some(tx));
+                }
+            }
+        }
+
+        let height = self.get_height().await?;
+        let scanned = self
+            .scanned
+            .lock()
+            .map_err(|_| Error::other("Scanned heights are poisoned"))?
+            .get(outpoint)
+            .copied();
+        let start = match scanned {
+            Some(scanned) => from_height.max(scanned.saturating_sub(RESCAN_DEPTH)),
+            None => from_height,
+        };
+        for block_height in start..=height {
+            let block = self.get_block(block_height).await?;
+            if let Some(tx) = block.txdata.into_iter().find(|tx| spends(tx, outpoint)) {
+                return Ok(Some(tx));
+            }
+        }
+        self.scanned
+            .lock()
+            .map_err(|_| Error::other("Scanned heights are poisoned"))?
+            .insert(*outpoint, height);
 
-pub struct BitcoinRegtestAdapter;
\ No newline at end of file
+        Ok(None)
+    }
+
+    async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate, Error> {
+        let estimate = self
+            .call("estimatesmartfee", vec![json!(target_blocks)])
+            .await?;
+
+        // Nodes without enough blocks or transactions report errors instead
+        Ok(estimate["feerate"]
+            .as_f64()
+            .and_then(fees::fee_rate_from_btc_per_kvb)
+            .unwrap_or_else(|| fees::fallback_fee_rate(target_blocks)))
+    }
+
+    async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+        let descriptor = format!("addr({})", address);
+        let scan = self
+            .call("scantxoutset", vec![json!("start"), json!([descriptor])])
+            .await?;
+
+        let invalid_utxo = || Error::new(ErrorKind::InvalidData, "Invalid unspent output");
+        scan["unspents"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|unspent| {
+                let txid = unspent["txid"]
+                    .as_str()
+                    .and_then(|txid| txid.parse::<Txid>().ok())
+                    .ok_or_else(invalid_utxo)?;
+                let vout = unspent["vout"].as_u64().ok_or_else(invalid_utxo)?;
+                let value = unspent["amount"]
+                    .as_f64()
+                    .and_then(|amount| Amount::from_btc(amount).ok())
+                    .ok_or_else(invalid_utxo)?;
+                let script_pubkey = unspent["scriptPubKey"]
+                    .as_str()
+                    .and_then(|script| ScriptBuf::from_hex(script).ok())
+                    .ok_or_else(invalid_utxo)?;
+
+                Ok(Utxo {
+                    outpoint: OutPoint::new(txid, vout as u32),
+                    txout: TxOut {
+                        value,
+                        script_pubkey,
+                    },
+                })
+            })
+            .collect()
+    }
+
+    async fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let tx_id = self
+            .call("sendrawtransaction", vec![json!(serialize_hex(tx))])
+            .await?;
+
+        tx_id
+            .as_str()
+            .and_then(|tx_id| tx_id.parse().ok())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid transaction id"))
+    }
+}
@@ -1,20 +1,34 @@
-use std::io::{Error, ErrorKind};
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
 
+use bitcoin::{
+    bip32::{ChildNumber, DerivationPath, Fingerprint, Xpriv, Xpub},
+    Network, Psbt,
+};
 use hmac::Hmac;
 use pbkdf2::pbkdf2;
-use secp256k1::{ecdsa::SerializedSignature, Message, PublicKey, Secp256k1, SecretKey};
+use secp256k1::{
+    ecdsa::SerializedSignature, schnorr, Keypair, Message, PublicKey, Secp256k1, SecretKey,
+};
 use sha2::Sha512;
+use unicode_normalization::UnicodeNormalization;
 
-use super::{secret, types::RecipientKey};
+use crate::settings::get_settings;
 
-fn mnemonic_to_seed(mnemonic: Vec<String>) -> Result<[u8; 64], Error> {
+use super::{secret, signer::Signer};
+
+/// BIP39 seed of the mnemonic, salted with the optional passphrase. Both are
+/// NFKD normalised first as the spec requires, so a passphrase typed with
+/// composed or decomposed characters gives the same seed.
+fn mnemonic_to_seed(mnemonic: &[String], passphrase: Option<&str>) -> Result<[u8; 64], Error> {
+    let mnemonic = mnemonic.join(" ").nfkd().collect::<String>();
+    let passphrase = passphrase.unwrap_or_default().nfkd().collect::<String>();
+    let salt = [super::DEFAULT_SALT, passphrase.as_bytes()].concat();
     let mut seed = [0u8; 64];
-    match pbkdf2::<Hmac<Sha512>>(
-        mnemonic.join(" ").as_bytes(),
-        super::DEFAULT_SALT,
-        2048,
-        &mut seed,
-    ) {
+    match pbkdf2::<Hmac<Sha512>>(mnemonic.as_bytes(), &salt, 2048, &mut seed) {
         Ok(_) => {}
         Err(e) => return Err(Error::new(ErrorKind::InvalidData, e)),
     }
@@ -22,49 +36,265 @@ fn mnemonic_to_seed(mnemonic: Vec<String>) -> Result<[u8; 64], Error> {
     Ok(seed)
 }
 
-#[derive(Debug)]
+/// Default BIP84 account path of the guardian keys on the network.
+pub fn default_account_path(network: Network) -> DerivationPath {
+    let coin_type = match network {
+        Network::Bitcoin => 0,
+        _ => 1,
+    };
+
+    DerivationPath::from_str(&format!("m/84'/{}'/0'", coin_type))
+        .expect("Default account path is valid")
+}
+
+/// Parse a BIP32 derivation path such as `m/84'/0'/0'`.
+///
+/// ### Errors
+/// If the path is malformed.
+pub fn parse_derivation_path(path: &str) -> Result<DerivationPath, Error> {
+    DerivationPath::from_str(path).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// Network and guardian account path of the settings.
+fn settings_account() -> Result<(Network, DerivationPath), Error> {
+    let settings = get_settings();
+
+    Ok((
+        settings.network,
+        parse_derivation_path(&settings.guardian_account_path)?,
+    ))
+}
+
+/// Path of the key at the index of the account receive chain.
+fn receive_path(account_path: &DerivationPath, index: u32) -> Result<DerivationPath, Error> {
+    let index =
+        ChildNumber::from_normal_idx(index).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    Ok(account_path.extend([ChildNumber::Normal { index: 0 }, index]))
+}
+
+/// Guardian key derived with BIP32 from the master key of the guardian seed.
+///
+/// Keys live on the receive chain of the account, at `account/0/index`, so
+/// one guardian seed serves many lockers and the keys can be recovered in
+/// any wallet that imports the account xpub.
 pub struct GuardianWallet {
+    master: Xpriv,
+    account_path: DerivationPath,
+    index: u32,
     sk: SecretKey,
     pk: PublicKey,
+    /// Mnemonic of a generated wallet until it is exported
+    mnemonic: Option<Vec<String>>,
 }
 
-impl GuardianWallet {
-    fn public_key_bytes(&self) -> [u8; 33] {
-        self.public_key().serialize()
+// Only the public parts, so the keys and mnemonic never end up in logs
+impl fmt::Debug for GuardianWallet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("GuardianWallet")
+            .field("key_origin", &self.key_origin())
+            .field("pk", &self.pk)
+            .finish_non_exhaustive()
     }
+}
 
+impl GuardianWallet {
+    /// Generate a wallet from a new random mnemonic, with the account path
+    /// and passphrase of the settings.
+    ///
+    /// ### Errors
+    /// If the mnemonic cannot be generated or the keys cannot be derived.
     pub async fn generate_new() -> Result<Self, Error> {
+        let passphrase = get_settings().guardian_passphrase.clone();
+
+        Self::generate_with_passphrase(passphrase.as_deref()).await
+    }
+
+    /// Generate a wallet from a new random mnemonic protected by the BIP39
+    /// passphrase, on the account path of the settings. The mnemonic can be
+    /// exported once with [`GuardianWallet::take_mnemonic`], restoring it
+    /// also needs the passphrase.
+    ///
+    /// ### Errors
+    /// If the mnemonic cannot be generated or the keys cannot be derived.
+    pub async fn generate_with_passphrase(passphrase: Option<&str>) -> Result<Self, Error> {
         let entropy = secret::token_bytes::<32>();
         let mnemonic = secret::generate_secret(&entropy).await?;
-        let seed = mnemonic_to_seed(mnemonic).unwrap();
+        let (network, account_path) = settings_account()?;
 
-        let secp = Secp256k1::new();
-        let sk =
-            SecretKey::from_slice(&seed[..32]).expect("Seed did not produce a valid secret key");
-        let pk = PublicKey::from_secret_key(&secp, &sk);
+        let mut wallet =
+            Self::from_mnemonic(mnemonic.clone(), passphrase, network, &account_path).await?;
+        wallet.mnemonic = Some(mnemonic);
 
-        Ok(Self { sk, pk })
+        Ok(wallet)
     }
 
-    pub fn public_key(&self) -> PublicKey {
-        self.pk
+    /// Restore the wallet of an existing mnemonic, e.g. after a restart or
+    /// to co-sign the spend of an older locker.
+    ///
+    /// ### Errors
+    /// If the mnemonic is invalid or the keys cannot be derived.
+    pub async fn from_mnemonic(
+        mnemonic: Vec<String>,
+        passphrase: Option<&str>,
+        network: Network,
+        account_path: &DerivationPath,
+    ) -> Result<Self, Error> {
+        // Checks the words and the checksum, the seed itself is derived from
+        // the words
+        secret::mnemonic_to_entropy(mnemonic.clone()).await?;
+        let seed = mnemonic_to_seed(&mnemonic, passphrase)?;
+
+        Self::from_seed(&seed, network, account_path)
+    }
+
+    /// Restore the wallet of the mnemonic on the network and account path of
+    /// the settings.
+    ///
+    /// ### Errors
+    /// If the mnemonic is invalid or the keys cannot be derived.
+    pub async fn restore(mnemonic: Vec<String>, passphrase: Option<&str>) -> Result<Self, Error> {
+        let (network, account_path) = settings_account()?;
+
+        Self::from_mnemonic(mnemonic, passphrase, network, &account_path).await
+    }
+
+    /// The mnemonic of a generated wallet. It is only returned once, so it
+    /// has to be backed up by the caller.
+    pub fn take_mnemonic(&mut self) -> Option<Vec<String>> {
+        self.mnemonic.take()
+    }
+
+    /// Derive the first key of the account from the BIP32 master key of the
+    /// seed.
+    ///
+    /// ### Errors
+    /// If the seed or the path do not produce a valid key.
+    pub fn from_seed(
+        seed: &[u8],
+        network: Network,
+        account_path: &DerivationPath,
+    ) -> Result<Self, Error> {
+        let master =
+            Xpriv::new_master(network, seed).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Self::from_master(master, account_path.clone(), 0)
+    }
+
+    /// Restore the first key of the account from a stored master key.
+    ///
+    /// ### Errors
+    /// If the path does not produce a valid key.
+    pub(crate) fn from_master_key(
+        master: Xpriv,
+        account_path: DerivationPath,
+    ) -> Result<Self, Error> {
+        Self::from_master(master, account_path, 0)
+    }
+
+    /// The master key and account path, everything needed to restore the
+    /// wallet with [`GuardianWallet::from_master_key`].
+    pub(crate) fn master_key(&self) -> (Xpriv, &DerivationPath) {
+        (self.master, &self.account_path)
+    }
+
+    fn from_master(master: Xpriv, account_path: DerivationPath, index: u32) -> Result<Self, Error> {
+        let path = receive_path(&account_path, index)?;
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let xpriv = master
+            .derive_priv(&secp, &path)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        // The bitcoin crate is on another secp256k1 release, keys cross over
+        // as bytes
+        let sk = SecretKey::from_slice(&xpriv.private_key.secret_bytes())
+            .expect("Derived key is a valid secret key");
+        let pk = PublicKey::from_secret_key(&Secp256k1::new(), &sk);
+
+        Ok(Self {
+            master,
+            account_path,
+            index,
+            sk,
+            pk,
+            mnemonic: None,
+        })
     }
 
-    pub fn public_key_commitment(&self) -> RecipientKey {
-        super::crypto::hash_160(&self.public_key_bytes())
+    /// The key at the index of the same account, e.g. one per locker.
+    ///
+    /// ### Errors
+    /// If the index is hardened or does not produce a valid key.
+    pub fn derive(&self, index: u32) -> Result<Self, Error> {
+        Self::from_master(self.master, self.account_path.clone(), index)
     }
 
-    pub fn sign(&self, hashed_data: [u8; 32]) -> Result<SerializedSignature, Error> {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    /// Full derivation path of the key from the master key.
+    pub fn derivation_path(&self) -> DerivationPath {
+        receive_path(&self.account_path, self.index).expect("Index was derived before")
+    }
+
+    /// Extended public key of the account, to watch or recover the guardian
+    /// keys in another wallet.
+    ///
+    /// ### Errors
+    /// If the account key cannot be derived.
+    pub fn account_xpub(&self) -> Result<Xpub, Error> {
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let account = self
+            .master
+            .derive_priv(&secp, &self.account_path)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+        Ok(Xpub::from_priv(&secp, &account))
+    }
+
+    /// Fingerprint of the master key, identifying the guardian seed.
+    pub fn fingerprint(&self) -> Fingerprint {
+        self.master
+            .fingerprint(&bitcoin::secp256k1::Secp256k1::new())
+    }
+
+    /// Key origin of the key in descriptor form, `[fingerprint/path]`.
+    pub fn key_origin(&self) -> String {
+        format!("[{}/{}]", self.fingerprint(), self.derivation_path())
+    }
+}
+
+impl Signer for GuardianWallet {
+    fn public_key(&self) -> PublicKey {
+        self.pk
+    }
+
+    fn sign(&self, hashed_data: [u8; 32]) -> Result<SerializedSignature, Error> {
         let secp = Secp256k1::new();
         let message = Message::from_digest(hashed_data);
         let signature = secp.sign_ecdsa(&message, &self.sk);
 
         Ok(signature.serialize_der())
     }
+
+    fn sign_schnorr(&self, hashed_data: [u8; 32]) -> Result<schnorr::Signature, Error> {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &self.sk);
+        let signature = secp.sign_schnorr_no_aux_rand(&hashed_data, &keypair);
+
+        Ok(signature)
+    }
+
+    fn sign_psbt(&self, psbt: &mut Psbt) -> Result<(), Error> {
+        super::psbt::sign_psbt(psbt, self)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use bitcoin::hex::DisplayHex;
+
     use super::*;
 
     #[tokio::test]
@@ -74,6 +304,83 @@ mod tests {
         assert_eq!(wallet.public_key().serialize().len(), 33);
     }
 
+    #[tokio::test]
+    async fn test_bip84_derivation() {
+        // BIP84 test vector of the "abandon ... about" mnemonic
+        let mnemonic = secret::generate_secret(&[0u8; 16]).await.unwrap();
+        let seed = mnemonic_to_seed(&mnemonic, None).unwrap();
+        let account_path = default_account_path(Network::Bitcoin);
+        let wallet = GuardianWallet::from_seed(&seed, Network::Bitcoin, &account_path).unwrap();
+
+        assert_eq!(
+            wallet.account_xpub().unwrap().to_string(),
+            "xpub6CatWdiZiodmUeTDp8LT5or8nmbKNcuyvz7WyksVFkKB4RHwCD3XyuvPEbvqAQY3rAPshWcMLoP2fMFMKHPJ4ZeZXYVUhLv1VMrjPC7PW6V"
+        );
+        assert_eq!(
+            wallet.p2wpkh_address(Network::Bitcoin).to_string(),
+            "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu"
+        );
+        let second = wallet.derive(1).unwrap();
+        assert_eq!(
+            second.p2wpkh_address(Network::Bitcoin).to_string(),
+            "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g"
+        );
+        assert_eq!(second.derivation_path().to_string(), "84'/0'/0'/0/1");
+        assert!(second.key_origin().starts_with("[73c5da0a/84'/0'/0'/0/1"));
+        assert!(wallet.derive(1 << 31).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mnemonic_to_seed_passphrase() {
+        // BIP39 test vector of the "abandon ... about" mnemonic
+        let mnemonic = secret::generate_secret(&[0u8; 16]).await.unwrap();
+        let seed = mnemonic_to_seed(&mnemonic, Some("TREZOR")).unwrap();
+        assert_eq!(
+            seed.to_lower_hex_string(),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        assert_ne!(seed, mnemonic_to_seed(&mnemonic, None).unwrap());
+        assert_eq!(
+            mnemonic_to_seed(&mnemonic, Some("")).unwrap(),
+            mnemonic_to_seed(&mnemonic, None).unwrap()
+        );
+
+        // Composed and decomposed forms of the passphrase are the same
+        let composed = mnemonic_to_seed(&mnemonic, Some("caf\u{e9}")).unwrap();
+        let decomposed = mnemonic_to_seed(&mnemonic, Some("cafe\u{301}")).unwrap();
+        assert_eq!(composed, decomposed);
+    }
+
+    #[tokio::test]
+    async fn test_from_mnemonic() {
+        let mut wallet = GuardianWallet::generate_with_passphrase(None)
+            .await
+            .unwrap();
+        let mnemonic = wallet.take_mnemonic().unwrap();
+        assert!(wallet.take_mnemonic().is_none());
+        assert!(!format!("{:?}", wallet).contains(&mnemonic.join(" ")));
+
+        let (network, account_path) = settings_account().unwrap();
+        let restored =
+            GuardianWallet::from_mnemonic(mnemonic.clone(), None, network, &account_path)
+                .await
+                .unwrap();
+        assert_eq!(restored.public_key(), wallet.public_key());
+        assert!(restored.mnemonic.is_none());
+
+        // The passphrase derives another wallet from the same words
+        let protected =
+            GuardianWallet::from_mnemonic(mnemonic.clone(), Some("TREZOR"), network, &account_path)
+                .await
+                .unwrap();
+        assert_ne!(protected.public_key(), wallet.public_key());
+
+        let mut invalid = mnemonic;
+        invalid[0] = "notaword".to_string();
+        let invalid = GuardianWallet::from_mnemonic(invalid, None, network, &account_path).await;
+        assert!(invalid.is_err());
+    }
+
     #[tokio::test]
     async fn test_public_key_commitment() {
         let wallet = GuardianWallet::generate_new().await.unwrap();
@@ -89,4 +396,16 @@ mod tests {
         assert!(signature.is_ok());
         assert_eq!(signature.unwrap().len(), 71);
     }
+
+    #[tokio::test]
+    async fn test_sign_schnorr() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let hashed_data = [1u8; 32];
+        let signature = wallet.sign_schnorr(hashed_data).unwrap();
+
+        let secp = Secp256k1::new();
+        assert!(secp
+            .verify_schnorr(&signature, &hashed_data, &wallet.x_only_public_key())
+            .is_ok());
+    }
 }
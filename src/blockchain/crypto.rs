@@ -1,8 +1,72 @@
+use bitcoin::opcodes::{all::*, Opcode};
 use ripemd::{Digest, Ripemd160};
+use serde::{Deserialize, Serialize};
 use sha2::Sha256;
 
 use super::types::RecipientKey;
 
+/// Hash function a locker uses to commit to its secret. Each one matches the
+/// script opcode that checks the preimage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Hash256,
+    Hash160,
+    Ripemd160,
+}
+
+impl HashAlgorithm {
+    /// Returns the hash of the input as committed in the locker script.
+    pub fn digest(&self, input: &[u8]) -> Vec<u8> {
+        match self {
+            HashAlgorithm::Sha256 => sha256(input).to_vec(),
+            HashAlgorithm::Hash256 => hash_256(input).to_vec(),
+            HashAlgorithm::Hash160 => hash_160(input).to_vec(),
+            HashAlgorithm::Ripemd160 => ripemd160(input).to_vec(),
+        }
+    }
+
+    /// Returns the size of the hash in bytes.
+    pub fn digest_size(&self) -> usize {
+        match self {
+            HashAlgorithm::Sha256 | HashAlgorithm::Hash256 => 32,
+            HashAlgorithm::Hash160 | HashAlgorithm::Ripemd160 => 20,
+        }
+    }
+
+    /// Returns the name of the hash function in policies and miniscript.
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Hash256 => "hash256",
+            HashAlgorithm::Hash160 => "hash160",
+            HashAlgorithm::Ripemd160 => "ripemd160",
+        }
+    }
+
+    /// Returns the opcode that checks the preimage in the script.
+    pub fn opcode(&self) -> Opcode {
+        match self {
+            HashAlgorithm::Sha256 => OP_SHA256,
+            HashAlgorithm::Hash256 => OP_HASH256,
+            HashAlgorithm::Hash160 => OP_HASH160,
+            HashAlgorithm::Ripemd160 => OP_RIPEMD160,
+        }
+    }
+
+    pub fn from_opcode(opcode: Opcode) -> Option<Self> {
+        match opcode {
+            OP_SHA256 => Some(HashAlgorithm::Sha256),
+            OP_HASH256 => Some(HashAlgorithm::Hash256),
+            OP_HASH160 => Some(HashAlgorithm::Hash160),
+            OP_RIPEMD160 => Some(HashAlgorithm::Ripemd160),
+            _ => None,
+        }
+    }
+}
+
 /// Returns the RIPEMD160 hash of the input.
 pub(super) fn ripemd160(input: &[u8]) -> RecipientKey {
     let mut hasher = Ripemd160::new();
@@ -31,6 +95,11 @@ pub(super) fn sha256(input: &[u8]) -> [u8; 32] {
     result.into()
 }
 
+/// Returns the double SHA256 of the input, as computed by OP_HASH256.
+pub(super) fn hash_256(input: &[u8]) -> [u8; 32] {
+    sha256(&sha256(input))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,6 +118,30 @@ mod tests {
         assert_eq!(output.len(), 20);
     }
 
+    #[test]
+    fn test_hash_algorithm_digest() {
+        let input = [0x00, 0x01, 0x02, 0x03];
+        assert_eq!(HashAlgorithm::Sha256.digest(&input), sha256(&input));
+        assert_eq!(
+            HashAlgorithm::Hash256.digest(&input),
+            sha256(&sha256(&input))
+        );
+        assert_eq!(HashAlgorithm::Hash160.digest(&input), hash_160(&input));
+        assert_eq!(HashAlgorithm::Ripemd160.digest(&input), ripemd160(&input));
+
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Hash256,
+            HashAlgorithm::Hash160,
+            HashAlgorithm::Ripemd160,
+        ] {
+            assert_eq!(
+                HashAlgorithm::from_opcode(algorithm.opcode()),
+                Some(algorithm)
+            );
+        }
+    }
+
     #[test]
     fn test_hash_160() {
         let input = [0x00, 0x01, 0x02, 0x03];
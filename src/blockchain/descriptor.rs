@@ -0,0 +1,450 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use bitcoin::{hex::DisplayHex, opcodes::all::OP_CHECKMULTISIG, script::Builder, Address, Network};
+use secp256k1::{PublicKey, XOnlyPublicKey};
+
+use super::{
+    crypto,
+    policy::{self, Policy},
+    taproot::{InternalKey, TaprootLocker},
+    transactions::{self, AddressType, EscrowKeys, HashLock},
+};
+
+/// Characters allowed in a descriptor, in the order used by the checksum.
+const INPUT_CHARSET: &str = "0123456789()[],'/*abcdefgh@:$%{}IJKLMNOPQRSTUVWXYZ&+-.;<=>?!^_|~ijklmnopqrstuvwxyzABCDEFGH`#\"\\ ";
+
+const CHECKSUM_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+const CHECKSUM_GENERATOR: [u64; 5] = [
+    0xf5dee51989,
+    0xa9fdca3312,
+    0x1bab10e32d,
+    0x3706b1677a,
+    0x644d626ffd,
+];
+
+/// Output descriptor of a locker, as understood by Bitcoin Core
+/// `importdescriptors`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockerDescriptor {
+    /// `wsh(and_v(v:sha256(H),pkh(KEY)))`, wrapped in `sh()` for nested
+    /// lockers, used for hash lock lockers
+    HashLock {
+        hash_lock: HashLock,
+        recipient: PublicKey,
+        address_type: AddressType,
+    },
+
+    /// `wsh(multi(k,KEY,...))`, used for escrow lockers
+    WshMulti {
+        threshold: usize,
+        keys: Vec<PublicKey>,
+    },
+
+    /// `tr(KEY,{HASH_LOCK,REFUND})` with both tapleaves, used for taproot
+    /// lockers
+    Tr(TaprootLocker),
+}
+
+fn polymod(symbols: impl Iterator<Item = u64>) -> u64 {
+    symbols.fold(1, |checksum, symbol| {
+        let top = checksum >> 35;
+        let checksum = ((checksum & 0x7ffffffff) << 5) ^ symbol;
+
+        CHECKSUM_GENERATOR
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    })
+}
+
+/// Compute the BIP380 checksum of a descriptor without its `#` suffix.
+///
+/// ### Errors
+/// If the descriptor has a character outside of the descriptor charset.
+pub fn descriptor_checksum(descriptor: &str) -> Result<String, Error> {
+    let mut symbols = Vec::with_capacity(descriptor.len() * 4 / 3 + 9);
+    let mut groups = Vec::with_capacity(3);
+    for c in descriptor.chars() {
+        let position = INPUT_CHARSET.find(c).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid descriptor character {:?}", c),
+            )
+        })? as u64;
+        symbols.push(position & 31);
+        groups.push(position >> 5);
+        if groups.len() == 3 {
+            symbols.push(groups[0] * 9 + groups[1] * 3 + groups[2]);
+            groups.clear();
+        }
+    }
+    match groups.as_slice() {
+        [first] => symbols.push(*first),
+        [first, second] => symbols.push(first * 3 + second),
+        _ => {}
+    }
+
+    let checksum = polymod(symbols.into_iter().chain([0; 8])) ^ 1;
+    let checksum = (0..8)
+        .map(|i| CHECKSUM_CHARSET[((checksum >> (5 * (7 - i))) & 31) as usize] as char)
+        .collect();
+
+    Ok(checksum)
+}
+
+fn invalid_descriptor(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+fn parse_key<T: FromStr<Err = secp256k1::Error>>(key: &str) -> Result<T, Error> {
+    T::from_str(key).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+/// Miniscript of a hash lock and its recipient key, `and_v(v:sha256(H),KEY)`.
+fn hash_lock_fragment(hash_lock: &HashLock, key: &str) -> String {
+    format!("and_v(v:{},{})", Policy::Hash(hash_lock.clone()), key)
+}
+
+/// Parse `and_v(v:FRAGMENT,KEY)` into the policy of the fragment and the key
+/// argument of the given key fragment.
+fn parse_and_v<'a>(miniscript: &'a str, key_fragment: &str) -> Result<(Policy, &'a str), Error> {
+    let invalid = || invalid_descriptor("Unsupported locker miniscript");
+    let (name, fragments) = policy::split_fragment(miniscript)?;
+    let [first, second] = fragments.as_slice() else {
+        return Err(invalid());
+    };
+    if name != "and_v" {
+        return Err(invalid());
+    }
+    let condition = first.strip_prefix("v:").ok_or_else(invalid)?.parse()?;
+    let key = arguments(second, key_fragment).ok_or_else(invalid)?;
+
+    Ok((condition, key))
+}
+
+/// Returns the arguments of `name(...)`.
+fn arguments<'a>(descriptor: &'a str, name: &str) -> Option<&'a str> {
+    descriptor
+        .strip_prefix(name)?
+        .strip_prefix('(')?
+        .strip_suffix(')')
+}
+
+impl LockerDescriptor {
+    /// Describe a hash lock locker without refund.
+    pub fn hash_lock(
+        hash_lock: &HashLock,
+        recipient: PublicKey,
+        address_type: AddressType,
+    ) -> Self {
+        LockerDescriptor::HashLock {
+            hash_lock: hash_lock.clone(),
+            recipient,
+            address_type,
+        }
+    }
+
+    /// Describe a 2-of-3 escrow locker.
+    pub fn escrow(keys: &EscrowKeys) -> Self {
+        LockerDescriptor::WshMulti {
+            threshold: 2,
+            keys: keys.ordered().to_vec(),
+        }
+    }
+
+    /// Describe a taproot locker by its internal key and both tapleaves.
+    ///
+    /// ### Errors
+    /// If the internal key of the locker cannot be computed.
+    pub fn taproot(locker: &TaprootLocker) -> Result<Self, Error> {
+        Ok(LockerDescriptor::Tr(TaprootLocker {
+            internal_key: InternalKey::XOnly(locker.internal_key.x_only_public_key()?),
+            ..locker.clone()
+        }))
+    }
+
+    /// Reconstruct the locker address on the given network.
+    ///
+    /// ### Errors
+    /// If a multisig descriptor has an invalid threshold or the taproot tree
+    /// cannot be built.
+    pub fn address(&self, network: Network) -> Result<Address, Error> {
+        match self {
+            LockerDescriptor::HashLock {
+                hash_lock,
+                recipient,
+                address_type,
+            } => {
+                let recipient = crypto::hash_160(&recipient.serialize());
+                let script = transactions::hash_lock_contract(hash_lock, recipient, None);
+
+                Ok(address_type.address(&script, network))
+            }
+            LockerDescriptor::WshMulti { threshold, keys } => {
+                if *threshold == 0 || *threshold > keys.len() || keys.len() > 16 {
+                    return Err(invalid_descriptor("Invalid multisig threshold"));
+                }
+                let script = keys
+                    .iter()
+                    .fold(
+                        Builder::new().push_int(*threshold as i64),
+                        |builder, key| builder.push_slice(key.serialize()),
+                    )
+                    .push_int(keys.len() as i64)
+                    .push_opcode(OP_CHECKMULTISIG)
+                    .into_script();
+
+                Ok(transactions::p2wsh_address(&script, network))
+            }
+            LockerDescriptor::Tr(locker) => Ok(Address::p2tr_tweaked(
+                locker.spend_info()?.output_key(),
+                network,
+            )),
+        }
+    }
+
+    fn body(&self) -> Result<String, fmt::Error> {
+        let body = match self {
+            LockerDescriptor::HashLock {
+                hash_lock,
+                recipient,
+                address_type,
+            } => {
+                let key = format!("pkh({})", recipient.serialize().to_lower_hex_string());
+                let wsh = format!("wsh({})", hash_lock_fragment(hash_lock, &key));
+
+                match address_type {
+                    AddressType::P2wsh => wsh,
+                    AddressType::P2shP2wsh => format!("sh({})", wsh),
+                }
+            }
+            LockerDescriptor::WshMulti { threshold, keys } => {
+                let keys = keys
+                    .iter()
+                    .map(|key| key.serialize().to_lower_hex_string())
+                    .collect::<Vec<String>>();
+
+                format!("wsh(multi({},{}))", threshold, keys.join(","))
+            }
+            LockerDescriptor::Tr(locker) => {
+                let x_only_key = |key: &XOnlyPublicKey| key.serialize().to_lower_hex_string();
+                let internal_key = match &locker.internal_key {
+                    InternalKey::XOnly(key) => x_only_key(key),
+                    internal_key => {
+                        x_only_key(&internal_key.x_only_public_key().map_err(|_| fmt::Error)?)
+                    }
+                };
+                let hash_lock = hash_lock_fragment(
+                    &locker.hash_lock,
+                    &format!("pk({})", x_only_key(&locker.recipient)),
+                );
+                let refund = format!(
+                    "and_v(v:{},pk({}))",
+                    Policy::Timelock(locker.timelock),
+                    x_only_key(&locker.depositor)
+                );
+
+                format!("tr({},{{{},{}}})", internal_key, hash_lock, refund)
+            }
+        };
+
+        Ok(body)
+    }
+}
+
+impl fmt::Display for LockerDescriptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let body = self.body()?;
+        let checksum = descriptor_checksum(&body).map_err(|_| fmt::Error)?;
+
+        write!(f, "{}#{}", body, checksum)
+    }
+}
+
+impl FromStr for LockerDescriptor {
+    type Err = Error;
+
+    /// Parse a descriptor, which must carry a valid checksum.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (body, checksum) = s
+            .split_once('#')
+            .ok_or_else(|| invalid_descriptor("Descriptor is missing its checksum"))?;
+        if descriptor_checksum(body)? != checksum {
+            return Err(invalid_descriptor("Invalid descriptor checksum"));
+        }
+
+        if let Some(multi) = arguments(body, "wsh").and_then(|wsh| arguments(wsh, "multi")) {
+            let mut arguments = multi.split(',');
+            let threshold = arguments
+                .next()
+                .and_then(|threshold| threshold.parse().ok())
+                .ok_or_else(|| invalid_descriptor("Invalid multisig threshold"))?;
+            let keys = arguments
+                .map(|key| {
+                    PublicKey::from_str(key).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+                })
+                .collect::<Result<Vec<PublicKey>, Error>>()?;
+            return Ok(LockerDescriptor::WshMulti { threshold, keys });
+        }
+
+        let unsupported = || invalid_descriptor("Unsupported locker miniscript");
+        let (wsh, address_type) = match arguments(body, "sh") {
+            Some(sh) => (arguments(sh, "wsh"), AddressType::P2shP2wsh),
+            None => (arguments(body, "wsh"), AddressType::P2wsh),
+        };
+        if let Some(miniscript) = wsh {
+            let (Policy::Hash(hash_lock), recipient) = parse_and_v(miniscript, "pkh")? else {
+                return Err(unsupported());
+            };
+            return Ok(LockerDescriptor::HashLock {
+                hash_lock,
+                recipient: parse_key(recipient)?,
+                address_type,
+            });
+        }
+        if arguments(body, "tr").is_some() {
+            // The tree braces are not nesting for the fragment split, they
+            // stay on the first and last leaf
+            let (_, arguments) = policy::split_fragment(body)?;
+            let [internal_key, hash_lock, refund] = arguments.as_slice() else {
+                return Err(unsupported());
+            };
+            let (Some(hash_lock), Some(refund)) =
+                (hash_lock.strip_prefix('{'), refund.strip_suffix('}'))
+            else {
+                return Err(unsupported());
+            };
+            let (Policy::Hash(hash_lock), recipient) = parse_and_v(hash_lock, "pk")? else {
+                return Err(unsupported());
+            };
+            let (Policy::Timelock(timelock), depositor) = parse_and_v(refund, "pk")? else {
+                return Err(unsupported());
+            };
+            return Ok(LockerDescriptor::Tr(TaprootLocker {
+                hash_lock,
+                recipient: parse_key(recipient)?,
+                depositor: parse_key(depositor)?,
+                timelock,
+                internal_key: InternalKey::XOnly(parse_key(internal_key)?),
+            }));
+        }
+
+        Err(invalid_descriptor("Unsupported locker descriptor"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::relative;
+
+    use super::*;
+    use crate::blockchain::{
+        address::GuardianWallet, crypto::HashAlgorithm, signer::Signer, transactions::Timelock,
+    };
+
+    #[test]
+    fn test_descriptor_checksum() {
+        // Vectors from BIP380 and the Bitcoin Core functional tests
+        assert_eq!(descriptor_checksum("raw(deadbeef)").unwrap(), "89f8spxm");
+        assert_eq!(
+            descriptor_checksum("addr(mkmZxiEcEd8ZqjQWVZuC6so5dFMKEFpN2j)").unwrap(),
+            "02wpgw69"
+        );
+        assert!(descriptor_checksum("raw(\u{e9})").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_hash_lock_descriptor() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let hash_lock = HashLock::new(HashAlgorithm::Sha256, &[1u8; 32]);
+        let network = crate::settings::get_settings().network;
+        for address_type in [AddressType::P2wsh, AddressType::P2shP2wsh] {
+            let witness_script = transactions::hash_lock_contract(
+                &hash_lock,
+                guardian.public_key_commitment(),
+                None,
+            );
+            let address = address_type.address(&witness_script, network);
+            let descriptor =
+                LockerDescriptor::hash_lock(&hash_lock, guardian.public_key(), address_type);
+            let exported = descriptor.to_string();
+
+            let imported = LockerDescriptor::from_str(&exported).unwrap();
+            assert_eq!(imported, descriptor);
+            assert_eq!(imported.address(network).unwrap(), address);
+
+            let tampered = exported.replace("pkh(", "pkh( ");
+            assert!(LockerDescriptor::from_str(&tampered).is_err());
+        }
+
+        let exported =
+            LockerDescriptor::hash_lock(&hash_lock, guardian.public_key(), AddressType::P2wsh)
+                .to_string();
+        let (body, _) = exported.split_once('#').unwrap();
+        assert_eq!(
+            body,
+            format!(
+                "wsh(and_v(v:sha256({}),pkh({})))",
+                hash_lock.hash.to_lower_hex_string(),
+                guardian.public_key()
+            )
+        );
+        assert!(LockerDescriptor::from_str(body).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_escrow_descriptor() {
+        let keys = EscrowKeys::new(
+            GuardianWallet::generate_new().await.unwrap().public_key(),
+            GuardianWallet::generate_new().await.unwrap().public_key(),
+            GuardianWallet::generate_new().await.unwrap().public_key(),
+        )
+        .unwrap();
+        let descriptor = LockerDescriptor::escrow(&keys);
+        let exported = descriptor.to_string();
+        assert!(exported.starts_with("wsh(multi(2,"));
+
+        let imported = LockerDescriptor::from_str(&exported).unwrap();
+        assert_eq!(
+            imported.address(Network::Regtest).unwrap(),
+            transactions::p2wsh_address(
+                &transactions::generate_escrow_witness_script(&keys).unwrap(),
+                Network::Regtest
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn test_taproot_descriptor() {
+        let recipient = GuardianWallet::generate_new().await.unwrap();
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let locker = TaprootLocker {
+            hash_lock: HashLock::new(HashAlgorithm::Sha256, &[7u8; 32]),
+            recipient: recipient.x_only_public_key(),
+            depositor: depositor.x_only_public_key(),
+            timelock: Timelock::Relative(relative::LockTime::from_height(144)),
+            internal_key: InternalKey::Nums,
+        };
+        let exported = LockerDescriptor::taproot(&locker).unwrap().to_string();
+        assert!(exported.starts_with(
+            "tr(50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0,{and_v(v:sha256("
+        ));
+        assert!(exported.contains(&format!(
+            "and_v(v:older(144),pk({}))}})#",
+            depositor.x_only_public_key()
+        )));
+
+        let imported = LockerDescriptor::from_str(&exported).unwrap();
+        let network = crate::settings::get_settings().network;
+        assert_eq!(
+            imported.address(network).unwrap(),
+            locker.address().unwrap()
+        );
+    }
+}
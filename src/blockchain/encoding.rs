@@ -0,0 +1,110 @@
+//! Serde helpers for the bitcoin types kept in stored records, which are
+//! written as strings, hex or satoshis so the records stay readable.
+
+use std::{fmt::Display, str::FromStr};
+
+use bitcoin::{absolute, address::NetworkUnchecked, relative, Address, Amount};
+use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+/// Serialize bytes as lowercase hex.
+pub(crate) mod hex_bytes {
+    use bitcoin::hex::{DisplayHex, FromHex};
+
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bytes: impl AsRef<[u8]>, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&bytes.as_ref().to_lower_hex_string())
+    }
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: TryFrom<Vec<u8>>,
+    {
+        let hex = String::deserialize(d)?;
+        let bytes = Vec::<u8>::from_hex(&hex).map_err(D::Error::custom)?;
+
+        T::try_from(bytes).map_err(|_| D::Error::custom("Invalid length"))
+    }
+}
+
+/// Serialize an amount as satoshis.
+pub(crate) mod sat {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(amount: &Amount, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(amount.to_sat())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Amount, D::Error> {
+        u64::deserialize(d).map(Amount::from_sat)
+    }
+}
+
+/// Serialize through the string form of the type.
+pub(crate) mod string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &impl Display, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D, T>(d: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: FromStr,
+        T::Err: Display,
+    {
+        String::deserialize(d)?.parse().map_err(D::Error::custom)
+    }
+}
+
+/// Serialize an address as its string. The network is not known here, the
+/// record holding the address has to check it.
+pub(crate) mod address {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(address: &Address, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(address)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Address, D::Error> {
+        let address = string::deserialize::<D, Address<NetworkUnchecked>>(d)?;
+
+        Ok(address.assume_checked())
+    }
+}
+
+/// Serialize an absolute lock time as its consensus value.
+pub(crate) mod absolute_lock_time {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        lock_time: &absolute::LockTime,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.serialize_u32(lock_time.to_consensus_u32())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<absolute::LockTime, D::Error> {
+        u32::deserialize(d).map(absolute::LockTime::from_consensus)
+    }
+}
+
+/// Serialize a relative lock time as its consensus sequence value.
+pub(crate) mod relative_lock_time {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        lock_time: &relative::LockTime,
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        s.serialize_u32(lock_time.to_consensus_u32())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<relative::LockTime, D::Error> {
+        let sequence = u32::deserialize(d)?;
+
+        relative::LockTime::from_consensus(sequence).map_err(D::Error::custom)
+    }
+}
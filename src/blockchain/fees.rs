@@ -0,0 +1,286 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{
+    absolute, transaction::Version, Address, Amount, FeeRate, Network, OutPoint, ScriptBuf,
+    Sequence, Transaction, TxIn, TxOut, Witness,
+};
+
+use super::{funding, signer::Signer};
+
+/// Fee rates in sat/vB used when the node has no estimate, by the largest
+/// confirmation target in blocks they apply to.
+const FALLBACK_FEE_RATES: [(u16, u64); 4] = [(2, 25), (6, 12), (24, 6), (144, 2)];
+
+/// Extra fee rate a replacement pays for its own relay, as required by BIP125.
+const INCREMENTAL_RELAY_FEE: FeeRate = FeeRate::BROADCAST_MIN;
+
+/// Fee rate to use for the confirmation target when the node cannot estimate
+/// one.
+pub fn fallback_fee_rate(target_blocks: u16) -> FeeRate {
+    let sat_per_vb = FALLBACK_FEE_RATES
+        .iter()
+        .find(|(target, _)| target_blocks <= *target)
+        .map_or(1, |(_, sat_per_vb)| *sat_per_vb);
+
+    FeeRate::from_sat_per_kwu(sat_per_vb * 250)
+}
+
+/// Convert an `estimatesmartfee` rate in BTC/kvB, never going below the
+/// minimum relay fee.
+pub(super) fn fee_rate_from_btc_per_kvb(btc_per_kvb: f64) -> Option<FeeRate> {
+    let sat_per_kvb = Amount::from_btc(btc_per_kvb).ok()?.to_sat();
+    let fee_rate = FeeRate::from_sat_per_kwu(sat_per_kvb.div_ceil(4));
+
+    Some(fee_rate.max(FeeRate::BROADCAST_MIN))
+}
+
+/// Fee paid by the transaction, given the total value of the outputs it
+/// spends.
+///
+/// ### Errors
+/// If the outputs of the transaction are worth more than it spends.
+pub fn transaction_fee(tx: &Transaction, spent_value: Amount) -> Result<Amount, Error> {
+    tx.output
+        .iter()
+        .try_fold(Amount::ZERO, |total, output| {
+            total.checked_add(output.value)
+        })
+        .and_then(|output_total| spent_value.checked_sub(output_total))
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Transaction outputs exceed the spent value",
+            )
+        })
+}
+
+/// Fee rate for a replacement of the transaction, at least the requested
+/// one.
+///
+/// Following BIP125 the replacement pays the whole fee of the original plus
+/// the incremental relay fee for its own size. Locker claims and refunds are
+/// rebuilt with the same witness, so paying this rate on the rebuilt spend
+/// covers it.
+///
+/// ### Errors
+/// If the transaction does not signal replacement or spends less than its
+/// outputs.
+pub fn replacement_fee_rate(
+    original: &Transaction,
+    spent_value: Amount,
+    fee_rate: FeeRate,
+) -> Result<FeeRate, Error> {
+    if !original.is_explicitly_rbf() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Transaction does not signal replacement",
+        ));
+    }
+    let weight = original.weight();
+    let original_fee = transaction_fee(original, spent_value)?;
+    let required_fee = INCREMENTAL_RELAY_FEE
+        .fee_vb(original.vsize() as u64)
+        .and_then(|relay_fee| original_fee.checked_add(relay_fee))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Fee rate is too high"))?;
+
+    // Rounded up so the replacement fee is never below the required one
+    let required =
+        FeeRate::from_sat_per_kwu((required_fee.to_sat() * 1000).div_ceil(weight.to_wu()));
+
+    Ok(fee_rate.max(required))
+}
+
+/// Build a child transaction spending an output of a stuck parent, so both
+/// confirm together at the fee rate.
+///
+/// The child pays the fee the parent is missing for the package on top of
+/// its own fee. The parent output must pay to the P2WPKH address of the
+/// wallet, e.g. a claim sent to the hot wallet.
+///
+/// ### Errors
+/// If the output does not exist or is not locked to the wallet, its value
+/// does not cover the child fee, or the signature fails.
+pub fn build_cpfp_transaction(
+    parent: &Transaction,
+    vout: u32,
+    parent_fee: Amount,
+    fee_rate: FeeRate,
+    wallet: &dyn Signer,
+    destination: &Address,
+    network: Network,
+) -> Result<Transaction, Error> {
+    let spent = parent
+        .output
+        .get(vout as usize)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Output does not exist"))?;
+    if spent.script_pubkey != wallet.p2wpkh_address(network).script_pubkey() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Output is not locked to the wallet",
+        ));
+    }
+
+    let mut child = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint::new(parent.compute_txid(), vout),
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: destination.script_pubkey(),
+        }],
+    };
+
+    let child_weight = funding::base_weight(&child.output) + funding::input_weight();
+    let fee_too_high = || Error::new(ErrorKind::InvalidInput, "Fee rate is too high");
+    let package_fee = fee_rate
+        .fee_wu(parent.weight() + child_weight)
+        .ok_or_else(fee_too_high)?;
+    let child_fee = fee_rate
+        .fee_wu(child_weight)
+        .ok_or_else(fee_too_high)?
+        .max(package_fee.checked_sub(parent_fee).unwrap_or(Amount::ZERO));
+
+    child.output[0].value = spent
+        .value
+        .checked_sub(child_fee)
+        .filter(|value| *value >= child.output[0].script_pubkey.minimal_non_dust())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Output value does not cover the child fee",
+            )
+        })?;
+    child.input[0].witness = funding::p2wpkh_witness(&child, 0, spent, wallet)?;
+
+    Ok(child)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{
+        address::GuardianWallet,
+        crypto::HashAlgorithm,
+        transactions::{self, AddressType},
+    };
+
+    #[test]
+    fn test_fee_rates() {
+        assert_eq!(fallback_fee_rate(1), FeeRate::from_sat_per_kwu(25 * 250));
+        assert_eq!(fallback_fee_rate(6), FeeRate::from_sat_per_kwu(12 * 250));
+        assert_eq!(fallback_fee_rate(1008), FeeRate::BROADCAST_MIN);
+
+        assert_eq!(
+            fee_rate_from_btc_per_kvb(0.0002),
+            Some(FeeRate::from_sat_per_kwu(20 * 250))
+        );
+        assert_eq!(
+            fee_rate_from_btc_per_kvb(0.000001),
+            Some(FeeRate::BROADCAST_MIN)
+        );
+        assert_eq!(fee_rate_from_btc_per_kvb(-1.0), None);
+    }
+
+    #[tokio::test]
+    async fn test_replace_claim() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let claim = |fee_rate| {
+            transactions::build_secret_claim_transaction(
+                OutPoint::null(),
+                value,
+                &guardian.p2wpkh_address(Network::Regtest),
+                fee_rate,
+                &[7u8; 32],
+                HashAlgorithm::Sha256,
+                &guardian,
+                None,
+                AddressType::P2wsh,
+            )
+            .unwrap()
+        };
+
+        let original = claim(FeeRate::from_sat_per_kwu(2 * 250));
+        let original_fee = transaction_fee(&original, value).unwrap();
+
+        // A replacement at the same rate still has to pay for its relay
+        let fee_rate =
+            replacement_fee_rate(&original, value, FeeRate::from_sat_per_kwu(2 * 250)).unwrap();
+        let replacement = claim(fee_rate);
+        let replacement_fee = transaction_fee(&replacement, value).unwrap();
+        assert!(replacement_fee >= original_fee + Amount::from_sat(original.vsize() as u64));
+
+        let fee_rate =
+            replacement_fee_rate(&original, value, FeeRate::from_sat_per_kwu(50 * 250)).unwrap();
+        assert_eq!(fee_rate, FeeRate::from_sat_per_kwu(50 * 250));
+
+        let mut final_tx = original.clone();
+        final_tx.input[0].sequence = Sequence::MAX;
+        assert!(replacement_fee_rate(&final_tx, value, fee_rate).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cpfp_child() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let parent = transactions::build_secret_claim_transaction(
+            OutPoint::null(),
+            value,
+            &guardian.p2wpkh_address(Network::Regtest),
+            FeeRate::from_sat_per_kwu(250),
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            &guardian,
+            None,
+            AddressType::P2wsh,
+        )
+        .unwrap();
+        let parent_fee = transaction_fee(&parent, value).unwrap();
+        let fee_rate = FeeRate::from_sat_per_kwu(20 * 250);
+        let destination = guardian.p2wpkh_address(Network::Regtest);
+
+        let child = build_cpfp_transaction(
+            &parent,
+            0,
+            parent_fee,
+            fee_rate,
+            &guardian,
+            &destination,
+            Network::Regtest,
+        )
+        .unwrap();
+        assert_eq!(child.input[0].previous_output.txid, parent.compute_txid());
+
+        // The package pays the target rate
+        let child_fee = transaction_fee(&child, parent.output[0].value).unwrap();
+        let package_weight = parent.weight() + child.weight();
+        assert!(parent_fee + child_fee >= fee_rate.fee_wu(package_weight).unwrap());
+
+        let other = GuardianWallet::generate_new().await.unwrap();
+        let foreign = build_cpfp_transaction(
+            &parent,
+            0,
+            parent_fee,
+            fee_rate,
+            &other,
+            &destination,
+            Network::Regtest,
+        );
+        assert!(foreign.is_err());
+        assert!(build_cpfp_transaction(
+            &parent,
+            1,
+            parent_fee,
+            fee_rate,
+            &guardian,
+            &destination,
+            Network::Regtest,
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,490 @@
+use std::{
+    cmp::Reverse,
+    io::{Error, ErrorKind},
+};
+
+use bitcoin::{
+    absolute,
+    hashes::Hash,
+    sighash::{EcdsaSighashType, SighashCache},
+    transaction::Version,
+    Address, Amount, FeeRate, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Weight, Witness,
+};
+
+use super::{
+    signer::Signer,
+    transactions::{MAX_SIGNATURE_SIZE, PUBLIC_KEY_SIZE},
+    validation,
+};
+
+/// Branches explored by branch and bound before falling back.
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// Output the hot wallet can spend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+}
+
+/// Inputs picked to fund the payments, and the change left after the fee.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoinSelection {
+    pub inputs: Vec<Utxo>,
+    pub change: Option<Amount>,
+}
+
+fn fee(fee_rate: FeeRate, weight: Weight) -> Result<Amount, Error> {
+    fee_rate
+        .fee_wu(weight)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Fee rate is too high"))
+}
+
+/// Add up amounts of the selection, or fail if they overflow.
+fn checked_sum(amounts: impl IntoIterator<Item = Amount>) -> Result<Amount, Error> {
+    amounts
+        .into_iter()
+        .try_fold(Amount::ZERO, |total, amount| total.checked_add(amount))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Amounts overflow"))
+}
+
+/// Weight of a P2WPKH input with the largest possible signature.
+pub(super) fn input_weight() -> Weight {
+    let mut witness = Witness::new();
+    witness.push([0u8; MAX_SIGNATURE_SIZE]);
+    witness.push([0u8; PUBLIC_KEY_SIZE]);
+
+    TxIn {
+        witness,
+        ..TxIn::default()
+    }
+    .segwit_weight()
+}
+
+/// Sign the P2WPKH input of the transaction following BIP143 and return its
+/// witness.
+pub(super) fn p2wpkh_witness(
+    tx: &Transaction,
+    index: usize,
+    spent: &TxOut,
+    wallet: &dyn Signer,
+) -> Result<Witness, Error> {
+    let sighash = SighashCache::new(tx)
+        .p2wpkh_signature_hash(
+            index,
+            &spent.script_pubkey,
+            spent.value,
+            EcdsaSighashType::All,
+        )
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let mut signature = wallet.sign(sighash.to_byte_array())?.to_vec();
+    signature.push(EcdsaSighashType::All.to_u32() as u8);
+
+    let mut witness = Witness::new();
+    witness.push(signature);
+    witness.push(wallet.public_key().serialize());
+
+    Ok(witness)
+}
+
+/// Weight of the transaction paying the outputs, before any input is added.
+pub(super) fn base_weight(outputs: &[TxOut]) -> Weight {
+    let tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![],
+        output: outputs.to_vec(),
+    };
+
+    // Segwit marker and flag
+    tx.weight() + Weight::from_wu(2)
+}
+
+/// Depth first search for the subset of values closest to the target without
+/// going over the upper bound. Values are sorted from largest to smallest.
+#[allow(clippy::too_many_arguments)]
+fn branch_and_bound(
+    values: &[u64],
+    index: usize,
+    current: u64,
+    remaining: u64,
+    target: u64,
+    upper_bound: u64,
+    selected: &mut Vec<usize>,
+    tries: &mut usize,
+    best: &mut Option<(u64, Vec<usize>)>,
+) {
+    *tries += 1;
+    if *tries > BNB_MAX_TRIES || current > upper_bound {
+        return;
+    }
+    if current >= target {
+        let waste = current - target;
+        if best
+            .as_ref()
+            .is_none_or(|(best_waste, _)| waste < *best_waste)
+        {
+            *best = Some((waste, selected.clone()));
+        }
+        return;
+    }
+    if index == values.len() || current + remaining < target {
+        return;
+    }
+
+    let remaining = remaining - values[index];
+    selected.push(index);
+    branch_and_bound(
+        values,
+        index + 1,
+        current + values[index],
+        remaining,
+        target,
+        upper_bound,
+        selected,
+        tries,
+        best,
+    );
+    selected.pop();
+    branch_and_bound(
+        values,
+        index + 1,
+        current,
+        remaining,
+        target,
+        upper_bound,
+        selected,
+        tries,
+        best,
+    );
+}
+
+/// Pick the UTXOs that fund the payments at the fee rate.
+///
+/// Branch and bound looks for a set of inputs that pays the outputs and fee
+/// without leaving enough for a change output. When there is none, the
+/// largest UTXOs are used and the excess is sent back to the change script,
+/// unless it would be dust.
+///
+/// ### Errors
+/// If the UTXOs do not cover the payments and the fee, or the amounts or the
+/// fee rate overflow.
+pub fn select_coins(
+    utxos: &[Utxo],
+    payments: &[TxOut],
+    fee_rate: FeeRate,
+    change_script: &Script,
+) -> Result<CoinSelection, Error> {
+    let payment_total = checked_sum(payments.iter().map(|payment| payment.value))?;
+    let target = checked_sum([payment_total, fee(fee_rate, base_weight(payments))?])?;
+    let input_fee = fee(fee_rate, input_weight())?;
+    let change_output = TxOut {
+        value: Amount::ZERO,
+        script_pubkey: change_script.to_owned(),
+    };
+    let change_fee = fee(fee_rate, change_output.weight())?;
+    let upper_bound = checked_sum([target, change_fee, input_fee])?;
+
+    // Inputs are compared by the value left once they paid for themselves
+    let mut candidates = utxos
+        .iter()
+        .filter(|utxo| utxo.txout.value > input_fee)
+        .map(|utxo| (utxo, (utxo.txout.value - input_fee).to_sat()))
+        .collect::<Vec<(&Utxo, u64)>>();
+    candidates.sort_by_key(|(_, value)| Reverse(*value));
+    let values = candidates
+        .iter()
+        .map(|(_, value)| *value)
+        .collect::<Vec<u64>>();
+
+    let mut best = None;
+    branch_and_bound(
+        &values,
+        0,
+        0,
+        checked_sum(values.iter().copied().map(Amount::from_sat))?.to_sat(),
+        target.to_sat(),
+        upper_bound.to_sat(),
+        &mut Vec::new(),
+        &mut 0,
+        &mut best,
+    );
+    if let Some((_, selected)) = best {
+        return Ok(CoinSelection {
+            inputs: selected
+                .into_iter()
+                .map(|i| candidates[i].0.clone())
+                .collect(),
+            change: None,
+        });
+    }
+
+    let mut inputs = vec![];
+    let mut total = Amount::ZERO;
+    for (utxo, value) in candidates {
+        inputs.push(utxo.clone());
+        total = checked_sum([total, Amount::from_sat(value)])?;
+        if total >= target {
+            let change = (total - target)
+                .checked_sub(change_fee)
+                .filter(|change| *change >= change_script.minimal_non_dust());
+
+            return Ok(CoinSelection { inputs, change });
+        }
+    }
+
+    Err(Error::new(
+        ErrorKind::InvalidInput,
+        "Insufficient funds for the payments and fee",
+    ))
+}
+
+/// Build a signed transaction funding the locker addresses from the UTXOs of
+/// the wallet. Change goes back to the P2WPKH address of the wallet.
+///
+/// ### Errors
+/// If a UTXO is not locked to the wallet, a payment is dust, the UTXOs do not
+/// cover the payments and the fee, or signing fails.
+pub fn build_funding_transaction(
+    utxos: &[Utxo],
+    payments: &[(Address, Amount)],
+    fee_rate: FeeRate,
+    wallet: &dyn Signer,
+    network: Network,
+) -> Result<Transaction, Error> {
+    let wallet_script = wallet.p2wpkh_address(network).script_pubkey();
+    if utxos
+        .iter()
+        .any(|utxo| utxo.txout.script_pubkey != wallet_script)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "UTXO is not locked to the wallet",
+        ));
+    }
+    if payments.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "No payments to fund"));
+    }
+
+    let mut output = payments
+        .iter()
+        .map(|(address, value)| TxOut {
+            value: *value,
+            script_pubkey: address.script_pubkey(),
+        })
+        .collect::<Vec<TxOut>>();
+    // Outputs below the dust threshold are not relayed
+    for payment in &output {
+        validation::validate_amount(
+            payment.value,
+            &payment.script_pubkey,
+            Amount::ZERO,
+            Amount::MAX_MONEY,
+        )?;
+    }
+    let selection = select_coins(utxos, &output, fee_rate, &wallet_script)?;
+    if let Some(change) = selection.change {
+        output.push(TxOut {
+            value: change,
+            script_pubkey: wallet_script.clone(),
+        });
+    }
+
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: selection
+            .inputs
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            })
+            .collect(),
+        output,
+    };
+
+    let witnesses = selection
+        .inputs
+        .iter()
+        .enumerate()
+        .map(|(index, utxo)| p2wpkh_witness(&tx, index, &utxo.txout, wallet))
+        .collect::<Result<Vec<Witness>, Error>>()?;
+    for (input, witness) in tx.input.iter_mut().zip(witnesses) {
+        input.witness = witness;
+    }
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        ecdsa,
+        secp256k1::{Message, Secp256k1},
+        Txid,
+    };
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, crypto::HashAlgorithm, transactions};
+
+    fn utxos(wallet: &dyn Signer, values: &[u64]) -> Vec<Utxo> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(vout, value)| Utxo {
+                outpoint: OutPoint::new(Txid::all_zeros(), vout as u32),
+                txout: TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: wallet.p2wpkh_address(Network::Regtest).script_pubkey(),
+                },
+            })
+            .collect()
+    }
+
+    fn locker(seed: u8) -> Address {
+        transactions::generate_p2wsh_address(&[seed; 32], HashAlgorithm::Sha256, [2u8; 20], None)
+            .unwrap()
+    }
+
+    fn payment(value: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: locker(1).script_pubkey(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_select_coins_without_change() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let payments = [payment(50_000)];
+        let target = 50_000
+            + fee(fee_rate, base_weight(&payments)).unwrap().to_sat()
+            + fee(fee_rate, input_weight()).unwrap().to_sat() * 2;
+
+        // Two inputs match the target exactly, the large one would need change
+        let utxos = utxos(&wallet, &[200_000, 20_000, target - 20_000]);
+        let selection =
+            select_coins(&utxos, &payments, fee_rate, &utxos[0].txout.script_pubkey).unwrap();
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.inputs.len(), 2);
+        assert!(!selection.inputs.contains(&utxos[0]));
+    }
+
+    #[tokio::test]
+    async fn test_select_coins_with_change() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let utxos = utxos(&wallet, &[10_000, 200_000, 30_000]);
+        let selection = select_coins(
+            &utxos,
+            &[payment(50_000)],
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &utxos[0].txout.script_pubkey,
+        )
+        .unwrap();
+        assert_eq!(selection.inputs, vec![utxos[1].clone()]);
+        assert!(selection.change.unwrap() > Amount::from_sat(149_000));
+
+        // Every input pays for its own weight
+        let insufficient = select_coins(
+            &utxos,
+            &[payment(240_000)],
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &utxos[0].txout.script_pubkey,
+        );
+        assert_eq!(insufficient.unwrap_err().kind(), ErrorKind::InvalidInput);
+
+        // Payments too large to add up fail instead of overflowing
+        let overflow = select_coins(
+            &utxos,
+            &[payment(u64::MAX - 1)],
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &utxos[0].txout.script_pubkey,
+        );
+        assert_eq!(overflow.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_build_funding_transaction() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let utxos = utxos(&wallet, &[40_000, 80_000, 25_000]);
+        let fee_rate = FeeRate::from_sat_per_vb(5).unwrap();
+        let payments = [
+            (locker(1), Amount::from_sat(60_000)),
+            (locker(2), Amount::from_sat(30_000)),
+        ];
+        let tx = build_funding_transaction(&utxos, &payments, fee_rate, &wallet, Network::Regtest)
+            .unwrap();
+
+        for (output, (address, value)) in tx.output.iter().zip(&payments) {
+            assert_eq!(output.script_pubkey, address.script_pubkey());
+            assert_eq!(output.value, *value);
+        }
+
+        let mut input_total = Amount::ZERO;
+        let secp = Secp256k1::verification_only();
+        let mut cache = SighashCache::new(&tx);
+        for (index, input) in tx.input.iter().enumerate() {
+            let utxo = utxos
+                .iter()
+                .find(|utxo| utxo.outpoint == input.previous_output)
+                .unwrap();
+            input_total += utxo.txout.value;
+
+            let sighash = cache
+                .p2wpkh_signature_hash(
+                    index,
+                    &utxo.txout.script_pubkey,
+                    utxo.txout.value,
+                    EcdsaSighashType::All,
+                )
+                .unwrap();
+            let signature = ecdsa::Signature::from_slice(&input.witness[0]).unwrap();
+            let public_key = bitcoin::PublicKey::from_slice(&input.witness[1]).unwrap();
+            secp.verify_ecdsa(
+                &Message::from_digest(sighash.to_byte_array()),
+                &signature.signature,
+                &public_key.inner,
+            )
+            .unwrap();
+        }
+
+        let output_total = tx.output.iter().map(|output| output.value).sum::<Amount>();
+        let paid = input_total - output_total;
+        assert!(paid >= fee_rate.fee_wu(tx.weight()).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_build_funding_transaction_rejects_foreign_utxo() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let other = GuardianWallet::generate_new().await.unwrap();
+        let tx = build_funding_transaction(
+            &utxos(&other, &[100_000]),
+            &[(locker(1), Amount::from_sat(50_000))],
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            &wallet,
+            Network::Regtest,
+        );
+        assert_eq!(tx.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_build_funding_transaction_rejects_dust() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let tx = build_funding_transaction(
+            &utxos(&wallet, &[100_000]),
+            &[
+                (locker(1), Amount::from_sat(50_000)),
+                (locker(2), Amount::from_sat(100)),
+            ],
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            &wallet,
+            Network::Regtest,
+        );
+        assert_eq!(tx.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+}
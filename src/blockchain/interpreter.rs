@@ -0,0 +1,1059 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{
+    absolute, ecdsa,
+    hashes::Hash,
+    hex::DisplayHex,
+    key::XOnlyPublicKey,
+    opcodes::{all::*, Opcode},
+    relative,
+    script::Instruction,
+    secp256k1::{Message, Secp256k1},
+    sighash::{Prevouts, SighashCache},
+    taproot::{self, ControlBlock, LeafVersion, TapLeafHash, TAPROOT_ANNEX_PREFIX},
+    Amount, PublicKey, Script, ScriptBuf, Sequence, Transaction, TxOut,
+};
+use serde::Serialize;
+
+use super::crypto::HashAlgorithm;
+
+/// Largest element the stack accepts, as enforced by consensus.
+const MAX_ELEMENT_SIZE: usize = 520;
+
+/// Largest number of keys in an OP_CHECKMULTISIG.
+const MAX_MULTISIG_KEYS: i64 = 20;
+
+/// Rules the script is executed with.
+#[derive(Debug, Clone, Copy)]
+enum SigVersion {
+    /// P2WSH witness script, signed with BIP143
+    WitnessV0,
+
+    /// Taproot script path leaf, signed with BIP341
+    Tapscript(TapLeafHash),
+}
+
+/// State of the execution after an instruction.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TraceStep {
+    /// Byte offset of the instruction in the script
+    pub position: usize,
+    pub instruction: String,
+
+    /// False when the instruction is in a branch that is not taken
+    pub executed: bool,
+
+    /// Stack in hex, bottom first
+    pub stack: Vec<String>,
+}
+
+/// Record of a witness execution, for debugging spends that fail.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ExecutionTrace {
+    /// Witness elements the script starts with, bottom first
+    pub initial_stack: Vec<String>,
+    pub steps: Vec<TraceStep>,
+
+    /// Why the spend is invalid, the failing instruction is the last step
+    pub error: Option<String>,
+}
+
+impl ExecutionTrace {
+    pub fn is_valid(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Executes locker scripts against a spending input.
+///
+/// Only the opcodes used by the locker contracts are supported, any other
+/// opcode fails the execution.
+struct Interpreter<'a> {
+    tx: &'a Transaction,
+    input_index: usize,
+    prevouts: &'a Prevouts<'a, TxOut>,
+    value: Amount,
+    sig_version: SigVersion,
+    stack: Vec<Vec<u8>>,
+
+    /// Whether each enclosing OP_IF branch is being executed
+    conditions: Vec<bool>,
+    trace: Vec<TraceStep>,
+}
+
+fn script_error(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidData, message.into())
+}
+
+fn hex_stack(stack: &[Vec<u8>]) -> Vec<String> {
+    stack
+        .iter()
+        .map(|element| element.to_lower_hex_string())
+        .collect()
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+fn decode_bool(value: &[u8]) -> bool {
+    match value.split_last() {
+        Some((last, rest)) => *last & 0x7f != 0 || rest.iter().any(|byte| *byte != 0),
+        None => false,
+    }
+}
+
+/// Encode a number in the minimal little endian script format.
+fn encode_number(number: i64) -> Vec<u8> {
+    let mut magnitude = number.unsigned_abs();
+    let mut encoded = Vec::new();
+    while magnitude > 0 {
+        encoded.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    // The top bit carries the sign, add a byte when it is taken
+    match encoded.last_mut() {
+        Some(last) if *last & 0x80 != 0 => encoded.push(if number < 0 { 0x80 } else { 0 }),
+        Some(last) if number < 0 => *last |= 0x80,
+        _ => {}
+    }
+
+    encoded
+}
+
+/// Decode a little endian script number of at most `max_size` bytes.
+fn decode_number(value: &[u8], max_size: usize) -> Result<i64, Error> {
+    if value.len() > max_size {
+        return Err(script_error("Script number overflow"));
+    }
+    let Some((last, _)) = value.split_last() else {
+        return Ok(0);
+    };
+
+    let mut number = value
+        .iter()
+        .enumerate()
+        .fold(0i64, |acc, (i, byte)| acc | (i64::from(*byte) << (8 * i)));
+    if last & 0x80 != 0 {
+        number &= !(0x80i64 << (8 * (value.len() - 1)));
+        number = -number;
+    }
+
+    Ok(number)
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(
+        tx: &'a Transaction,
+        input_index: usize,
+        prevouts: &'a Prevouts<'a, TxOut>,
+        value: Amount,
+        sig_version: SigVersion,
+    ) -> Self {
+        Self {
+            tx,
+            input_index,
+            prevouts,
+            value,
+            sig_version,
+            stack: Vec::new(),
+            conditions: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    fn is_executing(&self) -> bool {
+        self.conditions.iter().all(|executing| *executing)
+    }
+
+    fn pop(&mut self) -> Result<Vec<u8>, Error> {
+        self.stack
+            .pop()
+            .ok_or_else(|| script_error("Stack is empty"))
+    }
+
+    fn pop_number(&mut self, max_size: usize) -> Result<i64, Error> {
+        decode_number(&self.pop()?, max_size)
+    }
+
+    fn push(&mut self, value: Vec<u8>) -> Result<(), Error> {
+        if value.len() > MAX_ELEMENT_SIZE {
+            return Err(script_error("Stack element is too large"));
+        }
+        self.stack.push(value);
+
+        Ok(())
+    }
+
+    fn verify(&mut self) -> Result<(), Error> {
+        match decode_bool(&self.pop()?) {
+            true => Ok(()),
+            false => Err(script_error("Verify failed")),
+        }
+    }
+
+    fn execute(&mut self, script: &Script) -> Result<(), Error> {
+        for instruction in script.instruction_indices() {
+            let (position, instruction) =
+                instruction.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            let executed = self.is_executing();
+            let result = match instruction {
+                Instruction::PushBytes(data) if executed => self.push(data.as_bytes().to_vec()),
+                Instruction::PushBytes(_) => Ok(()),
+                Instruction::Op(opcode) => self.step(opcode, script),
+            };
+
+            self.trace.push(TraceStep {
+                position,
+                instruction: match instruction {
+                    Instruction::PushBytes(data) if data.is_empty() => OP_PUSHBYTES_0.to_string(),
+                    Instruction::PushBytes(data) => data.as_bytes().to_lower_hex_string(),
+                    Instruction::Op(opcode) => opcode.to_string(),
+                },
+                executed,
+                stack: hex_stack(&self.stack),
+            });
+            result?;
+        }
+        if !self.conditions.is_empty() {
+            return Err(script_error("Unbalanced conditional"));
+        }
+
+        match self.stack.as_slice() {
+            [top] if decode_bool(top) => Ok(()),
+            [_] => Err(script_error("Script evaluated to false")),
+            _ => Err(script_error("Stack is not clean")),
+        }
+    }
+
+    fn step(&mut self, opcode: Opcode, script: &Script) -> Result<(), Error> {
+        // Branch opcodes run even in skipped branches to track the nesting
+        match opcode {
+            OP_IF | OP_NOTIF => {
+                let mut executing = false;
+                if self.is_executing() {
+                    let condition = self.pop()?;
+                    // Segwit only accepts minimal booleans (MINIMALIF)
+                    if condition.len() > 1 || condition.first().is_some_and(|byte| *byte != 1) {
+                        return Err(script_error("OP_IF argument is not minimal"));
+                    }
+                    executing = decode_bool(&condition) == (opcode == OP_IF);
+                }
+                self.conditions.push(executing);
+                return Ok(());
+            }
+            OP_ELSE => {
+                let condition = self
+                    .conditions
+                    .last_mut()
+                    .ok_or_else(|| script_error("OP_ELSE without OP_IF"))?;
+                *condition = !*condition;
+                return Ok(());
+            }
+            OP_ENDIF => {
+                self.conditions
+                    .pop()
+                    .ok_or_else(|| script_error("OP_ENDIF without OP_IF"))?;
+                return Ok(());
+            }
+            _ if !self.is_executing() => return Ok(()),
+            _ => {}
+        }
+
+        match opcode {
+            OP_PUSHNUM_NEG1 => self.push(vec![0x81])?,
+            _ if (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8()).contains(&opcode.to_u8()) => {
+                self.push(vec![opcode.to_u8() - OP_PUSHNUM_1.to_u8() + 1])?
+            }
+            OP_VERIFY => self.verify()?,
+            OP_DUP => {
+                let top = self
+                    .stack
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| script_error("Stack is empty"))?;
+                self.push(top)?;
+            }
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_SIZE => {
+                let size = self
+                    .stack
+                    .last()
+                    .map(|top| top.len())
+                    .ok_or_else(|| script_error("Stack is empty"))?;
+                self.push(encode_number(size as i64))?;
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let (a, b) = (self.pop()?, self.pop()?);
+                self.push(encode_bool(a == b))?;
+                if opcode == OP_EQUALVERIFY {
+                    self.verify()?;
+                }
+            }
+            OP_SHA256 | OP_HASH256 | OP_HASH160 | OP_RIPEMD160 => {
+                let algorithm = HashAlgorithm::from_opcode(opcode).expect("Opcode is a hash");
+                let value = self.pop()?;
+                self.push(algorithm.digest(&value))?;
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let public_key = self.pop()?;
+                let signature = self.pop()?;
+                let valid = self.check_signature(&signature, &public_key, script)?;
+                // Only an empty signature may fail without aborting (NULLFAIL)
+                if !valid && !signature.is_empty() {
+                    return Err(script_error("Signature does not verify"));
+                }
+                self.push(encode_bool(valid))?;
+                if opcode == OP_CHECKSIGVERIFY {
+                    self.verify()?;
+                }
+            }
+            OP_CHECKMULTISIG if matches!(self.sig_version, SigVersion::Tapscript(_)) => {
+                return Err(script_error("OP_CHECKMULTISIG is disabled in tapscript"))
+            }
+            OP_CHECKMULTISIG => {
+                let valid = self.check_multisig(script)?;
+                self.push(encode_bool(valid))?;
+            }
+            OP_CLTV => self.check_lock_time()?,
+            OP_CSV => self.check_sequence()?,
+            _ => return Err(script_error(format!("Unsupported opcode {}", opcode))),
+        }
+
+        Ok(())
+    }
+
+    /// Check a signature of the input, ECDSA over the BIP143 sighash in
+    /// witness scripts and Schnorr over the BIP341 sighash in tapscript.
+    fn check_signature(
+        &self,
+        signature: &[u8],
+        public_key: &[u8],
+        script: &Script,
+    ) -> Result<bool, Error> {
+        if signature.is_empty() {
+            return Ok(false);
+        }
+        if let SigVersion::Tapscript(leaf_hash) = self.sig_version {
+            return self.check_schnorr_signature(signature, public_key, leaf_hash);
+        }
+
+        let signature = ecdsa::Signature::from_slice(signature)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let public_key =
+            PublicKey::from_slice(public_key).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if !public_key.compressed {
+            return Err(script_error("Segwit public keys must be compressed"));
+        }
+
+        let sighash = SighashCache::new(self.tx)
+            .p2wsh_signature_hash(self.input_index, script, self.value, signature.sighash_type)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let message = Message::from_digest(sighash.to_byte_array());
+
+        let valid = Secp256k1::verification_only()
+            .verify_ecdsa(&message, &signature.signature, &public_key.inner)
+            .is_ok();
+
+        Ok(valid)
+    }
+
+    fn check_schnorr_signature(
+        &self,
+        signature: &[u8],
+        public_key: &[u8],
+        leaf_hash: TapLeafHash,
+    ) -> Result<bool, Error> {
+        let signature = taproot::Signature::from_slice(signature)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let public_key = XOnlyPublicKey::from_slice(public_key)
+            .map_err(|_| script_error("Tapscript public keys must be 32 bytes"))?;
+
+        let sighash = SighashCache::new(self.tx)
+            .taproot_script_spend_signature_hash(
+                self.input_index,
+                self.prevouts,
+                leaf_hash,
+                signature.sighash_type,
+            )
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        let message = Message::from_digest(sighash.to_byte_array());
+
+        let valid = Secp256k1::verification_only()
+            .verify_schnorr(&signature.signature, &message, &public_key)
+            .is_ok();
+
+        Ok(valid)
+    }
+
+    fn check_multisig(&mut self, script: &Script) -> Result<bool, Error> {
+        let key_count = self.pop_number(4)?;
+        if !(0..=MAX_MULTISIG_KEYS).contains(&key_count) {
+            return Err(script_error("Invalid multisig key count"));
+        }
+        let keys = (0..key_count)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+        let signature_count = self.pop_number(4)?;
+        if !(0..=key_count).contains(&signature_count) {
+            return Err(script_error("Invalid multisig signature count"));
+        }
+        let signatures = (0..signature_count)
+            .map(|_| self.pop())
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+        // The extra element popped by OP_CHECKMULTISIG must be empty (NULLDUMMY)
+        if !self.pop()?.is_empty() {
+            return Err(script_error("Multisig dummy is not empty"));
+        }
+
+        // Both lists were popped in reverse, signatures must follow key order
+        let mut keys = keys.iter();
+        let mut valid = true;
+        for signature in &signatures {
+            let mut matched = false;
+            for public_key in keys.by_ref() {
+                if !signature.is_empty() && self.check_signature(signature, public_key, script)? {
+                    matched = true;
+                    break;
+                }
+            }
+            if !matched {
+                valid = false;
+                break;
+            }
+        }
+        if !valid && signatures.iter().any(|signature| !signature.is_empty()) {
+            return Err(script_error("Multisig signatures do not verify"));
+        }
+
+        Ok(valid)
+    }
+
+    fn check_lock_time(&self) -> Result<(), Error> {
+        let top = self
+            .stack
+            .last()
+            .ok_or_else(|| script_error("Stack is empty"))?;
+        let lock_time = u32::try_from(decode_number(top, 5)?)
+            .map_err(|_| script_error("Negative lock time"))?;
+
+        if self.tx.input[self.input_index].sequence == Sequence::MAX {
+            return Err(script_error("Input is final, lock time is disabled"));
+        }
+        if !absolute::LockTime::from_consensus(lock_time).is_implied_by(self.tx.lock_time) {
+            return Err(script_error("Lock time is not satisfied"));
+        }
+
+        Ok(())
+    }
+
+    fn check_sequence(&self) -> Result<(), Error> {
+        let top = self
+            .stack
+            .last()
+            .ok_or_else(|| script_error("Stack is empty"))?;
+        let sequence =
+            u32::try_from(decode_number(top, 5)?).map_err(|_| script_error("Negative sequence"))?;
+        // A disabled relative lock time makes the opcode a no-op
+        let Ok(lock_time) = relative::LockTime::from_consensus(sequence) else {
+            return Ok(());
+        };
+
+        if self.tx.version.0 < 2 {
+            return Err(script_error("Relative lock times need version 2"));
+        }
+        if !lock_time.is_implied_by_sequence(self.tx.input[self.input_index].sequence) {
+            return Err(script_error("Sequence is not satisfied"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the output spent by the input.
+fn spent_output<'a>(prevouts: &'a Prevouts<'a, TxOut>, input_index: usize) -> Option<&'a TxOut> {
+    match prevouts {
+        Prevouts::One(index, prevout) if *index == input_index => Some(prevout),
+        Prevouts::One(_, _) => None,
+        Prevouts::All(prevouts) => prevouts.get(input_index),
+    }
+}
+
+/// Run the script on the witness elements, recording the steps in the trace.
+fn execute_script(
+    mut interpreter: Interpreter,
+    elements: Vec<Vec<u8>>,
+    script: &Script,
+    trace: &mut ExecutionTrace,
+) -> Result<(), Error> {
+    trace.initial_stack = hex_stack(&elements);
+    for element in elements {
+        interpreter.push(element)?;
+    }
+    let result = interpreter.execute(script);
+    trace.steps = interpreter.trace;
+
+    result
+}
+
+fn spend(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &Prevouts<TxOut>,
+    trace: &mut ExecutionTrace,
+) -> Result<(), Error> {
+    let input = tx
+        .input
+        .get(input_index)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Input does not exist"))?;
+    let prevout = spent_output(prevouts, input_index)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Spent output is missing"))?;
+    let mut elements = input.witness.to_vec();
+
+    let script_pubkey = if prevout.script_pubkey.is_p2sh() {
+        // Nested witness program, revealed as the only push of the scriptSig
+        let mut instructions = input.script_sig.instructions();
+        let redeem_script = match (instructions.next(), instructions.next()) {
+            (Some(Ok(Instruction::PushBytes(push))), None) => {
+                ScriptBuf::from_bytes(push.as_bytes().to_vec())
+            }
+            _ => return Err(script_error("Script sig must only push the redeem script")),
+        };
+        if prevout.script_pubkey != ScriptBuf::new_p2sh(&redeem_script.script_hash()) {
+            return Err(script_error("Redeem script does not match the output"));
+        }
+        if !redeem_script.is_p2wsh() {
+            return Err(script_error("Only P2WSH redeem scripts are supported"));
+        }
+        redeem_script
+    } else if input.script_sig.is_empty() {
+        prevout.script_pubkey.clone()
+    } else {
+        return Err(script_error("Witness spends must have an empty script sig"));
+    };
+
+    if script_pubkey.is_p2wsh() {
+        let witness_script = ScriptBuf::from_bytes(
+            elements
+                .pop()
+                .ok_or_else(|| script_error("Witness is empty"))?,
+        );
+        if script_pubkey != ScriptBuf::new_p2wsh(&witness_script.wscript_hash()) {
+            return Err(script_error("Witness script does not match the output"));
+        }
+        let interpreter = Interpreter::new(
+            tx,
+            input_index,
+            prevouts,
+            prevout.value,
+            SigVersion::WitnessV0,
+        );
+
+        return execute_script(interpreter, elements, &witness_script, trace);
+    }
+
+    if !script_pubkey.is_p2tr() {
+        return Err(script_error(
+            "Only P2WSH, P2SH-P2WSH and P2TR outputs are supported",
+        ));
+    }
+    let output_key = XOnlyPublicKey::from_slice(&script_pubkey.as_bytes()[2..])
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    if elements.len() > 1
+        && elements
+            .last()
+            .is_some_and(|last| last.first() == Some(&TAPROOT_ANNEX_PREFIX))
+    {
+        return Err(script_error("Taproot annex is not supported"));
+    }
+
+    if let [signature] = elements.as_slice() {
+        // Key path spend, signed by the tweaked output key
+        trace.initial_stack = hex_stack(&elements);
+        let signature = taproot::Signature::from_slice(signature)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        let sighash = SighashCache::new(tx)
+            .taproot_key_spend_signature_hash(input_index, prevouts, signature.sighash_type)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        return Secp256k1::verification_only()
+            .verify_schnorr(
+                &signature.signature,
+                &Message::from_digest(sighash.to_byte_array()),
+                &output_key,
+            )
+            .map_err(|_| script_error("Key path signature does not verify"));
+    }
+
+    let control_block = elements
+        .pop()
+        .ok_or_else(|| script_error("Witness is empty"))?;
+    let control_block =
+        ControlBlock::decode(&control_block).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let leaf = ScriptBuf::from_bytes(
+        elements
+            .pop()
+            .ok_or_else(|| script_error("Witness is missing the leaf"))?,
+    );
+    if control_block.leaf_version != LeafVersion::TapScript {
+        return Err(script_error("Only tapscript leaves are supported"));
+    }
+    if !control_block.verify_taproot_commitment(&Secp256k1::verification_only(), output_key, &leaf)
+    {
+        return Err(script_error("Leaf is not committed in the output key"));
+    }
+    let leaf_hash = TapLeafHash::from_script(&leaf, LeafVersion::TapScript);
+    let interpreter = Interpreter::new(
+        tx,
+        input_index,
+        prevouts,
+        prevout.value,
+        SigVersion::Tapscript(leaf_hash),
+    );
+
+    execute_script(interpreter, elements, &leaf, trace)
+}
+
+/// Execute the witness of an input against the output it spends and record
+/// every step. P2WSH and P2TR outputs, through the key or a tapscript leaf,
+/// are supported.
+///
+/// `prevouts` must hold all the spent outputs for taproot inputs unless
+/// they are signed with `SIGHASH_ANYONECANPAY`.
+pub fn trace_spend(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &Prevouts<TxOut>,
+) -> ExecutionTrace {
+    let mut trace = ExecutionTrace::default();
+    if let Err(e) = spend(tx, input_index, prevouts, &mut trace) {
+        trace.error = Some(e.to_string());
+    }
+
+    trace
+}
+
+/// Check that the witness of an input satisfies the output it spends.
+///
+/// ### Errors
+/// If the witness does not satisfy the output, with the reason and the
+/// failing instruction.
+pub fn verify_spend(
+    tx: &Transaction,
+    input_index: usize,
+    prevouts: &Prevouts<TxOut>,
+) -> Result<(), Error> {
+    let trace = trace_spend(tx, input_index, prevouts);
+    let Some(error) = trace.error else {
+        return Ok(());
+    };
+
+    let message = match trace.steps.last() {
+        Some(step) => format!(
+            "{} at {} (byte {}), stack [{}]",
+            error,
+            step.instruction,
+            step.position,
+            step.stack.join(", ")
+        ),
+        None => error,
+    };
+
+    Err(script_error(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{
+        key::TweakedPublicKey, sighash::TapSighashType, transaction::Version, Address, FeeRate,
+        Network, OutPoint, TxIn, Witness,
+    };
+
+    use super::*;
+    use crate::blockchain::{
+        address::GuardianWallet,
+        signer::Signer,
+        taproot::{InternalKey, TaprootLocker},
+        transactions::{self, EscrowKeys, HashLock, Refund, Timelock},
+    };
+
+    const ALGORITHMS: [HashAlgorithm; 4] = [
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Hash256,
+        HashAlgorithm::Hash160,
+        HashAlgorithm::Ripemd160,
+    ];
+
+    fn prevout(witness_script: &Script, value: Amount) -> Prevouts<'static, TxOut> {
+        Prevouts::One(
+            0,
+            TxOut {
+                value,
+                script_pubkey: ScriptBuf::new_p2wsh(&witness_script.wscript_hash()),
+            },
+        )
+    }
+
+    fn destination() -> bitcoin::Address {
+        transactions::p2wsh_address(Script::from_bytes(&[0x51]), Network::Regtest)
+    }
+
+    #[test]
+    fn test_decode_number() {
+        assert_eq!(decode_number(&[], 4).unwrap(), 0);
+        assert_eq!(decode_number(&[0x81], 4).unwrap(), -1);
+        assert_eq!(decode_number(&[0xff, 0x00], 4).unwrap(), 255);
+        assert_eq!(decode_number(&[0x90, 0x01], 4).unwrap(), 400);
+        assert_eq!(
+            decode_number(&[0xa0, 0xbb, 0x0d, 0x00, 0x00], 5).unwrap(),
+            900_000
+        );
+        assert!(decode_number(&[1, 2, 3, 4, 5], 4).is_err());
+
+        for number in [0, 1, -1, 32, 127, 128, -128, 255, 520, 900_000] {
+            assert_eq!(decode_number(&encode_number(number), 5).unwrap(), number);
+        }
+        assert_eq!(encode_number(32), vec![0x20]);
+        assert_eq!(encode_number(128), vec![0x80, 0x00]);
+        assert_eq!(encode_number(-128), vec![0x80, 0x80]);
+    }
+
+    #[tokio::test]
+    async fn test_hash_lock_claims_are_spendable() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let secret = [7u8; 32];
+
+        for algorithm in ALGORITHMS {
+            for refund in [
+                None,
+                Some(Refund {
+                    depositor: [3u8; 20],
+                    timelock: Timelock::Relative(relative::LockTime::from_height(6)),
+                }),
+            ] {
+                let tx = transactions::build_secret_claim_transaction(
+                    OutPoint::null(),
+                    value,
+                    &destination(),
+                    FeeRate::from_sat_per_vb(2).unwrap(),
+                    &secret,
+                    algorithm,
+                    &guardian,
+                    refund,
+                    transactions::AddressType::P2wsh,
+                )
+                .unwrap();
+                let witness_script = transactions::generate_witness_script(
+                    &secret,
+                    algorithm,
+                    guardian.public_key_commitment(),
+                    refund,
+                )
+                .unwrap();
+
+                verify_spend(&tx, 0, &prevout(&witness_script, value)).unwrap();
+
+                // Any other preimage is rejected by the hash opcode
+                let mut forged = tx.clone();
+                let mut witness = forged.input[0].witness.to_vec();
+                witness[2] = vec![8u8; 32];
+                forged.input[0].witness = witness.into();
+                assert!(verify_spend(&forged, 0, &prevout(&witness_script, value)).is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_lock_refunds_are_spendable() {
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+
+        for algorithm in ALGORITHMS {
+            for timelock in [
+                Timelock::Absolute(absolute::LockTime::from_height(900_000).unwrap()),
+                Timelock::Relative(relative::LockTime::from_height(144)),
+            ] {
+                let refund = Refund {
+                    depositor: depositor.public_key_commitment(),
+                    timelock,
+                };
+                let hash_lock = HashLock::new(algorithm, &[7u8; 32]);
+                let tx = transactions::build_refund_transaction(
+                    OutPoint::null(),
+                    value,
+                    &destination(),
+                    FeeRate::from_sat_per_vb(2).unwrap(),
+                    &hash_lock,
+                    [2u8; 20],
+                    refund,
+                    &depositor,
+                    transactions::AddressType::P2wsh,
+                )
+                .unwrap();
+                let witness_script =
+                    transactions::hash_lock_contract(&hash_lock, [2u8; 20], Some(refund));
+                let prevout = prevout(&witness_script, value);
+
+                verify_spend(&tx, 0, &prevout).unwrap();
+
+                // Spending before the timelock expires fails
+                let mut early = tx.clone();
+                early.lock_time = absolute::LockTime::ZERO;
+                early.input[0].sequence = Sequence::ENABLE_RBF_NO_LOCKTIME;
+                assert!(verify_spend(&early, 0, &prevout).is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_nested_hash_lock_is_spendable() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let secret = [7u8; 32];
+        let address_type = transactions::AddressType::P2shP2wsh;
+        let tx = transactions::build_secret_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination(),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &secret,
+            HashAlgorithm::Hash160,
+            &guardian,
+            None,
+            address_type,
+        )
+        .unwrap();
+        let witness_script = transactions::generate_witness_script(
+            &secret,
+            HashAlgorithm::Hash160,
+            guardian.public_key_commitment(),
+            None,
+        )
+        .unwrap();
+        let nested = Prevouts::One(
+            0,
+            TxOut {
+                value,
+                script_pubkey: address_type
+                    .address(&witness_script, Network::Regtest)
+                    .script_pubkey(),
+            },
+        );
+
+        verify_spend(&tx, 0, &nested).unwrap();
+
+        // The redeem script must be revealed in the scriptSig
+        let mut stripped = tx.clone();
+        stripped.input[0].script_sig = ScriptBuf::new();
+        assert!(verify_spend(&stripped, 0, &nested).is_err());
+
+        // Native outputs are spent with an empty scriptSig
+        assert!(verify_spend(&tx, 0, &prevout(&witness_script, value)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_escrow_is_spendable() {
+        let buyer = GuardianWallet::generate_new().await.unwrap();
+        let seller = GuardianWallet::generate_new().await.unwrap();
+        let arbiter = GuardianWallet::generate_new().await.unwrap();
+        let keys = EscrowKeys::new(
+            buyer.public_key(),
+            seller.public_key(),
+            arbiter.public_key(),
+        )
+        .unwrap();
+        let value = Amount::from_sat(100_000);
+        let prevout = prevout(
+            &transactions::generate_escrow_witness_script(&keys).unwrap(),
+            value,
+        );
+
+        for signers in [[&buyer, &seller], [&arbiter, &buyer], [&seller, &arbiter]] {
+            let tx = transactions::build_escrow_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &keys,
+                signers.map(|signer| signer as &dyn Signer),
+            )
+            .unwrap();
+
+            verify_spend(&tx, 0, &prevout).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_foreign_witness_script() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let tx = transactions::build_secret_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination(),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            &guardian,
+            None,
+            transactions::AddressType::P2wsh,
+        )
+        .unwrap();
+        let other = transactions::generate_witness_script(
+            &[8u8; 32],
+            HashAlgorithm::Sha256,
+            guardian.public_key_commitment(),
+            None,
+        )
+        .unwrap();
+
+        assert!(verify_spend(&tx, 0, &prevout(&other, value)).is_err());
+        assert!(verify_spend(&tx, 1, &prevout(&other, value)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_trace_failed_spend() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let mut tx = transactions::build_secret_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination(),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            &guardian,
+            None,
+            transactions::AddressType::P2wsh,
+        )
+        .unwrap();
+        let witness_script = transactions::generate_witness_script(
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            guardian.public_key_commitment(),
+            None,
+        )
+        .unwrap();
+
+        let trace = trace_spend(&tx, 0, &prevout(&witness_script, value));
+        assert!(trace.is_valid());
+        assert_eq!(trace.initial_stack.len(), 3);
+        assert_eq!(trace.steps.len(), witness_script.instructions().count());
+        assert_eq!(trace.steps.last().unwrap().stack, vec!["01"]);
+
+        let mut witness = tx.input[0].witness.to_vec();
+        witness[2] = vec![8u8; 32];
+        tx.input[0].witness = witness.into();
+        let trace = trace_spend(&tx, 0, &prevout(&witness_script, value));
+        assert_eq!(trace.error.as_deref(), Some("Verify failed"));
+        let failed = trace.steps.last().unwrap();
+        assert_eq!(failed.instruction, "OP_EQUALVERIFY");
+        assert_eq!(failed.position, 38);
+
+        let error = verify_spend(&tx, 0, &prevout(&witness_script, value)).unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Verify failed at OP_EQUALVERIFY"));
+    }
+
+    #[tokio::test]
+    async fn test_taproot_locker_is_spendable() {
+        let recipient = GuardianWallet::generate_new().await.unwrap();
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let locker = TaprootLocker {
+            hash_lock: HashLock::new(HashAlgorithm::Hash160, &[7u8; 32]),
+            recipient: recipient.x_only_public_key(),
+            depositor: depositor.x_only_public_key(),
+            timelock: Timelock::Relative(relative::LockTime::from_height(144)),
+            internal_key: InternalKey::Nums,
+        };
+        let prevouts = [TxOut {
+            value,
+            script_pubkey: locker.address().unwrap().script_pubkey(),
+        }];
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+
+        let claim = locker
+            .build_claim_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                fee_rate,
+                &[7u8; 32],
+                &recipient,
+            )
+            .unwrap();
+        let trace = trace_spend(&claim, 0, &Prevouts::All(&prevouts));
+        assert_eq!(trace.error, None);
+        assert!(trace
+            .steps
+            .iter()
+            .any(|step| step.instruction == "OP_HASH160"));
+
+        let refund = locker
+            .build_refund_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                fee_rate,
+                &depositor,
+            )
+            .unwrap();
+        verify_spend(&refund, 0, &Prevouts::All(&prevouts)).unwrap();
+
+        // A leaf of another locker is not committed in the output
+        let mut other = locker.clone();
+        other.timelock = Timelock::Relative(relative::LockTime::from_height(6));
+        let refund = other
+            .build_refund_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                fee_rate,
+                &depositor,
+            )
+            .unwrap();
+        assert!(verify_spend(&refund, 0, &Prevouts::All(&prevouts)).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_taproot_key_path() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let output_key =
+            XOnlyPublicKey::from_slice(&wallet.x_only_public_key().serialize()).unwrap();
+        let prevouts = [TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: Address::p2tr_tweaked(
+                TweakedPublicKey::dangerous_assume_tweaked(output_key),
+                Network::Regtest,
+            )
+            .script_pubkey(),
+        }];
+        let mut tx = Transaction {
+            version: Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: destination().script_pubkey(),
+            }],
+        };
+        let sighash = SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&prevouts), TapSighashType::Default)
+            .unwrap();
+        let signature = wallet.sign_schnorr(sighash.to_byte_array()).unwrap();
+        tx.input[0].witness = Witness::from_slice(&[signature.to_byte_array()]);
+
+        verify_spend(&tx, 0, &Prevouts::All(&prevouts)).unwrap();
+        // Taproot sighashes commit to all the spent outputs
+        assert!(verify_spend(&tx, 0, &Prevouts::One(0, prevouts[0].clone())).is_err());
+    }
+}
@@ -0,0 +1,493 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{Address, Amount, FeeRate, Network, OutPoint, ScriptBuf, Transaction, TxOut, Txid};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::settings::get_settings;
+
+use super::{
+    crypto::HashAlgorithm,
+    encoding, secret,
+    signer::Signer,
+    transactions::{self, AddressType, HashLock, Refund},
+    types::RecipientKey,
+    validation,
+};
+
+/// Domain of the milestone secret derivation, so the secrets never collide
+/// with other uses of the master entropy.
+const MILESTONE_DOMAIN: &[u8] = b"coinslock/milestone";
+
+/// Derive the secret of a milestone from the master entropy with
+/// HMAC-SHA256, keyed by the master entropy over the domain and the index.
+fn derive_milestone_secret(master_entropy: &[u8], index: u32) -> [u8; 32] {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(master_entropy).expect("HMAC accepts keys of any size");
+    mac.update(MILESTONE_DOMAIN);
+    mac.update(&index.to_be_bytes());
+
+    mac.finalize().into_bytes().into()
+}
+
+/// Returns the mnemonic of a single milestone, derived from the master
+/// mnemonic. The buyer hands it to the recipient to release that milestone
+/// without revealing the others.
+///
+/// ### Errors
+/// If the master mnemonic is invalid.
+pub async fn milestone_mnemonic(
+    master_mnemonic: Vec<String>,
+    index: u32,
+) -> Result<Vec<String>, Error> {
+    let master_entropy = secret::mnemonic_to_entropy(master_mnemonic).await?;
+
+    secret::generate_secret(&derive_milestone_secret(&master_entropy, index)).await
+}
+
+/// Where a milestone locker stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MilestoneStatus {
+    /// Waiting for the buyer to fund the locker
+    Pending,
+
+    /// Funded with the output
+    #[serde(with = "encoding::string")]
+    Funded(OutPoint),
+
+    /// Claim of the funding output built for the recipient. It can be built
+    /// again, e.g. with a higher fee, until it confirms
+    Released {
+        #[serde(with = "encoding::string")]
+        outpoint: OutPoint,
+        #[serde(with = "encoding::string")]
+        tx_id: Txid,
+    },
+}
+
+impl Milestone {
+    /// The output funding the milestone, once it was funded.
+    pub fn funding_outpoint(&self) -> Option<OutPoint> {
+        match self.status {
+            MilestoneStatus::Pending => None,
+            MilestoneStatus::Funded(outpoint) | MilestoneStatus::Released { outpoint, .. } => {
+                Some(outpoint)
+            }
+        }
+    }
+
+    pub fn is_released(&self) -> bool {
+        matches!(self.status, MilestoneStatus::Released { .. })
+    }
+}
+
+/// One milestone of the escrow, locked to its own hash lock.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Milestone {
+    pub index: u32,
+    #[serde(with = "encoding::sat")]
+    pub amount: Amount,
+    pub hash_lock: HashLock,
+    #[serde(with = "encoding::hex_bytes")]
+    pub witness_script: ScriptBuf,
+    #[serde(with = "encoding::address")]
+    pub address: Address,
+    pub status: MilestoneStatus,
+}
+
+/// Parent record of a series of milestone lockers paying the same recipient.
+///
+/// Every milestone has its own secret derived from the master mnemonic of
+/// the buyer, so one backup covers the whole series while each milestone is
+/// released on its own.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MilestoneEscrow {
+    #[serde(with = "encoding::string")]
+    pub network: Network,
+    #[serde(with = "encoding::hex_bytes")]
+    pub recipient: RecipientKey,
+    pub refund: Option<Refund>,
+    pub milestones: Vec<Milestone>,
+}
+
+/// Add up milestone amounts, or fail if they overflow.
+fn checked_total<'a>(amounts: impl IntoIterator<Item = &'a Amount>) -> Result<Amount, Error> {
+    amounts
+        .into_iter()
+        .try_fold(Amount::ZERO, |total, amount| total.checked_add(*amount))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Milestone amounts overflow"))
+}
+
+impl MilestoneEscrow {
+    /// Create the milestone lockers for the amounts, in order. Each amount is
+    /// checked like the amount of a single locker.
+    ///
+    /// ### Errors
+    /// If the master mnemonic is invalid, there are no milestones, the refund
+    /// timelock is zero or an amount is dust or outside of the configured
+    /// limits.
+    pub async fn new(
+        master_mnemonic: Vec<String>,
+        algorithm: HashAlgorithm,
+        recipient: RecipientKey,
+        refund: Option<Refund>,
+        amounts: &[Amount],
+        network: Network,
+    ) -> Result<Self, Error> {
+        if amounts.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Escrow needs at least one milestone",
+            ));
+        }
+        checked_total(amounts)?;
+        transactions::check_refund(refund.as_ref())?;
+        let (min_amount, max_amount) = {
+            let settings = get_settings();
+            (
+                Amount::from_sat(settings.min_locker_amount),
+                Amount::from_sat(settings.max_locker_amount),
+            )
+        };
+        let master_entropy = secret::mnemonic_to_entropy(master_mnemonic).await?;
+
+        let milestones = (0..)
+            .zip(amounts)
+            .map(|(index, amount)| {
+                let secret = derive_milestone_secret(&master_entropy, index);
+                let hash_lock = HashLock::new(algorithm, &secret);
+                let witness_script =
+                    transactions::hash_lock_contract(&hash_lock, recipient, refund)
+                        .into_script_buf();
+                let address = transactions::p2wsh_address(&witness_script, network);
+                validation::validate_amount(
+                    *amount,
+                    &address.script_pubkey(),
+                    min_amount,
+                    max_amount,
+                )?;
+
+                Ok(Milestone {
+                    index,
+                    amount: *amount,
+                    hash_lock,
+                    witness_script,
+                    address,
+                    status: MilestoneStatus::Pending,
+                })
+            })
+            .collect::<Result<Vec<Milestone>, Error>>()?;
+
+        Ok(Self {
+            network,
+            recipient,
+            refund,
+            milestones,
+        })
+    }
+
+    fn milestone_mut(&mut self, index: u32) -> Result<&mut Milestone, Error> {
+        self.milestones
+            .get_mut(index as usize)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Milestone does not exist"))
+    }
+
+    /// Record the funding output of a pending milestone. The release signs
+    /// for the milestone amount, so the output must pay exactly that amount
+    /// to the milestone address.
+    ///
+    /// ### Errors
+    /// If the milestone does not exist, was already funded or the output does
+    /// not fund it.
+    pub fn fund(&mut self, index: u32, outpoint: OutPoint, txout: &TxOut) -> Result<(), Error> {
+        let milestone = self.milestone_mut(index)?;
+        if milestone.status != MilestoneStatus::Pending {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Milestone is already funded",
+            ));
+        }
+        if txout.script_pubkey != milestone.address.script_pubkey() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Output does not pay to the milestone",
+            ));
+        }
+        if txout.value != milestone.amount {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Output pays {} sat, the milestone is {} sat",
+                    txout.value.to_sat(),
+                    milestone.amount.to_sat()
+                ),
+            ));
+        }
+        milestone.status = MilestoneStatus::Funded(outpoint);
+
+        Ok(())
+    }
+
+    /// Build and sign the claim of a funded milestone with its mnemonic. A
+    /// released milestone can be claimed again, the new claim replaces the
+    /// previous one if that did not confirm and is rejected as a double
+    /// spend if it did.
+    ///
+    /// ### Errors
+    /// If the milestone is not funded, the mnemonic is not the one of the
+    /// milestone or the claim cannot be built.
+    pub async fn build_release_transaction(
+        &self,
+        index: u32,
+        mnemonic: Vec<String>,
+        destination: &Address,
+        fee_rate: FeeRate,
+        guardian: &dyn Signer,
+    ) -> Result<Transaction, Error> {
+        let secret = secret::mnemonic_to_entropy(mnemonic).await?;
+
+        self.build_secret_release_transaction(index, &secret, destination, fee_rate, guardian)
+    }
+
+    /// Build and sign the claim of a funded milestone with its secret, the
+    /// entropy of its mnemonic. See [`Self::build_release_transaction`].
+    ///
+    /// ### Errors
+    /// If the milestone is not funded, the secret does not unlock it or the
+    /// claim cannot be built.
+    pub fn build_secret_release_transaction(
+        &self,
+        index: u32,
+        secret: &[u8],
+        destination: &Address,
+        fee_rate: FeeRate,
+        guardian: &dyn Signer,
+    ) -> Result<Transaction, Error> {
+        let milestone = self
+            .milestones
+            .get(index as usize)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Milestone does not exist"))?;
+        let Some(outpoint) = milestone.funding_outpoint() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Milestone is not funded",
+            ));
+        };
+        if !milestone.hash_lock.is_unlocked_by(secret) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Mnemonic does not unlock the milestone",
+            ));
+        }
+
+        transactions::build_secret_claim_transaction(
+            outpoint,
+            milestone.amount,
+            destination,
+            fee_rate,
+            secret,
+            milestone.hash_lock.algorithm,
+            guardian,
+            self.refund,
+            AddressType::P2wsh,
+        )
+    }
+
+    /// Record the claim of a funded milestone, or the claim replacing the
+    /// previous one of a released milestone.
+    ///
+    /// ### Errors
+    /// If the milestone does not exist or is not funded.
+    pub fn release(&mut self, index: u32, tx_id: Txid) -> Result<(), Error> {
+        let milestone = self.milestone_mut(index)?;
+        let Some(outpoint) = milestone.funding_outpoint() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Milestone is not funded",
+            ));
+        };
+        milestone.status = MilestoneStatus::Released { outpoint, tx_id };
+
+        Ok(())
+    }
+
+    /// The first milestone that was not released yet.
+    pub fn next_milestone(&self) -> Option<&Milestone> {
+        self.milestones
+            .iter()
+            .find(|milestone| !milestone.is_released())
+    }
+
+    /// Amount of all the milestones.
+    ///
+    /// ### Errors
+    /// If the amounts overflow.
+    pub fn total_amount(&self) -> Result<Amount, Error> {
+        checked_total(self.milestones.iter().map(|milestone| &milestone.amount))
+    }
+
+    /// Amount of the milestones released so far.
+    ///
+    /// ### Errors
+    /// If the amounts overflow.
+    pub fn released_amount(&self) -> Result<Amount, Error> {
+        checked_total(
+            self.milestones
+                .iter()
+                .filter(|milestone| milestone.is_released())
+                .map(|milestone| &milestone.amount),
+        )
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.next_milestone().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hashes::Hash, sighash::Prevouts, TxOut};
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, interpreter};
+
+    async fn master_mnemonic() -> Vec<String> {
+        secret::generate_secret(&[3u8; 32]).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_milestone_secrets() {
+        let master = master_mnemonic().await;
+        let first = milestone_mnemonic(master.clone(), 0).await.unwrap();
+        let second = milestone_mnemonic(master.clone(), 1).await.unwrap();
+        assert_eq!(first, milestone_mnemonic(master, 0).await.unwrap());
+        assert_ne!(first, second);
+        assert_eq!(first.len(), 24);
+
+        let other = secret::generate_secret(&[4u8; 32]).await.unwrap();
+        assert_ne!(first, milestone_mnemonic(other, 0).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_milestone_escrow() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let master = master_mnemonic().await;
+        let amounts = [
+            Amount::from_sat(50_000),
+            Amount::from_sat(30_000),
+            Amount::from_sat(20_000),
+        ];
+        let mut escrow = MilestoneEscrow::new(
+            master.clone(),
+            HashAlgorithm::Sha256,
+            guardian.public_key_commitment(),
+            None,
+            &amounts,
+            Network::Regtest,
+        )
+        .await
+        .unwrap();
+        assert_eq!(escrow.total_amount().unwrap(), Amount::from_sat(100_000));
+        assert_ne!(escrow.milestones[0].address, escrow.milestones[1].address);
+
+        // Every milestone is checked like the amount of a single locker
+        for invalid in [
+            vec![Amount::from_sat(50_000), Amount::from_sat(100)],
+            vec![Amount::from_sat(u64::MAX), Amount::from_sat(u64::MAX)],
+        ] {
+            let result = MilestoneEscrow::new(
+                master.clone(),
+                HashAlgorithm::Sha256,
+                guardian.public_key_commitment(),
+                None,
+                &invalid,
+                Network::Regtest,
+            )
+            .await;
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let destination = guardian.p2wpkh_address(Network::Regtest);
+        let mnemonic = milestone_mnemonic(master.clone(), 1).await.unwrap();
+        let unfunded = escrow
+            .build_release_transaction(1, mnemonic.clone(), &destination, fee_rate, &guardian)
+            .await;
+        assert!(unfunded.is_err());
+
+        let outpoint = OutPoint::new(Txid::all_zeros(), 1);
+        let mut funding = TxOut {
+            value: Amount::from_sat(25_000),
+            script_pubkey: escrow.milestones[1].address.script_pubkey(),
+        };
+        // The release would sign for another amount than the one funded
+        assert!(escrow.fund(1, outpoint, &funding).is_err());
+        assert!(escrow
+            .fund(
+                2,
+                outpoint,
+                &TxOut {
+                    value: amounts[2],
+                    ..funding.clone()
+                }
+            )
+            .is_err());
+        funding.value = amounts[1];
+        escrow.fund(1, outpoint, &funding).unwrap();
+        assert!(escrow.fund(1, outpoint, &funding).is_err());
+
+        // The mnemonic of another milestone does not release it
+        let wrong = milestone_mnemonic(master, 0).await.unwrap();
+        let wrong = escrow
+            .build_release_transaction(1, wrong, &destination, fee_rate, &guardian)
+            .await;
+        assert!(wrong.is_err());
+
+        let tx = escrow
+            .build_release_transaction(1, mnemonic, &destination, fee_rate, &guardian)
+            .await
+            .unwrap();
+        let milestone = &escrow.milestones[1];
+        assert_eq!(milestone.status, MilestoneStatus::Funded(outpoint));
+        interpreter::verify_spend(&tx, 0, &Prevouts::One(0, funding)).unwrap();
+
+        escrow.release(1, tx.compute_txid()).unwrap();
+        assert_eq!(escrow.released_amount().unwrap(), Amount::from_sat(30_000));
+        assert_eq!(escrow.next_milestone().unwrap().index, 0);
+        assert!(!escrow.is_complete());
+        assert!(escrow.release(2, tx.compute_txid()).is_err());
+
+        // A release that did not confirm is replaced with a higher fee
+        let replacement = escrow
+            .build_release_transaction(
+                1,
+                milestone_mnemonic(master_mnemonic().await, 1)
+                    .await
+                    .unwrap(),
+                &destination,
+                FeeRate::from_sat_per_vb(10).unwrap(),
+                &guardian,
+            )
+            .await
+            .unwrap();
+        assert_eq!(replacement.input[0].previous_output, outpoint);
+        assert!(replacement.output[0].value < tx.output[0].value);
+        escrow.release(1, replacement.compute_txid()).unwrap();
+        assert_eq!(
+            escrow.milestones[1].status,
+            MilestoneStatus::Released {
+                outpoint,
+                tx_id: replacement.compute_txid()
+            }
+        );
+        assert_eq!(escrow.released_amount().unwrap(), Amount::from_sat(30_000));
+
+        let data = serde_json::to_vec(&escrow).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<MilestoneEscrow>(&data).unwrap(),
+            escrow
+        );
+    }
+}
@@ -1,8 +1,21 @@
+pub mod adapters;
 pub mod address;
 pub mod crypto;
+pub mod descriptor;
+pub mod fees;
+pub mod funding;
+pub mod interpreter;
+pub mod milestone;
+pub mod payout;
+pub mod policy;
+pub mod psbt;
 pub mod secret;
+pub mod signer;
+pub mod swap;
+pub mod taproot;
 pub mod transactions;
-pub mod adapters;
+pub mod validation;
+pub(crate) mod encoding;
 mod types;
 
 const WORD_LIST_PATH: &str = "data/wordlist.txt";
@@ -0,0 +1,307 @@
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use bitcoin::{
+    address::NetworkUnchecked, Address, Amount, FeeRate, Network, Transaction, TxOut, Witness,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// Basis points of a payout taking the whole value.
+pub const FULL_SHARE: u16 = 10_000;
+
+/// Part of the locker value paid to a payout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PayoutShare {
+    /// Fixed amount in satoshis
+    Fixed(u64),
+
+    /// Share in basis points of what is left once the fixed payouts and the
+    /// fee are paid
+    BasisPoints(u16),
+}
+
+/// Output of a claim transaction paying part of the locker value.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Payout {
+    #[serde(
+        serialize_with = "serialize_address",
+        deserialize_with = "deserialize_address"
+    )]
+    pub address: Address<NetworkUnchecked>,
+    pub share: PayoutShare,
+}
+
+fn serialize_address<S: Serializer>(
+    address: &Address<NetworkUnchecked>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_str(address.assume_checked_ref())
+}
+
+fn deserialize_address<'de, D: Deserializer<'de>>(
+    d: D,
+) -> Result<Address<NetworkUnchecked>, D::Error> {
+    let address = String::deserialize(d)?;
+
+    Address::from_str(&address).map_err(de::Error::custom)
+}
+
+impl Payout {
+    /// Payout of the whole locker value, minus the fee, to the destination.
+    pub fn to(destination: &Address) -> Self {
+        Self {
+            address: destination.as_unchecked().clone(),
+            share: PayoutShare::BasisPoints(FULL_SHARE),
+        }
+    }
+}
+
+fn invalid_payouts(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+/// Check the payouts before they are recorded on a locker of the amount.
+///
+/// Shares must add up to the whole remainder, so at least one payout takes a
+/// share and the claim never leaves more than its fee to the miners.
+///
+/// ### Errors
+/// If there are no payouts, an address is for another network, a share is
+/// zero, a fixed payout is dust, the shares do not add up or the fixed
+/// payouts do not fit in the amount.
+pub fn validate_payouts(payouts: &[Payout], amount: Amount, network: Network) -> Result<(), Error> {
+    if payouts.is_empty() {
+        return Err(invalid_payouts("Locker needs at least one payout"));
+    }
+    if payouts
+        .iter()
+        .any(|payout| !payout.address.is_valid_for_network(network))
+    {
+        return Err(invalid_payouts("Payout address is for another network"));
+    }
+    for payout in payouts {
+        match payout.share {
+            PayoutShare::BasisPoints(0) => {
+                return Err(invalid_payouts("Payout share cannot be zero"));
+            }
+            PayoutShare::Fixed(sat) => {
+                let script_pubkey = payout.address.assume_checked_ref().script_pubkey();
+                if Amount::from_sat(sat) < script_pubkey.minimal_non_dust() {
+                    return Err(invalid_payouts("Fixed payout is below the dust limit"));
+                }
+            }
+            PayoutShare::BasisPoints(_) => {}
+        }
+    }
+
+    let fixed_total = fixed_total(payouts)?;
+    if share_total(payouts)? != u64::from(FULL_SHARE) {
+        return Err(invalid_payouts("Payout shares must add up to 100%"));
+    }
+    if fixed_total >= amount {
+        return Err(invalid_payouts("Fixed payouts leave nothing for the fee"));
+    }
+
+    Ok(())
+}
+
+/// Add up the fixed payouts, or fail if they overflow.
+fn fixed_total(payouts: &[Payout]) -> Result<Amount, Error> {
+    payouts
+        .iter()
+        .filter_map(|payout| match payout.share {
+            PayoutShare::Fixed(sat) => Some(Amount::from_sat(sat)),
+            PayoutShare::BasisPoints(_) => None,
+        })
+        .try_fold(Amount::ZERO, Amount::checked_add)
+        .ok_or_else(|| invalid_payouts("Fixed payouts overflow"))
+}
+
+/// Add up the basis points of the shares, or fail if they overflow.
+fn share_total(payouts: &[Payout]) -> Result<u64, Error> {
+    payouts
+        .iter()
+        .filter_map(|payout| match payout.share {
+            PayoutShare::BasisPoints(points) => Some(u64::from(points)),
+            PayoutShare::Fixed(_) => None,
+        })
+        .try_fold(0u64, u64::checked_add)
+        .ok_or_else(|| invalid_payouts("Payout shares overflow"))
+}
+
+/// Split the value between the payouts once the fee is paid.
+///
+/// Fixed payouts are paid first, then the remainder is shared by basis
+/// points, the last share taking the rounding. The outputs and the fee
+/// always add up to the value.
+///
+/// ### Errors
+/// If the value does not cover the fixed payouts and the fee, or the shares
+/// do not add up.
+pub fn split_value(value: Amount, fee: Amount, payouts: &[Payout]) -> Result<Vec<Amount>, Error> {
+    if share_total(payouts)? != u64::from(FULL_SHARE) {
+        return Err(invalid_payouts("Payout shares must add up to 100%"));
+    }
+    let fixed_total = fixed_total(payouts)?;
+    let remainder = value
+        .checked_sub(fee)
+        .and_then(|value| value.checked_sub(fixed_total))
+        .ok_or_else(|| invalid_payouts("Locker value does not cover the payouts and fee"))?;
+
+    let mut left = remainder;
+    let mut shares_left = payouts
+        .iter()
+        .filter(|payout| matches!(payout.share, PayoutShare::BasisPoints(_)))
+        .count();
+    let amounts = payouts
+        .iter()
+        .map(|payout| match payout.share {
+            PayoutShare::Fixed(sat) => Amount::from_sat(sat),
+            PayoutShare::BasisPoints(points) => {
+                shares_left -= 1;
+                // Widened so large values cannot overflow, the share is never
+                // more than the remainder
+                let share = if shares_left == 0 {
+                    left
+                } else {
+                    Amount::from_sat(
+                        (u128::from(remainder.to_sat()) * u128::from(points)
+                            / u128::from(FULL_SHARE)) as u64,
+                    )
+                };
+                left -= share;
+                share
+            }
+        })
+        .collect();
+
+    Ok(amounts)
+}
+
+/// Set the payouts as the outputs of the locker spend, with the fee sized
+/// for the given witness.
+pub(super) fn apply_payouts(
+    tx: &mut Transaction,
+    value: Amount,
+    fee_rate: FeeRate,
+    witness: Witness,
+    payouts: &[Payout],
+) -> Result<(), Error> {
+    tx.output = payouts
+        .iter()
+        .map(|payout| TxOut {
+            value: Amount::ZERO,
+            script_pubkey: payout.address.assume_checked_ref().script_pubkey(),
+        })
+        .collect();
+
+    tx.input[0].witness = witness;
+    let fee = fee_rate
+        .fee_wu(tx.weight())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Fee rate is too high"))?;
+    tx.input[0].witness = Witness::new();
+
+    for (output, amount) in tx.output.iter_mut().zip(split_value(value, fee, payouts)?) {
+        if amount < output.script_pubkey.minimal_non_dust() {
+            return Err(invalid_payouts("Payout is below the dust threshold"));
+        }
+        output.value = amount;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{crypto::HashAlgorithm, transactions};
+
+    fn payout(seed: u8, share: PayoutShare) -> Payout {
+        let address = transactions::p2wsh_address(
+            &transactions::generate_witness_script(
+                &[seed; 32],
+                HashAlgorithm::Sha256,
+                [2u8; 20],
+                None,
+            )
+            .unwrap(),
+            Network::Regtest,
+        );
+
+        Payout {
+            address: address.as_unchecked().clone(),
+            share,
+        }
+    }
+
+    #[test]
+    fn test_split_value() {
+        let value = Amount::from_sat(100_000);
+        let fee = Amount::from_sat(1_001);
+        let payouts = [
+            payout(1, PayoutShare::BasisPoints(9_750)),
+            payout(2, PayoutShare::BasisPoints(250)),
+            payout(3, PayoutShare::Fixed(10_000)),
+        ];
+        let amounts = split_value(value, fee, &payouts).unwrap();
+        assert_eq!(amounts[1], Amount::from_sat(2_225));
+        assert_eq!(amounts[2], Amount::from_sat(10_000));
+        assert_eq!(amounts.iter().copied().sum::<Amount>() + fee, value);
+
+        // The rest of the value is never left to the fee
+        let fixed = [payout(1, PayoutShare::Fixed(60_000))];
+        assert!(split_value(value, fee, &fixed).is_err());
+        let too_much = [payout(1, PayoutShare::Fixed(99_500))];
+        assert!(split_value(value, fee, &too_much).is_err());
+        let uneven = [payout(1, PayoutShare::BasisPoints(5_000))];
+        assert!(split_value(value, fee, &uneven).is_err());
+    }
+
+    #[test]
+    fn test_validate_payouts() {
+        let amount = Amount::from_sat(100_000);
+        let payouts = [
+            payout(1, PayoutShare::BasisPoints(FULL_SHARE)),
+            payout(2, PayoutShare::Fixed(5_000)),
+        ];
+        assert!(validate_payouts(&payouts, amount, Network::Regtest).is_ok());
+        assert!(validate_payouts(&payouts, amount, Network::Bitcoin).is_err());
+        assert!(validate_payouts(&[], amount, Network::Regtest).is_err());
+
+        let fixed = [payout(1, PayoutShare::Fixed(100_000))];
+        assert!(validate_payouts(&fixed, amount, Network::Regtest).is_err());
+        let fixed = [payout(1, PayoutShare::Fixed(60_000))];
+        assert!(validate_payouts(&fixed, amount, Network::Regtest).is_err());
+        let overflow = [
+            payout(1, PayoutShare::BasisPoints(FULL_SHARE)),
+            payout(2, PayoutShare::Fixed(u64::MAX)),
+            payout(3, PayoutShare::Fixed(1_000)),
+        ];
+        let error = validate_payouts(&overflow, amount, Network::Regtest).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        // Every payout gets an output, none of them can be dust or empty
+        let dust = [
+            payout(1, PayoutShare::BasisPoints(FULL_SHARE)),
+            payout(2, PayoutShare::Fixed(1)),
+        ];
+        let error = validate_payouts(&dust, amount, Network::Regtest).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        let zero_share = [
+            payout(1, PayoutShare::BasisPoints(FULL_SHARE)),
+            payout(2, PayoutShare::BasisPoints(0)),
+        ];
+        let error = validate_payouts(&zero_share, amount, Network::Regtest).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+
+        let serialized = serde_json::to_string(&payouts[1]).unwrap();
+        assert!(serialized.contains("\"fixed\":5000"));
+        assert_eq!(
+            serde_json::from_str::<Payout>(&serialized).unwrap(),
+            payouts[1]
+        );
+    }
+}
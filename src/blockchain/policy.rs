@@ -0,0 +1,624 @@
+use std::{
+    fmt,
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use bitcoin::{
+    absolute,
+    hex::{DisplayHex, FromHex},
+    opcodes::all::*,
+    relative,
+    script::{Builder, PushBytesBuf},
+    Address, Amount, FeeRate, Network, OutPoint, ScriptBuf, Sequence, Transaction, Witness,
+};
+use secp256k1::PublicKey;
+
+use super::{
+    crypto::HashAlgorithm,
+    descriptor,
+    signer::Signer,
+    transactions::{self, HashLock, Timelock, MAX_SIGNATURE_SIZE, PREIMAGE_SIZE},
+};
+
+/// A spending policy for a locker.
+///
+/// Policies are written like `or(and(sha256(H),pk(G)),and(pk(D),after(N)))`
+/// where keys are compressed public keys and hashes are in hex, in the byte
+/// order they have in the script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Policy {
+    /// `pk(KEY)`: a signature of the key
+    Key(PublicKey),
+
+    /// `sha256(H)`, `hash256(H)`, `ripemd160(H)` or `hash160(H)`: a 32 byte
+    /// preimage of the hash
+    Hash(HashLock),
+
+    /// `after(N)` for absolute and `older(N)` for relative timelocks
+    Timelock(Timelock),
+
+    /// `and(X,Y)`: both policies
+    And(Box<Policy>, Box<Policy>),
+
+    /// `or(X,Y)`: any of the policies
+    Or(Box<Policy>, Box<Policy>),
+}
+
+/// An element of the witness before it is signed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum WitnessItem {
+    Signature(PublicKey),
+    Data(Vec<u8>),
+}
+
+impl WitnessItem {
+    fn size(&self) -> usize {
+        match self {
+            WitnessItem::Signature(_) => MAX_SIGNATURE_SIZE + 1,
+            WitnessItem::Data(data) => data.len() + 1,
+        }
+    }
+}
+
+/// The witness elements that satisfy a policy, bottom of the stack first,
+/// with the timelocks the spend must carry.
+#[derive(Debug, Clone, Default)]
+struct Satisfaction {
+    items: Vec<WitnessItem>,
+    lock_time: Option<absolute::LockTime>,
+    sequence: Option<relative::LockTime>,
+}
+
+impl Satisfaction {
+    fn size(&self) -> usize {
+        self.items.iter().map(WitnessItem::size).sum()
+    }
+
+    /// Satisfaction of both policies, which cannot mix timelock units.
+    fn and(first: Satisfaction, second: Satisfaction) -> Option<Satisfaction> {
+        let lock_time = match (first.lock_time, second.lock_time) {
+            // The later lock time satisfies both
+            (Some(a), Some(b)) if a.is_same_unit(b) => Some(if a.is_implied_by(b) { b } else { a }),
+            (Some(_), Some(_)) => return None,
+            (a, b) => a.or(b),
+        };
+        let sequence = match (first.sequence, second.sequence) {
+            (Some(a), Some(b)) if a.is_same_unit(b) => Some(if a.is_implied_by(b) { b } else { a }),
+            (Some(_), Some(_)) => return None,
+            (a, b) => a.or(b),
+        };
+        // The first policy runs first so its elements are on top
+        let mut items = second.items;
+        items.extend(first.items);
+
+        Some(Satisfaction {
+            items,
+            lock_time,
+            sequence,
+        })
+    }
+
+    fn with_item(mut self, item: WitnessItem) -> Self {
+        self.items.push(item);
+        self
+    }
+}
+
+/// Wrap a data push the way the script builder expects.
+fn push_bytes(data: &[u8]) -> PushBytesBuf {
+    PushBytesBuf::try_from(data.to_vec()).expect("Data fits in a script push")
+}
+
+impl Policy {
+    /// Append the script of the policy to the builder. With `verify` the
+    /// fragment aborts on failure instead of leaving false on the stack.
+    fn compile(&self, builder: Builder, verify: bool) -> Builder {
+        match self {
+            Policy::Key(key) => builder.push_slice(key.serialize()).push_opcode(if verify {
+                OP_CHECKSIGVERIFY
+            } else {
+                OP_CHECKSIG
+            }),
+            Policy::Hash(hash_lock) => builder
+                .push_opcode(OP_SIZE)
+                .push_int(PREIMAGE_SIZE as i64)
+                .push_opcode(OP_EQUALVERIFY)
+                .push_opcode(hash_lock.algorithm.opcode())
+                .push_slice(push_bytes(&hash_lock.hash))
+                .push_opcode(if verify { OP_EQUALVERIFY } else { OP_EQUAL }),
+            Policy::Timelock(timelock) => {
+                let builder = match timelock {
+                    Timelock::Absolute(lock_time) => {
+                        builder.push_lock_time(*lock_time).push_opcode(OP_CLTV)
+                    }
+                    Timelock::Relative(lock_time) => builder
+                        .push_sequence(lock_time.to_sequence())
+                        .push_opcode(OP_CSV),
+                };
+                match verify {
+                    true => builder.push_opcode(OP_VERIFY),
+                    false => builder,
+                }
+            }
+            Policy::And(first, second) => {
+                let builder = first.compile(builder, true);
+                second.compile(builder, verify)
+            }
+            Policy::Or(first, second) => {
+                let builder = first.compile(builder.push_opcode(OP_IF), false);
+                let builder = second
+                    .compile(builder.push_opcode(OP_ELSE), false)
+                    .push_opcode(OP_ENDIF);
+                match verify {
+                    true => builder.push_opcode(OP_VERIFY),
+                    false => builder,
+                }
+            }
+        }
+    }
+
+    /// Miniscript the policy compiles to.
+    fn miniscript(&self, verify: bool) -> String {
+        let fragment = match self {
+            Policy::Key(_) | Policy::Hash(_) | Policy::Timelock(_) => self.to_string(),
+            Policy::And(first, second) => {
+                format!(
+                    "and_v({},{})",
+                    first.miniscript(true),
+                    second.miniscript(false)
+                )
+            }
+            Policy::Or(first, second) => {
+                format!(
+                    "or_i({},{})",
+                    first.miniscript(false),
+                    second.miniscript(false)
+                )
+            }
+        };
+
+        match verify {
+            true => format!("v:{}", fragment),
+            false => fragment,
+        }
+    }
+
+    /// Find the smallest witness that satisfies the policy with the given
+    /// signers and preimages.
+    fn satisfy(&self, keys: &[PublicKey], preimages: &[Vec<u8>]) -> Option<Satisfaction> {
+        match self {
+            Policy::Key(key) => keys
+                .contains(key)
+                .then(|| Satisfaction::default().with_item(WitnessItem::Signature(*key))),
+            Policy::Hash(hash_lock) => preimages
+                .iter()
+                .find(|preimage| {
+                    preimage.len() == PREIMAGE_SIZE && hash_lock.is_unlocked_by(preimage)
+                })
+                .map(|preimage| {
+                    Satisfaction::default().with_item(WitnessItem::Data(preimage.clone()))
+                }),
+            Policy::Timelock(Timelock::Absolute(lock_time)) => Some(Satisfaction {
+                lock_time: Some(*lock_time),
+                ..Default::default()
+            }),
+            Policy::Timelock(Timelock::Relative(lock_time)) => Some(Satisfaction {
+                sequence: Some(*lock_time),
+                ..Default::default()
+            }),
+            Policy::And(first, second) => Satisfaction::and(
+                first.satisfy(keys, preimages)?,
+                second.satisfy(keys, preimages)?,
+            ),
+            Policy::Or(first, second) => {
+                // The selector on top of the stack picks the OP_IF branch
+                let first = first
+                    .satisfy(keys, preimages)
+                    .map(|satisfaction| satisfaction.with_item(WitnessItem::Data(vec![1])));
+                let second = second
+                    .satisfy(keys, preimages)
+                    .map(|satisfaction| satisfaction.with_item(WitnessItem::Data(vec![])));
+
+                match (first, second) {
+                    (Some(first), Some(second)) if second.size() < first.size() => Some(second),
+                    (Some(first), _) => Some(first),
+                    (None, second) => second,
+                }
+            }
+        }
+    }
+
+    /// Compile the policy to a P2WSH witness script.
+    pub fn compile_to_script(&self) -> CompiledPolicy {
+        CompiledPolicy {
+            witness_script: self.compile(Builder::new(), false).into_script(),
+            policy: self.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Policy::Key(key) => write!(f, "pk({})", key.serialize().to_lower_hex_string()),
+            Policy::Hash(hash_lock) => write!(
+                f,
+                "{}({})",
+                hash_lock.algorithm.name(),
+                hash_lock.hash.to_lower_hex_string()
+            ),
+            Policy::Timelock(Timelock::Absolute(lock_time)) => {
+                write!(f, "after({})", lock_time.to_consensus_u32())
+            }
+            Policy::Timelock(Timelock::Relative(lock_time)) => {
+                write!(f, "older({})", lock_time.to_consensus_u32())
+            }
+            Policy::And(first, second) => write!(f, "and({},{})", first, second),
+            Policy::Or(first, second) => write!(f, "or({},{})", first, second),
+        }
+    }
+}
+
+fn invalid_policy(message: impl Into<String>) -> Error {
+    Error::new(ErrorKind::InvalidInput, message.into())
+}
+
+/// Split `name(arguments)` and the arguments at top level commas.
+pub(super) fn split_fragment(fragment: &str) -> Result<(&str, Vec<&str>), Error> {
+    let (name, rest) = fragment
+        .split_once('(')
+        .ok_or_else(|| invalid_policy(format!("Invalid policy fragment {}", fragment)))?;
+    let arguments = rest
+        .strip_suffix(')')
+        .ok_or_else(|| invalid_policy(format!("Unclosed policy fragment {}", fragment)))?;
+
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut split = Vec::new();
+    for (i, c) in arguments.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| invalid_policy("Unbalanced parentheses"))?
+            }
+            ',' if depth == 0 => {
+                split.push(&arguments[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth != 0 {
+        return Err(invalid_policy("Unbalanced parentheses"));
+    }
+    split.push(&arguments[start..]);
+
+    Ok((name, split))
+}
+
+fn parse_number(argument: &str) -> Result<u32, Error> {
+    argument
+        .parse()
+        .map_err(|_| invalid_policy(format!("Invalid number {}", argument)))
+}
+
+impl FromStr for Policy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, arguments) = split_fragment(s.trim())?;
+        let arguments = arguments.iter().map(|argument| argument.trim());
+
+        match (name, arguments.collect::<Vec<&str>>().as_slice()) {
+            ("pk", [key]) => PublicKey::from_str(key)
+                .map(Policy::Key)
+                .map_err(|e| invalid_policy(format!("Invalid key {}: {}", key, e))),
+            ("and", [first, second]) => Ok(Policy::And(
+                Box::new(first.parse()?),
+                Box::new(second.parse()?),
+            )),
+            ("or", [first, second]) => Ok(Policy::Or(
+                Box::new(first.parse()?),
+                Box::new(second.parse()?),
+            )),
+            ("after", [lock_time]) => {
+                let lock_time = parse_number(lock_time)?;
+                if lock_time == 0 {
+                    return Err(invalid_policy("after() needs a non zero lock time"));
+                }
+                Ok(Policy::Timelock(Timelock::Absolute(
+                    absolute::LockTime::from_consensus(lock_time),
+                )))
+            }
+            ("older", [sequence]) => {
+                let sequence = parse_number(sequence)?;
+                if sequence == 0 {
+                    return Err(invalid_policy("older() needs a non zero lock time"));
+                }
+                relative::LockTime::from_consensus(sequence)
+                    .map(|lock_time| Policy::Timelock(Timelock::Relative(lock_time)))
+                    .map_err(|e| invalid_policy(e.to_string()))
+            }
+            (name, [hash]) => {
+                let algorithm = [
+                    HashAlgorithm::Sha256,
+                    HashAlgorithm::Hash256,
+                    HashAlgorithm::Hash160,
+                    HashAlgorithm::Ripemd160,
+                ]
+                .into_iter()
+                .find(|algorithm| algorithm.name() == name)
+                .ok_or_else(|| invalid_policy(format!("Unknown policy fragment {}", name)))?;
+                let hash = Vec::<u8>::from_hex(hash)
+                    .ok()
+                    .filter(|hash| hash.len() == algorithm.digest_size())
+                    .ok_or_else(|| invalid_policy(format!("Invalid {} hash", name)))?;
+
+                Ok(Policy::Hash(HashLock { algorithm, hash }))
+            }
+            (name, _) => Err(invalid_policy(format!(
+                "Invalid arguments for policy fragment {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// A policy compiled to a witness script.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledPolicy {
+    pub policy: Policy,
+    pub witness_script: ScriptBuf,
+}
+
+impl CompiledPolicy {
+    /// Address of the locker on the given network.
+    pub fn address(&self, network: Network) -> Address {
+        transactions::p2wsh_address(&self.witness_script, network)
+    }
+
+    /// Miniscript of the witness script.
+    pub fn miniscript(&self) -> String {
+        self.policy.miniscript(false)
+    }
+
+    /// `wsh()` output descriptor of the locker, with checksum.
+    pub fn descriptor(&self) -> String {
+        let descriptor = format!("wsh({})", self.miniscript());
+        let checksum =
+            descriptor::descriptor_checksum(&descriptor).expect("Miniscript uses the charset");
+
+        format!("{}#{}", descriptor, checksum)
+    }
+
+    /// Build a transaction that spends the locker with the cheapest path the
+    /// signers and preimages can satisfy. Timelocks of the path are set in the
+    /// transaction.
+    ///
+    /// ### Errors
+    /// If no path of the policy can be satisfied or the locker value does not
+    /// cover the fee.
+    pub fn build_spend_transaction(
+        &self,
+        outpoint: OutPoint,
+        value: Amount,
+        destination: &Address,
+        fee_rate: FeeRate,
+        signers: &[&dyn Signer],
+        preimages: &[Vec<u8>],
+    ) -> Result<Transaction, Error> {
+        let keys = signers
+            .iter()
+            .map(|signer| signer.public_key())
+            .collect::<Vec<PublicKey>>();
+        let satisfaction = self.policy.satisfy(&keys, preimages).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Policy cannot be satisfied with the given signers and preimages",
+            )
+        })?;
+
+        let sequence = match satisfaction.sequence {
+            Some(lock_time) => lock_time.to_sequence(),
+            None => Sequence::ENABLE_RBF_NO_LOCKTIME,
+        };
+        let lock_time = satisfaction.lock_time.unwrap_or(absolute::LockTime::ZERO);
+        let mut tx = transactions::spend_transaction(outpoint, destination, sequence, lock_time);
+
+        let witness = |items: Vec<Vec<u8>>| {
+            let mut witness = Witness::from_slice(&items);
+            witness.push(self.witness_script.as_bytes());
+            witness
+        };
+        let placeholder = satisfaction
+            .items
+            .iter()
+            .map(|item| match item {
+                WitnessItem::Signature(_) => vec![0u8; MAX_SIGNATURE_SIZE],
+                WitnessItem::Data(data) => data.clone(),
+            })
+            .collect();
+        transactions::apply_fee(&mut tx, value, fee_rate, witness(placeholder))?;
+
+        let items = satisfaction
+            .items
+            .iter()
+            .map(|item| match item {
+                WitnessItem::Signature(key) => {
+                    let signer = signers
+                        .iter()
+                        .find(|signer| signer.public_key() == *key)
+                        .expect("Satisfaction only uses the signer keys");
+                    transactions::sign_locker_input(&tx, &self.witness_script, value, *signer)
+                }
+                WitnessItem::Data(data) => Ok(data.clone()),
+            })
+            .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+        tx.input[0].witness = witness(items);
+
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{sighash::Prevouts, Script, TxOut};
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, interpreter};
+
+    fn prevout(compiled: &CompiledPolicy, value: Amount) -> Prevouts<'static, TxOut> {
+        Prevouts::One(
+            0,
+            TxOut {
+                value,
+                script_pubkey: ScriptBuf::new_p2wsh(&compiled.witness_script.wscript_hash()),
+            },
+        )
+    }
+
+    fn destination() -> Address {
+        transactions::p2wsh_address(Script::from_bytes(&[0x51]), Network::Regtest)
+    }
+
+    async fn locker_policy() -> (Policy, GuardianWallet, GuardianWallet) {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let policy = format!(
+            "or(and(sha256({}),pk({})),and(pk({}),after(900000)))",
+            HashLock::new(HashAlgorithm::Sha256, &[7u8; 32])
+                .hash
+                .to_lower_hex_string(),
+            guardian.public_key(),
+            depositor.public_key(),
+        );
+
+        (policy.parse().unwrap(), guardian, depositor)
+    }
+
+    #[tokio::test]
+    async fn test_policy_roundtrip() {
+        let (policy, _, _) = locker_policy().await;
+        assert_eq!(Policy::from_str(&policy.to_string()).unwrap(), policy);
+
+        assert!(Policy::from_str("pk(00)").is_err());
+        assert!(Policy::from_str("sha256(0011)").is_err());
+        assert!(Policy::from_str("and(after(10))").is_err());
+        assert!(Policy::from_str("or(after(10),older(5)").is_err());
+        assert!(Policy::from_str("thresh(1,after(10))").is_err());
+        assert!(Policy::from_str("after(0)").is_err());
+        assert!(Policy::from_str("older(0)").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compile_policy() {
+        let (policy, guardian, depositor) = locker_policy().await;
+        let compiled = policy.compile_to_script();
+        let hash = HashLock::new(HashAlgorithm::Sha256, &[7u8; 32]).hash;
+
+        let expected = Builder::new()
+            .push_opcode(OP_IF)
+            .push_opcode(OP_SIZE)
+            .push_int(32)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_SHA256)
+            .push_slice(push_bytes(&hash))
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(guardian.public_key().serialize())
+            .push_opcode(OP_CHECKSIG)
+            .push_opcode(OP_ELSE)
+            .push_slice(depositor.public_key().serialize())
+            .push_opcode(OP_CHECKSIGVERIFY)
+            .push_lock_time(absolute::LockTime::from_consensus(900_000))
+            .push_opcode(OP_CLTV)
+            .push_opcode(OP_ENDIF)
+            .into_script();
+        assert_eq!(compiled.witness_script, expected);
+        assert_eq!(
+            compiled.miniscript(),
+            format!(
+                "or_i(and_v(v:sha256({}),pk({})),and_v(v:pk({}),after(900000)))",
+                hash.to_lower_hex_string(),
+                guardian.public_key(),
+                depositor.public_key()
+            )
+        );
+        assert!(compiled.descriptor().starts_with("wsh(or_i("));
+        assert_eq!(
+            compiled.address(Network::Regtest),
+            transactions::p2wsh_address(&expected, Network::Regtest)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_satisfy_policy() {
+        let (policy, guardian, depositor) = locker_policy().await;
+        let compiled = policy.compile_to_script();
+        let value = Amount::from_sat(100_000);
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+
+        let claim = compiled
+            .build_spend_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                fee_rate,
+                &[&guardian],
+                &[vec![7u8; 32]],
+            )
+            .unwrap();
+        assert_eq!(claim.lock_time, absolute::LockTime::ZERO);
+        interpreter::verify_spend(&claim, 0, &prevout(&compiled, value)).unwrap();
+
+        let refund = compiled
+            .build_spend_transaction(
+                OutPoint::null(),
+                value,
+                &destination(),
+                fee_rate,
+                &[&depositor],
+                &[],
+            )
+            .unwrap();
+        assert_eq!(
+            refund.lock_time,
+            absolute::LockTime::from_consensus(900_000)
+        );
+        interpreter::verify_spend(&refund, 0, &prevout(&compiled, value)).unwrap();
+
+        // The guardian alone cannot claim without the secret
+        let missing_secret = compiled.build_spend_transaction(
+            OutPoint::null(),
+            value,
+            &destination(),
+            fee_rate,
+            &[&guardian],
+            &[vec![8u8; 32]],
+        );
+        assert!(missing_secret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_satisfy_rejects_mixed_timelocks() {
+        let signer = GuardianWallet::generate_new().await.unwrap();
+        let policy: Policy = format!(
+            "and(pk({}),and(after(900000),after(1700000000)))",
+            signer.public_key()
+        )
+        .parse()
+        .unwrap();
+
+        let tx = policy.compile_to_script().build_spend_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination(),
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &[&signer],
+            &[],
+        );
+        assert!(tx.is_err());
+    }
+}
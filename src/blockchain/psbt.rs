@@ -0,0 +1,554 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{
+    ecdsa,
+    hashes::{hash160, ripemd160, sha256, sha256d, Hash},
+    psbt::Input,
+    script::{Instruction, PushBytesBuf},
+    secp256k1::{Message, Secp256k1},
+    sighash::{EcdsaSighashType, SighashCache},
+    Address, Amount, FeeRate, OutPoint, Psbt, PublicKey, ScriptBuf, Transaction, TxOut,
+};
+
+use super::{
+    crypto::HashAlgorithm,
+    payout::Payout,
+    signer::Signer,
+    transactions::{self, AddressType, HashLock},
+};
+
+/// Returns true if the script pushes the given data.
+fn script_contains(witness_script: &ScriptBuf, data: &[u8]) -> bool {
+    let Ok(data) = PushBytesBuf::try_from(data.to_vec()) else {
+        return false;
+    };
+
+    witness_script
+        .instructions()
+        .any(|instruction| instruction == Ok(Instruction::PushBytes(data.as_push_bytes())))
+}
+
+/// Wrap the unsigned locker spend in a PSBT carrying the witness script and
+/// the locker output being spent, plus the redeem script for P2SH lockers.
+fn locker_psbt(
+    mut tx: Transaction,
+    value: Amount,
+    witness_script: ScriptBuf,
+    address_type: AddressType,
+) -> Result<Psbt, Error> {
+    // The fee already accounts for the scriptSig, which is added on finalize
+    tx.input[0].script_sig = ScriptBuf::new();
+    let mut psbt =
+        Psbt::from_unsigned_tx(tx).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+    let p2wsh = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+    let script_pubkey = match address_type {
+        AddressType::P2wsh => p2wsh,
+        AddressType::P2shP2wsh => {
+            let script_pubkey = ScriptBuf::new_p2sh(&p2wsh.script_hash());
+            psbt.inputs[0].redeem_script = Some(p2wsh);
+            script_pubkey
+        }
+    };
+    psbt.inputs[0].witness_utxo = Some(TxOut {
+        value,
+        script_pubkey,
+    });
+    psbt.inputs[0].witness_script = Some(witness_script);
+
+    Ok(psbt)
+}
+
+fn no_locker_input() -> Error {
+    Error::new(ErrorKind::InvalidInput, "PSBT has no locker input")
+}
+
+/// Store the secret in the preimage map of the locker hash algorithm.
+fn insert_preimage(input: &mut Input, hash_lock: &HashLock, secret: &[u8]) -> Result<(), Error> {
+    let invalid = |e| Error::new(ErrorKind::InvalidData, e);
+    let secret = secret.to_vec();
+    match hash_lock.algorithm {
+        HashAlgorithm::Sha256 => input.sha256_preimages.insert(
+            sha256::Hash::from_slice(&hash_lock.hash).map_err(invalid)?,
+            secret,
+        ),
+        HashAlgorithm::Hash256 => input.hash256_preimages.insert(
+            sha256d::Hash::from_slice(&hash_lock.hash).map_err(invalid)?,
+            secret,
+        ),
+        HashAlgorithm::Hash160 => input.hash160_preimages.insert(
+            hash160::Hash::from_slice(&hash_lock.hash).map_err(invalid)?,
+            secret,
+        ),
+        HashAlgorithm::Ripemd160 => input.ripemd160_preimages.insert(
+            ripemd160::Hash::from_slice(&hash_lock.hash).map_err(invalid)?,
+            secret,
+        ),
+    };
+
+    Ok(())
+}
+
+/// The secret held in whichever preimage map the claim uses.
+fn preimage(input: &Input) -> Option<Vec<u8>> {
+    input
+        .sha256_preimages
+        .values()
+        .chain(input.hash256_preimages.values())
+        .chain(input.hash160_preimages.values())
+        .chain(input.ripemd160_preimages.values())
+        .next()
+        .cloned()
+}
+
+/// Create a PSBT that claims a funded locker to the payouts.
+///
+/// The secret is stored as a preimage of the locker hash algorithm in the
+/// input so any finalizer can complete the witness once the recipient
+/// signature is added.
+///
+/// ### Errors
+/// If the secret does not unlock the locker or the locker value does not
+/// cover the payouts and the fee.
+pub fn create_claim_psbt(
+    outpoint: OutPoint,
+    value: Amount,
+    payouts: &[Payout],
+    fee_rate: FeeRate,
+    secret: &[u8],
+    witness_script: ScriptBuf,
+    address_type: AddressType,
+) -> Result<Psbt, Error> {
+    let Some(hash_lock) = transactions::committed_hash_lock(&witness_script)
+        .filter(|hash_lock| hash_lock.is_unlocked_by(secret))
+    else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Secret does not unlock this locker",
+        ));
+    };
+
+    let tx = transactions::unsigned_claim_transaction(
+        outpoint,
+        value,
+        payouts,
+        fee_rate,
+        secret,
+        &witness_script,
+        address_type,
+    )?;
+    let mut psbt = locker_psbt(tx, value, witness_script, address_type)?;
+    insert_preimage(&mut psbt.inputs[0], &hash_lock, secret)?;
+
+    Ok(psbt)
+}
+
+/// Create a PSBT that refunds an expired locker to the depositor.
+///
+/// The timelock fields of the spend are taken from the refund branch of the
+/// witness script, so they always satisfy its CLTV or CSV check.
+///
+/// ### Errors
+/// If the locker has no refund branch or its value does not cover the fee.
+pub fn create_refund_psbt(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    witness_script: ScriptBuf,
+    address_type: AddressType,
+) -> Result<Psbt, Error> {
+    let Some(timelock) = transactions::committed_timelock(&witness_script)
+        .filter(|_| transactions::has_refund_branch(&witness_script))
+    else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Locker has no refund branch",
+        ));
+    };
+
+    let tx = transactions::unsigned_refund_transaction(
+        outpoint,
+        value,
+        destination,
+        fee_rate,
+        &witness_script,
+        timelock,
+        address_type,
+    )?;
+
+    locker_psbt(tx, value, witness_script, address_type)
+}
+
+/// Add the signature of the signer for the locker input as a partial
+/// signature, signing the BIP143 sighash computed here.
+///
+/// ### Errors
+/// If the PSBT is missing the locker data or the signer is not part of the
+/// locker contract.
+pub fn sign_psbt(psbt: &mut Psbt, wallet: &dyn Signer) -> Result<(), Error> {
+    let input = psbt.inputs.first().ok_or_else(no_locker_input)?;
+    let (Some(witness_script), Some(utxo)) = (&input.witness_script, &input.witness_utxo) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "PSBT is missing the locker witness script or output",
+        ));
+    };
+    if !script_contains(witness_script, &wallet.public_key_commitment()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Wallet is not part of this locker",
+        ));
+    }
+
+    let signature =
+        transactions::sign_locker_input(&psbt.unsigned_tx, witness_script, utxo.value, wallet)?;
+    let signature = ecdsa::Signature::from_slice(&signature)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let public_key = PublicKey::from_slice(&wallet.public_key().serialize())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    psbt.inputs
+        .first_mut()
+        .ok_or_else(no_locker_input)?
+        .partial_sigs
+        .insert(public_key, signature);
+
+    Ok(())
+}
+
+/// Check every partial signature of the locker input against the BIP143
+/// sighash of the PSBT, e.g. the ones returned by an external signer.
+///
+/// ### Errors
+/// If the PSBT is missing the locker data, or a signature does not commit to
+/// the whole transaction or does not verify.
+pub fn verify_partial_sigs(psbt: &Psbt) -> Result<(), Error> {
+    let input = psbt.inputs.first().ok_or_else(no_locker_input)?;
+    let (Some(witness_script), Some(utxo)) = (&input.witness_script, &input.witness_utxo) else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "PSBT is missing the locker witness script or output",
+        ));
+    };
+
+    let secp = Secp256k1::verification_only();
+    let mut cache = SighashCache::new(&psbt.unsigned_tx);
+    for (public_key, signature) in &input.partial_sigs {
+        if signature.sighash_type != EcdsaSighashType::All {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Signature of {} does not sign the whole transaction",
+                    public_key
+                ),
+            ));
+        }
+        let sighash = cache
+            .p2wsh_signature_hash(0, witness_script, utxo.value, signature.sighash_type)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+        secp.verify_ecdsa(
+            &Message::from_digest(sighash.to_byte_array()),
+            &signature.signature,
+            &public_key.inner,
+        )
+        .map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Invalid signature of {}", public_key),
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Merge PSBTs of the same locker spend, e.g. signed by different parties.
+///
+/// ### Errors
+/// If no PSBT is given or they do not spend the same transaction.
+pub fn combine_psbts(psbts: Vec<Psbt>) -> Result<Psbt, Error> {
+    let mut psbts = psbts.into_iter();
+    let mut combined = psbts
+        .next()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "No PSBT to combine"))?;
+    for psbt in psbts {
+        combined
+            .combine(psbt)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    }
+
+    Ok(combined)
+}
+
+/// Build the final witness of the locker input from the partial signature
+/// and, for claims, the secret preimage.
+///
+/// The claim branch is picked when a preimage is present, otherwise the PSBT
+/// is finalized as a refund. The signature used is the one whose key matches
+/// the branch, and the PSBT is left unchanged when finalizing fails.
+///
+/// ### Errors
+/// If the locker input is not signed by the branch key or cannot be
+/// satisfied.
+pub fn finalize_psbt(psbt: &mut Psbt) -> Result<(), Error> {
+    let input = psbt.inputs.first_mut().ok_or_else(no_locker_input)?;
+    let Some(witness_script) = input.witness_script.clone() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "PSBT is missing the locker witness script",
+        ));
+    };
+
+    // Pick the branch first so a failed finalize leaves the PSBT untouched
+    let secret = preimage(input);
+    let branch = match secret {
+        Some(_) => 0,
+        None if transactions::has_refund_branch(&witness_script) => 1,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Locker claim is missing the secret",
+            ))
+        }
+    };
+    let Some(key_hash) = transactions::contract_key_hashes(&witness_script)
+        .get(branch)
+        .copied()
+    else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Witness script is not a locker contract",
+        ));
+    };
+    let Some((public_key, signature)) = input
+        .partial_sigs
+        .iter()
+        .find(|(public_key, _)| public_key.pubkey_hash().to_byte_array() == key_hash)
+    else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Locker input is not signed",
+        ));
+    };
+
+    let signature = signature.to_vec();
+    let public_key = public_key.to_bytes();
+    let witness = match secret {
+        Some(secret) => {
+            transactions::claim_witness(&signature, &public_key, &secret, &witness_script)
+        }
+        None => transactions::refund_witness(&signature, &public_key, &witness_script),
+    };
+
+    if input.redeem_script.take().is_some() {
+        input.final_script_sig = Some(AddressType::P2shP2wsh.script_sig(&witness_script));
+    }
+    input.final_script_witness = Some(witness);
+    input.witness_script = None;
+    input.partial_sigs.clear();
+    input.sha256_preimages.clear();
+    input.hash256_preimages.clear();
+    input.hash160_preimages.clear();
+    input.ripemd160_preimages.clear();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{absolute, relative};
+
+    use super::*;
+    use crate::blockchain::{
+        address::GuardianWallet,
+        secret,
+        signer::Signer,
+        transactions::{Refund, Timelock},
+    };
+
+    fn hash_lock() -> HashLock {
+        HashLock::new(HashAlgorithm::Sha256, &[1u8; 32])
+    }
+
+    fn destination() -> Address {
+        transactions::generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_claim_psbt_matches_claim_transaction() {
+        for algorithm in [
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Hash256,
+            HashAlgorithm::Hash160,
+            HashAlgorithm::Ripemd160,
+        ] {
+            for address_type in [AddressType::P2wsh, AddressType::P2shP2wsh] {
+                assert_claim_psbt_matches_claim_transaction(algorithm, address_type).await;
+            }
+        }
+    }
+
+    async fn assert_claim_psbt_matches_claim_transaction(
+        algorithm: HashAlgorithm,
+        address_type: AddressType,
+    ) {
+        let entropy = secret::token_bytes::<32>();
+        let mnemonic = secret::generate_secret(&entropy).await.unwrap();
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let value = Amount::from_sat(100_000);
+        let witness_script = transactions::hash_lock_contract(
+            &HashLock::new(algorithm, &entropy),
+            guardian.public_key_commitment(),
+            None,
+        );
+
+        let unsigned = create_claim_psbt(
+            OutPoint::null(),
+            value,
+            &[Payout::to(&destination())],
+            fee_rate,
+            &entropy,
+            witness_script.into_script_buf(),
+            address_type,
+        )
+        .unwrap();
+        let mut signed = Psbt::from_str(&unsigned.to_string()).unwrap();
+        sign_psbt(&mut signed, &guardian).unwrap();
+
+        let mut psbt = combine_psbts(vec![unsigned, signed]).unwrap();
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = psbt.extract_tx().unwrap();
+
+        let expected = transactions::build_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination(),
+            fee_rate,
+            mnemonic,
+            algorithm,
+            &guardian,
+            None,
+            address_type,
+        )
+        .await
+        .unwrap();
+        assert_eq!(tx, expected);
+    }
+
+    #[tokio::test]
+    async fn test_refund_psbt() {
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let refund = Refund {
+            depositor: depositor.public_key_commitment(),
+            timelock: Timelock::Absolute(absolute::LockTime::from_height(900_000).unwrap()),
+        };
+        let witness_script =
+            transactions::hash_lock_contract(&hash_lock(), [2u8; 20], Some(refund));
+
+        let mut psbt = create_refund_psbt(
+            OutPoint::null(),
+            Amount::from_sat(50_000),
+            &destination(),
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            witness_script.into_script_buf(),
+            AddressType::P2wsh,
+        )
+        .unwrap();
+        let unsigned = psbt.clone();
+        assert!(finalize_psbt(&mut psbt).is_err());
+        assert_eq!(psbt, unsigned);
+
+        sign_psbt(&mut psbt, &depositor).unwrap();
+        verify_partial_sigs(&psbt).unwrap();
+        // A signature from a key outside the refund branch is ignored
+        let (_, &signature) = psbt.inputs[0].partial_sigs.first_key_value().unwrap();
+        let foreign = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        psbt.inputs[0].partial_sigs.insert(foreign, signature);
+        assert!(verify_partial_sigs(&psbt).is_err());
+        finalize_psbt(&mut psbt).unwrap();
+        let tx = psbt.extract_tx().unwrap();
+        assert_eq!(tx.input[0].witness.len(), 4);
+        assert_eq!(
+            tx.input[0].witness.nth(1).unwrap(),
+            depositor.public_key().serialize()
+        );
+        assert_eq!(
+            tx.lock_time,
+            absolute::LockTime::from_height(900_000).unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_psbt_rejects_foreign_secret_and_wallet() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let witness_script: ScriptBuf =
+            transactions::hash_lock_contract(&hash_lock(), [2u8; 20], None).into_script_buf();
+
+        let claim = create_claim_psbt(
+            OutPoint::null(),
+            Amount::from_sat(50_000),
+            &[Payout::to(&destination())],
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            &[7u8; 32],
+            witness_script.clone(),
+            AddressType::P2wsh,
+        );
+        assert!(claim.is_err());
+
+        let refund = create_refund_psbt(
+            OutPoint::null(),
+            Amount::from_sat(50_000),
+            &destination(),
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            witness_script,
+            AddressType::P2wsh,
+        );
+        assert!(refund.is_err());
+
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Relative(relative::LockTime::from_height(6)),
+        };
+        let witness_script =
+            transactions::hash_lock_contract(&hash_lock(), [2u8; 20], Some(refund));
+        let mut psbt = create_refund_psbt(
+            OutPoint::null(),
+            Amount::from_sat(50_000),
+            &destination(),
+            FeeRate::from_sat_per_vb(1).unwrap(),
+            witness_script.into_script_buf(),
+            AddressType::P2wsh,
+        )
+        .unwrap();
+        // The refund takes its timelock from the script
+        assert_eq!(
+            psbt.unsigned_tx.input[0].sequence.to_relative_lock_time(),
+            Some(relative::LockTime::from_height(6))
+        );
+        assert!(sign_psbt(&mut psbt, &guardian).is_err());
+
+        // A PSBT without inputs is an error, not a panic
+        let mut empty = Psbt::from_unsigned_tx(Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap();
+        for error in [
+            sign_psbt(&mut empty, &guardian).unwrap_err(),
+            verify_partial_sigs(&empty).unwrap_err(),
+            finalize_psbt(&mut empty).unwrap_err(),
+            guardian.sign_psbt(&mut empty).unwrap_err(),
+        ] {
+            assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -109,11 +109,20 @@ pub async fn generate_secret(entropy: &[u8]) -> SecretResult<Vec<String>> {
 /// A 16-byte array containing the entropy.
 pub async fn mnemonic_to_entropy(mnemonic: Vec<String>) -> SecretResult<Vec<u8>> {
     let words = read_word_list(super::WORD_LIST_PATH).await?;
+    if ![12, 24].contains(&mnemonic.len()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid mnemonic length",
+        ));
+    }
 
     let mnemonic_indices = mnemonic
         .iter()
-        .map(|word| words.iter().position(|w| w == word).unwrap())
-        .collect::<Vec<usize>>();
+        .map(|word| words.iter().position(|w| w == word))
+        .collect::<Option<Vec<usize>>>()
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "Unknown mnemonic word")
+        })?;
 
     let total_bits = mnemonic_indices.iter().fold(String::new(), |acc, index| {
         let bit_group = format!("{:011b}", index);
@@ -204,6 +213,17 @@ mod tests {
         assert!(mnemonic.is_err());
     }
 
+    #[tokio::test]
+    async fn test_invalid_mnemonic() {
+        let entropy = secret::mnemonic_to_entropy(vec!["abandon".to_string(); 11]).await;
+        assert!(entropy.is_err());
+
+        let mut mnemonic = vec!["abandon".to_string(); 11];
+        mnemonic.push("notaword".to_string());
+        let entropy = secret::mnemonic_to_entropy(mnemonic).await;
+        assert!(entropy.is_err());
+    }
+
     #[tokio::test]
     async fn test_mnemonic_to_entropy() {
         let entropy = token_bytes::<16>();
@@ -0,0 +1,627 @@
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Write},
+    net::Shutdown,
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Child, Command, Stdio},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use bitcoin::{
+    hex::{DisplayHex, FromHex},
+    Address, CompressedPublicKey, Network, Psbt,
+};
+use secp256k1::{
+    ecdsa::{SerializedSignature, Signature},
+    schnorr, Message, PublicKey, Secp256k1, XOnlyPublicKey,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::settings::get_settings;
+
+use super::{crypto, psbt, types::RecipientKey};
+
+/// Time the external signer has to answer a request. A signer that misses it
+/// is given up, its late answer would be taken for the next request.
+pub const SIGNER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Signer of the settings, shared by the requests so they do not reconnect
+static SETTINGS_SIGNER: Mutex<Option<Arc<ExternalSigner>>> = Mutex::new(None);
+
+/// Key that signs locker spends, wherever the private key is kept.
+pub trait Signer {
+    fn public_key(&self) -> PublicKey;
+
+    /// Sign the digest with ECDSA and return the DER encoded signature.
+    ///
+    /// ### Errors
+    /// If the signer fails or refuses to sign.
+    fn sign(&self, hashed_data: [u8; 32]) -> Result<SerializedSignature, Error>;
+
+    /// Sign with BIP340 Schnorr, as required by taproot spends.
+    ///
+    /// ### Errors
+    /// If the signer fails or refuses to sign.
+    fn sign_schnorr(&self, hashed_data: [u8; 32]) -> Result<schnorr::Signature, Error>;
+
+    /// Add the signature of the key for the locker input of the PSBT.
+    ///
+    /// ### Errors
+    /// If the PSBT is not a locker spend of the key or the signer fails.
+    fn sign_psbt(&self, psbt: &mut Psbt) -> Result<(), Error>;
+
+    fn x_only_public_key(&self) -> XOnlyPublicKey {
+        self.public_key().x_only_public_key().0
+    }
+
+    fn public_key_commitment(&self) -> RecipientKey {
+        crypto::hash_160(&self.public_key().serialize())
+    }
+
+    /// Native segwit address paying to the key.
+    fn p2wpkh_address(&self, network: Network) -> Address {
+        let public_key = CompressedPublicKey::from_slice(&self.public_key().serialize())
+            .expect("Signer key is a valid compressed key");
+
+        Address::p2wpkh(&public_key, network)
+    }
+}
+
+/// Request of the external signer protocol, one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum SignerRequest {
+    PublicKey,
+    SignEcdsa { digest: String },
+    SignSchnorr { digest: String },
+    SignPsbt { psbt: String },
+}
+
+/// Response of the external signer, the hex or base64 result or an error.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SignerResponse {
+    Result(String),
+    Error(String),
+}
+
+fn invalid_response(e: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+fn handle_request(signer: &dyn Signer, request: SignerRequest) -> Result<String, Error> {
+    let digest = |digest: &str| {
+        <[u8; 32]>::from_hex(digest).map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+    };
+
+    match request {
+        SignerRequest::PublicKey => Ok(signer.public_key().to_string()),
+        SignerRequest::SignEcdsa { digest: hex } => {
+            Ok(signer.sign(digest(&hex)?)?.to_vec().to_lower_hex_string())
+        }
+        SignerRequest::SignSchnorr { digest: hex } => Ok(signer
+            .sign_schnorr(digest(&hex)?)?
+            .to_byte_array()
+            .to_lower_hex_string()),
+        SignerRequest::SignPsbt { psbt } => {
+            let mut psbt =
+                Psbt::from_str(&psbt).map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+            signer.sign_psbt(&mut psbt)?;
+
+            Ok(psbt.to_string())
+        }
+    }
+}
+
+/// Answer external signer requests with the signer until the reader is
+/// closed. This is the signing side of [`ExternalSigner`], e.g. run by a
+/// signing service next to the guardian key.
+///
+/// ### Errors
+/// If the connection fails. Failed requests are answered with an error and
+/// do not stop the loop.
+pub fn serve(
+    signer: &dyn Signer,
+    reader: impl BufRead,
+    mut writer: impl Write,
+) -> Result<(), Error> {
+    for line in reader.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = serde_json::from_str::<SignerRequest>(&line)
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+            .and_then(|request| handle_request(signer, request));
+        let response = match response {
+            Ok(result) => SignerResponse::Result(result),
+            Err(e) => SignerResponse::Error(e.to_string()),
+        };
+
+        serde_json::to_writer(&mut writer, &response).map_err(Error::other)?;
+        writer.write_all(b"\n")?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+struct Connection {
+    writer: Box<dyn Write + Send>,
+    /// Response lines, read on their own thread so requests can time out
+    responses: Receiver<Result<String, Error>>,
+}
+
+impl Connection {
+    fn new(reader: impl BufRead + Send + 'static, writer: impl Write + Send + 'static) -> Self {
+        let (sender, responses) = mpsc::channel();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let failed = line.is_err();
+                if sender.send(line).is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            writer: Box::new(writer),
+            responses,
+        }
+    }
+
+    /// Send the request and wait for its response line, at most `timeout`.
+    fn exchange(
+        &mut self,
+        request: &SignerRequest,
+        timeout: Duration,
+    ) -> Result<SignerResponse, Error> {
+        let mut line = serde_json::to_string(request).map_err(Error::other)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        self.writer.flush()?;
+
+        let line = match self.responses.recv_timeout(timeout) {
+            Ok(line) => line?,
+            Err(RecvTimeoutError::Timeout) => {
+                return Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "Signer did not answer in time",
+                ))
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "Signer closed the connection",
+                ))
+            }
+        };
+
+        serde_json::from_str::<SignerResponse>(&line).map_err(invalid_response)
+    }
+}
+
+/// Signer that forwards every request to a separate process, so the guardian
+/// key can live in an isolated signing service or an HSM.
+///
+/// Requests and responses are JSON objects, one per line, over the standard
+/// input and output of a child process or a Unix socket. Every request has
+/// to be answered within the timeout, and signatures are checked against the
+/// public key of the signer before they are used. Requests block, async code
+/// runs them on the blocking thread pool.
+pub struct ExternalSigner {
+    connection: Mutex<Connection>,
+    public_key: PublicKey,
+    timeout: Duration,
+    /// Set once a request failed midway, the connection is out of step
+    broken: AtomicBool,
+    child: Option<Child>,
+    socket: Option<UnixStream>,
+}
+
+impl ExternalSigner {
+    /// Start the signer process and talk to it over its standard input and
+    /// output.
+    ///
+    /// ### Errors
+    /// If the process cannot be started or does not return its public key.
+    pub fn spawn(program: impl AsRef<Path>, args: &[&str]) -> Result<Self, Error> {
+        let mut child = Command::new(program.as_ref())
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(Error::other("Signer process has no standard streams"));
+        };
+        let connection = Connection::new(BufReader::new(stdout), stdin);
+
+        Self::new(connection, Some(child), None)
+    }
+
+    /// Connect to a signer listening on the Unix socket.
+    ///
+    /// ### Errors
+    /// If the socket cannot be reached or the signer does not return its
+    /// public key.
+    pub fn connect(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path)?;
+        stream.set_write_timeout(Some(SIGNER_TIMEOUT))?;
+        let connection = Connection::new(BufReader::new(stream.try_clone()?), stream.try_clone()?);
+
+        Self::new(connection, None, Some(stream))
+    }
+
+    /// The signer of the settings, or `None` if no signer socket is set. The
+    /// connection is shared and only made again once it broke.
+    ///
+    /// ### Errors
+    /// If the signer socket cannot be reached.
+    pub fn from_settings() -> Result<Option<Arc<Self>>, Error> {
+        let Some(socket) = get_settings().signer_socket.clone() else {
+            return Ok(None);
+        };
+        let mut shared = SETTINGS_SIGNER
+            .lock()
+            .map_err(|_| Error::other("Shared signer is poisoned"))?;
+        if let Some(signer) = shared.as_ref().filter(|signer| !signer.is_broken()) {
+            return Ok(Some(signer.clone()));
+        }
+
+        let signer = Arc::new(Self::connect(socket)?);
+        *shared = Some(signer.clone());
+
+        Ok(Some(signer))
+    }
+
+    fn new(
+        mut connection: Connection,
+        child: Option<Child>,
+        socket: Option<UnixStream>,
+    ) -> Result<Self, Error> {
+        let public_key = connection
+            .exchange(&SignerRequest::PublicKey, SIGNER_TIMEOUT)
+            .and_then(|response| match response {
+                SignerResponse::Result(public_key) => {
+                    PublicKey::from_str(&public_key).map_err(invalid_response)
+                }
+                SignerResponse::Error(e) => Err(Error::other(format!("Signer error: {}", e))),
+            });
+        let public_key = match public_key {
+            Ok(public_key) => public_key,
+            Err(e) => {
+                if let Some(mut child) = child {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            connection: Mutex::new(connection),
+            public_key,
+            timeout: SIGNER_TIMEOUT,
+            broken: AtomicBool::new(false),
+            child,
+            socket,
+        })
+    }
+
+    /// Give the signer `timeout` to answer each request instead of
+    /// [`SIGNER_TIMEOUT`].
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Whether a request failed midway, no more requests are sent then.
+    pub fn is_broken(&self) -> bool {
+        self.broken.load(Ordering::Acquire)
+    }
+
+    fn request(&self, request: &SignerRequest) -> Result<String, Error> {
+        let mut connection = self
+            .connection
+            .lock()
+            .map_err(|_| Error::other("Signer connection is poisoned"))?;
+        if self.is_broken() {
+            return Err(Error::new(
+                ErrorKind::BrokenPipe,
+                "Signer connection is broken",
+            ));
+        }
+
+        let response = connection.exchange(request, self.timeout);
+        match response {
+            Ok(SignerResponse::Result(result)) => Ok(result),
+            Ok(SignerResponse::Error(e)) => Err(Error::other(format!("Signer error: {}", e))),
+            Err(e) => {
+                self.broken.store(true, Ordering::Release);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    fn sign(&self, hashed_data: [u8; 32]) -> Result<SerializedSignature, Error> {
+        let digest = hashed_data.to_lower_hex_string();
+        let signature = self.request(&SignerRequest::SignEcdsa { digest })?;
+        let signature = Vec::<u8>::from_hex(&signature).map_err(invalid_response)?;
+        let signature = Signature::from_der(&signature).map_err(invalid_response)?;
+
+        Secp256k1::verification_only()
+            .verify_ecdsa(
+                &Message::from_digest(hashed_data),
+                &signature,
+                &self.public_key,
+            )
+            .map_err(invalid_response)?;
+
+        Ok(signature.serialize_der())
+    }
+
+    fn sign_schnorr(&self, hashed_data: [u8; 32]) -> Result<schnorr::Signature, Error> {
+        let digest = hashed_data.to_lower_hex_string();
+        let signature = self.request(&SignerRequest::SignSchnorr { digest })?;
+        let signature = <[u8; 64]>::from_hex(&signature).map_err(invalid_response)?;
+        let signature = schnorr::Signature::from_byte_array(signature);
+
+        Secp256k1::verification_only()
+            .verify_schnorr(&signature, &hashed_data, &self.x_only_public_key())
+            .map_err(invalid_response)?;
+
+        Ok(signature)
+    }
+
+    fn sign_psbt(&self, psbt: &mut Psbt) -> Result<(), Error> {
+        if psbt.inputs.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "PSBT has no locker input",
+            ));
+        }
+        let signed = self.request(&SignerRequest::SignPsbt {
+            psbt: psbt.to_string(),
+        })?;
+        let signed = Psbt::from_str(&signed).map_err(invalid_response)?;
+        if signed.unsigned_tx != psbt.unsigned_tx {
+            return Err(invalid_response("Signer returned another transaction"));
+        }
+
+        // Only a PSBT whose signatures all verify replaces the caller's
+        let mut combined = psbt.clone();
+        combined.combine(signed).map_err(invalid_response)?;
+        let public_key = bitcoin::PublicKey::from_slice(&self.public_key.serialize())
+            .map_err(invalid_response)?;
+        let signed_by_signer = combined
+            .inputs
+            .first()
+            .is_some_and(|input| input.partial_sigs.contains_key(&public_key));
+        if !signed_by_signer {
+            return Err(invalid_response("Signer did not sign the locker input"));
+        }
+        psbt::verify_partial_sigs(&combined).map_err(invalid_response)?;
+        *psbt = combined;
+
+        Ok(())
+    }
+}
+
+impl Drop for ExternalSigner {
+    fn drop(&mut self) {
+        // Closing the input ends the signer loop and the response reader
+        if let Ok(mut connection) = self.connection.lock() {
+            connection.writer = Box::new(std::io::sink());
+        }
+        if let Some(socket) = self.socket.take() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        // The process is reaped here, a signer that stopped answering is
+        // killed first
+        if let Some(mut child) = self.child.take() {
+            if self.is_broken() {
+                let _ = child.kill();
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{os::unix::net::UnixListener, thread};
+
+    use bitcoin::{Amount, OutPoint};
+
+    use super::*;
+    use crate::blockchain::{
+        address::GuardianWallet,
+        crypto::HashAlgorithm,
+        interpreter,
+        payout::Payout,
+        psbt, secret,
+        transactions::{self, AddressType},
+    };
+
+    fn socket_path() -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "coinslock-signer-{}.sock",
+            secret::token_bytes::<8>().to_lower_hex_string()
+        ))
+    }
+
+    /// Serve the signer on a new Unix socket, as a stand-in signing service.
+    fn serve_on_socket(signer: impl Signer + Send + 'static) -> std::path::PathBuf {
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve(&signer, BufReader::new(stream.try_clone().unwrap()), stream).unwrap();
+        });
+
+        path
+    }
+
+    /// Signing service that returns PSBT signatures over the wrong digest
+    struct ForgingSigner(GuardianWallet);
+
+    impl Signer for ForgingSigner {
+        fn public_key(&self) -> PublicKey {
+            self.0.public_key()
+        }
+
+        fn sign(&self, hashed_data: [u8; 32]) -> Result<SerializedSignature, Error> {
+            self.0.sign(hashed_data)
+        }
+
+        fn sign_schnorr(&self, hashed_data: [u8; 32]) -> Result<schnorr::Signature, Error> {
+            self.0.sign_schnorr(hashed_data)
+        }
+
+        fn sign_psbt(&self, psbt: &mut Psbt) -> Result<(), Error> {
+            let public_key =
+                bitcoin::PublicKey::from_slice(&self.public_key().serialize()).unwrap();
+            let mut signature = self.0.sign([7u8; 32])?.to_vec();
+            signature.push(bitcoin::EcdsaSighashType::All.to_u32() as u8);
+            let signature = bitcoin::ecdsa::Signature::from_slice(&signature).unwrap();
+            psbt.inputs[0].partial_sigs.insert(public_key, signature);
+
+            Ok(())
+        }
+    }
+
+    fn claim_psbt(signer: &dyn Signer) -> Psbt {
+        let secret = [9u8; 32];
+        let witness_script = transactions::generate_witness_script(
+            &secret,
+            HashAlgorithm::Sha256,
+            signer.public_key_commitment(),
+            None,
+        )
+        .unwrap();
+
+        psbt::create_claim_psbt(
+            OutPoint::null(),
+            Amount::from_sat(50_000),
+            &[Payout::to(&signer.p2wpkh_address(Network::Regtest))],
+            bitcoin::FeeRate::from_sat_per_vb(2).unwrap(),
+            &secret,
+            witness_script,
+            AddressType::P2wsh,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_external_signer() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let public_key = wallet.public_key();
+        let local =
+            GuardianWallet::from_master_key(wallet.master_key().0, wallet.master_key().1.clone())
+                .unwrap();
+        let path = serve_on_socket(wallet);
+        let signer = ExternalSigner::connect(&path).unwrap();
+        assert_eq!(signer.public_key(), public_key);
+
+        let digest = [5u8; 32];
+        assert_eq!(signer.sign(digest).unwrap(), local.sign(digest).unwrap());
+        let signature = signer.sign_schnorr(digest).unwrap();
+        assert!(Secp256k1::verification_only()
+            .verify_schnorr(&signature, &digest, &public_key.x_only_public_key().0)
+            .is_ok());
+
+        // The PSBT is signed by the service and finalized locally
+        let mut claim = claim_psbt(&signer);
+        signer.sign_psbt(&mut claim).unwrap();
+        psbt::finalize_psbt(&mut claim).unwrap();
+        let prevout = claim.inputs[0].witness_utxo.clone().unwrap();
+        let tx = claim.extract_tx().unwrap();
+        interpreter::verify_spend(&tx, 0, &bitcoin::sighash::Prevouts::One(0, prevout)).unwrap();
+
+        drop(signer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_external_signer_rejects_forged_psbt() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let path = serve_on_socket(ForgingSigner(wallet));
+        let signer = ExternalSigner::connect(&path).unwrap();
+
+        let mut claim = claim_psbt(&signer);
+        let unsigned = claim.clone();
+        let error = signer.sign_psbt(&mut claim).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert_eq!(claim, unsigned);
+
+        drop(signer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_external_signer_timeout() {
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let public_key = wallet.public_key();
+        let path = socket_path();
+        let listener = UnixListener::bind(&path).unwrap();
+        // Answers the public key, then never again
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut lines = BufReader::new(stream).lines();
+            lines.next().unwrap().unwrap();
+            let response = SignerResponse::Result(public_key.to_string());
+            writeln!(writer, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+            for _ in lines {}
+        });
+
+        let signer = ExternalSigner::connect(&path)
+            .unwrap()
+            .with_timeout(Duration::from_millis(100));
+        assert_eq!(signer.public_key(), public_key);
+        let error = signer.sign([5u8; 32]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::TimedOut);
+        assert!(signer.is_broken());
+        let error = signer.sign([5u8; 32]).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::BrokenPipe);
+
+        drop(signer);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_serve_errors() {
+        let requests = concat!(
+            "{\"method\":\"sign_ecdsa\",\"digest\":\"00\"}\n",
+            "not json\n",
+        );
+        let wallet = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(GuardianWallet::generate_new())
+            .unwrap();
+        let mut output = Vec::new();
+        serve(&wallet, requests.as_bytes(), &mut output).unwrap();
+
+        let responses = output
+            .lines()
+            .map(|line| serde_json::from_str::<SignerResponse>(&line.unwrap()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(responses.len(), 2);
+        assert!(responses
+            .iter()
+            .all(|response| matches!(response, SignerResponse::Error(_))));
+    }
+}
@@ -0,0 +1,782 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{
+    absolute, Address, Amount, FeeRate, Network, OutPoint, ScriptBuf, Transaction, Txid,
+};
+
+use super::{
+    adapters::BitcoinAdapter,
+    crypto::HashAlgorithm,
+    fees,
+    signer::Signer,
+    transactions::{self, AddressType, HashLock, Refund, Timelock},
+    types::RecipientKey,
+};
+
+/// Seconds between the participant and the initiator refund deadlines, the
+/// time the participant has to claim once the initiator revealed the secret.
+pub const SWAP_SAFETY_MARGIN: u32 = 12 * 60 * 60;
+
+/// The party of the swap this instance acts for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SwapRole {
+    /// Knows the secret and claims the participant locker first.
+    Initiator,
+
+    /// Learns the secret from the initiator claim and claims the initiator locker.
+    Participant,
+}
+
+/// A party of the swap with the network it funds its locker on and the
+/// amount both parties agreed it locks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwapParty {
+    pub key: RecipientKey,
+    pub network: Network,
+    pub amount: Amount,
+}
+
+/// Confirmed funding output of a locker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Funding {
+    pub outpoint: OutPoint,
+    pub value: Amount,
+
+    /// Chain height the output was found unspent at, spends are searched
+    /// from there
+    pub height: u64,
+}
+
+/// One of the two lockers of the swap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SwapLocker {
+    pub network: Network,
+    pub recipient: RecipientKey,
+    pub refund: Refund,
+    pub witness_script: ScriptBuf,
+    pub address: Address,
+
+    /// Agreed amount, a funding output below it is never claimed
+    pub amount: Amount,
+    pub funding: Option<Funding>,
+}
+
+/// Progress of the swap.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SwapState {
+    /// Waiting for both lockers to be funded
+    Created,
+
+    /// Both lockers are funded and waiting to be claimed
+    Funded,
+
+    /// Our claim was broadcast
+    Claimed(Txid),
+}
+
+/// An atomic swap between two lockers sharing the same secret hash.
+///
+/// Both refunds open at absolute deadlines, so they do not depend on when
+/// each locker confirms. The initiator locker refund opens
+/// [`SWAP_SAFETY_MARGIN`] after the participant one, so the participant always
+/// has time to claim after the secret is revealed.
+#[derive(Debug, Clone)]
+pub struct AtomicSwap {
+    pub role: SwapRole,
+    pub hash_lock: HashLock,
+    pub secret: Option<Vec<u8>>,
+
+    /// Funded by the initiator, claimed by the participant
+    pub initiator_locker: SwapLocker,
+
+    /// Funded by the participant, claimed by the initiator
+    pub participant_locker: SwapLocker,
+
+    pub state: SwapState,
+
+    /// Our last broadcast claim, kept to replace it if it gets stuck
+    pub claim: Option<Transaction>,
+}
+
+impl SwapLocker {
+    fn new(
+        hash_lock: &HashLock,
+        recipient: RecipientKey,
+        depositor: &SwapParty,
+        deadline: absolute::LockTime,
+    ) -> Result<Self, Error> {
+        let refund = Refund {
+            depositor: depositor.key,
+            timelock: Timelock::Absolute(deadline),
+        };
+        transactions::check_refund(Some(&refund))?;
+        let witness_script =
+            transactions::hash_lock_contract(hash_lock, recipient, Some(refund)).into_script_buf();
+        let address = transactions::p2wsh_address(&witness_script, depositor.network);
+
+        Ok(Self {
+            network: depositor.network,
+            recipient,
+            refund,
+            witness_script,
+            address,
+            amount: depositor.amount,
+            funding: None,
+        })
+    }
+
+    /// Look for the funding output of the counterparty locker on its chain,
+    /// until one paying at least the agreed amount is found. Smaller outputs,
+    /// e.g. dust sent to the address, are ignored.
+    async fn discover_funding<C: BitcoinAdapter>(&mut self, chain: &C) -> Result<(), Error> {
+        if self.funding.is_some() {
+            return Ok(());
+        }
+        let Some(utxo) = chain
+            .list_unspent(&self.address)
+            .await?
+            .into_iter()
+            .find(|utxo| utxo.txout.value >= self.amount)
+        else {
+            return Ok(());
+        };
+        let height = chain.get_height().await?;
+        self.funding = Some(Funding {
+            outpoint: utxo.outpoint,
+            value: utxo.txout.value,
+            height,
+        });
+
+        Ok(())
+    }
+
+    fn funding(&self) -> Result<Funding, Error> {
+        let funding = self
+            .funding
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Swap locker is not funded"))?;
+        if funding.value < self.amount {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "Swap locker holds {} sat, {} sat were agreed",
+                    funding.value.to_sat(),
+                    self.amount.to_sat()
+                ),
+            ));
+        }
+
+        Ok(funding)
+    }
+}
+
+impl AtomicSwap {
+    fn new(
+        role: SwapRole,
+        hash_lock: HashLock,
+        initiator: SwapParty,
+        participant: SwapParty,
+        deadline: u32,
+    ) -> Result<Self, Error> {
+        let invalid_deadline = || Error::new(ErrorKind::InvalidInput, "Invalid swap deadline");
+        let participant_deadline =
+            absolute::LockTime::from_time(deadline).map_err(|_| invalid_deadline())?;
+        let initiator_deadline = deadline
+            .checked_add(SWAP_SAFETY_MARGIN)
+            .and_then(|deadline| absolute::LockTime::from_time(deadline).ok())
+            .ok_or_else(invalid_deadline)?;
+
+        Ok(Self {
+            role,
+            secret: None,
+            initiator_locker: SwapLocker::new(
+                &hash_lock,
+                participant.key,
+                &initiator,
+                initiator_deadline,
+            )?,
+            participant_locker: SwapLocker::new(
+                &hash_lock,
+                initiator.key,
+                &participant,
+                participant_deadline,
+            )?,
+            hash_lock,
+            state: SwapState::Created,
+            claim: None,
+        })
+    }
+
+    /// Start a swap as the initiator, who picks the secret.
+    ///
+    /// `deadline` is the Unix time from which the participant can refund its
+    /// locker, both parties must agree on it.
+    ///
+    /// ### Errors
+    /// If the secret is not 32 bytes long or the deadline is not a valid
+    /// lock time.
+    pub fn initiate(
+        secret: &[u8],
+        algorithm: HashAlgorithm,
+        initiator: SwapParty,
+        participant: SwapParty,
+        deadline: u32,
+    ) -> Result<Self, Error> {
+        transactions::check_preimage_size(secret)?;
+        let mut swap = Self::new(
+            SwapRole::Initiator,
+            HashLock::new(algorithm, secret),
+            initiator,
+            participant,
+            deadline,
+        )?;
+        swap.secret = Some(secret.to_vec());
+
+        Ok(swap)
+    }
+
+    /// Join a swap as the participant, knowing only the hash lock.
+    ///
+    /// ### Errors
+    /// If the deadline is not a valid lock time.
+    pub fn participate(
+        hash_lock: HashLock,
+        initiator: SwapParty,
+        participant: SwapParty,
+        deadline: u32,
+    ) -> Result<Self, Error> {
+        Self::new(
+            SwapRole::Participant,
+            hash_lock,
+            initiator,
+            participant,
+            deadline,
+        )
+    }
+
+    /// The locker this side of the swap claims.
+    pub fn claimable_locker(&self) -> &SwapLocker {
+        match self.role {
+            SwapRole::Initiator => &self.participant_locker,
+            SwapRole::Participant => &self.initiator_locker,
+        }
+    }
+
+    /// The locker this side of the swap funds.
+    pub fn funded_locker(&self) -> &SwapLocker {
+        match self.role {
+            SwapRole::Initiator => &self.initiator_locker,
+            SwapRole::Participant => &self.participant_locker,
+        }
+    }
+
+    /// Record the output that funded our locker. `height` is a chain height
+    /// at or below the one the funding confirmed at, spends of the locker
+    /// are searched from there.
+    ///
+    /// Our own funding is never looked up in the UTXO set, it may already be
+    /// spent by the counterparty claim when we start polling.
+    ///
+    /// ### Errors
+    /// If the locker was already funded or the output pays less than the
+    /// agreed amount.
+    pub fn record_funding(
+        &mut self,
+        outpoint: OutPoint,
+        value: Amount,
+        height: u64,
+    ) -> Result<(), Error> {
+        let locker = match self.role {
+            SwapRole::Initiator => &mut self.initiator_locker,
+            SwapRole::Participant => &mut self.participant_locker,
+        };
+        if locker.funding.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Swap locker is already funded",
+            ));
+        }
+        if value < locker.amount {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Funding is below the agreed amount",
+            ));
+        }
+        locker.funding = Some(Funding {
+            outpoint,
+            value,
+            height,
+        });
+
+        Ok(())
+    }
+
+    /// Check both lockers and claim ours once it can be done.
+    ///
+    /// Our locker counts as funded once its funding was recorded with
+    /// [`AtomicSwap::record_funding`], the counterparty locker once an
+    /// output paying the agreed amount is found on its chain. The initiator
+    /// claims as soon as both lockers are funded. The participant watches
+    /// the participant locker, extracts the secret from the initiator claim
+    /// and then claims the initiator locker.
+    ///
+    /// ### Errors
+    /// If a chain cannot be queried or the claim cannot be built or broadcast.
+    pub async fn poll<I: BitcoinAdapter, P: BitcoinAdapter>(
+        &mut self,
+        initiator_chain: &I,
+        participant_chain: &P,
+        wallet: &dyn Signer,
+        destination: &Address,
+        fee_rate: FeeRate,
+    ) -> Result<&SwapState, Error> {
+        if let SwapState::Claimed(_) = self.state {
+            return Ok(&self.state);
+        }
+        match self.role {
+            SwapRole::Initiator => {
+                self.participant_locker
+                    .discover_funding(participant_chain)
+                    .await?
+            }
+            SwapRole::Participant => {
+                self.initiator_locker
+                    .discover_funding(initiator_chain)
+                    .await?
+            }
+        }
+        let (Some(_), Some(participant_funding)) = (
+            self.initiator_locker.funding,
+            self.participant_locker.funding,
+        ) else {
+            return Ok(&self.state);
+        };
+        self.state = SwapState::Funded;
+
+        if self.secret.is_none() {
+            let spend = participant_chain
+                .find_spending_tx(&participant_funding.outpoint, participant_funding.height)
+                .await?;
+            // A refund of the participant locker reveals nothing
+            self.secret = spend.and_then(|tx| {
+                transactions::extract_preimage(&tx, &self.participant_locker.witness_script).ok()
+            });
+        }
+        let Some(secret) = &self.secret else {
+            return Ok(&self.state);
+        };
+
+        let tx = self.build_claim(secret, wallet, destination, fee_rate)?;
+
+        self.broadcast_claim(initiator_chain, participant_chain, tx)
+            .await
+    }
+
+    /// Replace our claim with one paying the fee rate estimated for the
+    /// confirmation target, e.g. when the mempool spiked after it was
+    /// broadcast. The replacement always pays more than the claim, as
+    /// required by BIP125.
+    ///
+    /// ### Errors
+    /// If nothing was claimed yet, the fee cannot be estimated or the
+    /// replacement cannot be built or broadcast.
+    pub async fn bump_claim<I: BitcoinAdapter, P: BitcoinAdapter>(
+        &mut self,
+        initiator_chain: &I,
+        participant_chain: &P,
+        wallet: &dyn Signer,
+        destination: &Address,
+        target_blocks: u16,
+    ) -> Result<&SwapState, Error> {
+        let (Some(claim), Some(secret)) = (&self.claim, &self.secret) else {
+            return Err(Error::new(ErrorKind::InvalidInput, "Swap is not claimed"));
+        };
+        let estimate = match self.role {
+            SwapRole::Initiator => participant_chain.estimate_fee_rate(target_blocks).await?,
+            SwapRole::Participant => initiator_chain.estimate_fee_rate(target_blocks).await?,
+        };
+        let funding = self.claimable_locker().funding()?;
+        let fee_rate = fees::replacement_fee_rate(claim, funding.value, estimate)?;
+        let tx = self.build_claim(secret, wallet, destination, fee_rate)?;
+
+        self.broadcast_claim(initiator_chain, participant_chain, tx)
+            .await
+    }
+
+    fn build_claim(
+        &self,
+        secret: &[u8],
+        wallet: &dyn Signer,
+        destination: &Address,
+        fee_rate: FeeRate,
+    ) -> Result<Transaction, Error> {
+        let locker = self.claimable_locker();
+        if wallet.public_key_commitment() != locker.recipient {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Wallet is not the swap locker recipient",
+            ));
+        }
+        let funding = locker.funding()?;
+
+        transactions::build_secret_claim_transaction(
+            funding.outpoint,
+            funding.value,
+            destination,
+            fee_rate,
+            secret,
+            self.hash_lock.algorithm,
+            wallet,
+            Some(locker.refund),
+            AddressType::P2wsh,
+        )
+    }
+
+    async fn broadcast_claim<I: BitcoinAdapter, P: BitcoinAdapter>(
+        &mut self,
+        initiator_chain: &I,
+        participant_chain: &P,
+        tx: Transaction,
+    ) -> Result<&SwapState, Error> {
+        let tx_id = match self.role {
+            SwapRole::Initiator => participant_chain.broadcast(&tx).await?,
+            SwapRole::Participant => initiator_chain.broadcast(&tx).await?,
+        };
+        self.state = SwapState::Claimed(tx_id);
+        self.claim = Some(tx);
+
+        Ok(&self.state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use bitcoin::{hashes::Hash, Transaction, TxOut};
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, funding::Utxo};
+
+    const DEADLINE: u32 = 1_700_000_000;
+
+    /// In memory chain that keeps broadcast transactions in its mempool.
+    /// Spent outputs leave the UTXO set, as with `scantxoutset`.
+    #[derive(Default)]
+    struct MockChain {
+        mempool: Mutex<Vec<Transaction>>,
+        unspent: Mutex<Vec<Utxo>>,
+    }
+
+    impl MockChain {
+        fn fund(&self, address: &Address, funding: Funding) {
+            self.unspent.lock().unwrap().push(Utxo {
+                outpoint: funding.outpoint,
+                txout: TxOut {
+                    value: funding.value,
+                    script_pubkey: address.script_pubkey(),
+                },
+            });
+        }
+    }
+
+    impl BitcoinAdapter for MockChain {
+        async fn get_height(&self) -> Result<u64, Error> {
+            Ok(100)
+        }
+
+        async fn get_tx(&self, _tx_id: &str) -> Option<String> {
+            None
+        }
+
+        async fn find_spending_tx(
+            &self,
+            outpoint: &OutPoint,
+            _from_height: u64,
+        ) -> Result<Option<Transaction>, Error> {
+            let mempool = self.mempool.lock().unwrap();
+            let tx = mempool
+                .iter()
+                .find(|tx| tx.input.iter().any(|i| i.previous_output == *outpoint));
+
+            Ok(tx.cloned())
+        }
+
+        async fn estimate_fee_rate(&self, target_blocks: u16) -> Result<FeeRate, Error> {
+            Ok(fees::fallback_fee_rate(target_blocks))
+        }
+
+        async fn list_unspent(&self, address: &Address) -> Result<Vec<Utxo>, Error> {
+            let unspent = self.unspent.lock().unwrap();
+
+            Ok(unspent
+                .iter()
+                .filter(|utxo| utxo.txout.script_pubkey == address.script_pubkey())
+                .cloned()
+                .collect())
+        }
+
+        async fn broadcast(&self, tx: &Transaction) -> Result<Txid, Error> {
+            self.unspent.lock().unwrap().retain(|utxo| {
+                !tx.input
+                    .iter()
+                    .any(|input| input.previous_output == utxo.outpoint)
+            });
+            self.mempool.lock().unwrap().push(tx.clone());
+
+            Ok(tx.compute_txid())
+        }
+    }
+
+    fn funding(byte: u8) -> Funding {
+        Funding {
+            outpoint: OutPoint::new(Txid::from_byte_array([byte; 32]), 0),
+            value: Amount::from_sat(100_000),
+            height: 100,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swap_lockers() {
+        let initiator = SwapParty {
+            key: [1u8; 20],
+            network: Network::Regtest,
+            amount: Amount::from_sat(100_000),
+        };
+        let participant = SwapParty {
+            key: [2u8; 20],
+            network: Network::Testnet,
+            amount: Amount::from_sat(200_000),
+        };
+        let swap = AtomicSwap::initiate(
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            initiator,
+            participant,
+            DEADLINE,
+        )
+        .unwrap();
+
+        assert_eq!(swap.initiator_locker.recipient, participant.key);
+        assert_eq!(swap.participant_locker.recipient, initiator.key);
+        assert_eq!(swap.initiator_locker.amount, initiator.amount);
+        assert_eq!(swap.participant_locker.amount, participant.amount);
+        assert_eq!(swap.funded_locker(), &swap.initiator_locker);
+        assert_eq!(
+            swap.initiator_locker.refund.timelock,
+            Timelock::Absolute(
+                absolute::LockTime::from_time(DEADLINE + SWAP_SAFETY_MARGIN).unwrap()
+            )
+        );
+        assert_eq!(
+            swap.participant_locker.refund.timelock,
+            Timelock::Absolute(absolute::LockTime::from_time(DEADLINE).unwrap())
+        );
+        assert!(swap
+            .participant_locker
+            .address
+            .to_string()
+            .starts_with("tb1"));
+        assert!(AtomicSwap::initiate(
+            &[7u8; 16],
+            HashAlgorithm::Sha256,
+            initiator,
+            participant,
+            DEADLINE
+        )
+        .is_err());
+        // Block heights and deadlines past the lock time range are rejected
+        for deadline in [800_000, u32::MAX - 1] {
+            assert!(AtomicSwap::initiate(
+                &[7u8; 32],
+                HashAlgorithm::Sha256,
+                initiator,
+                participant,
+                deadline
+            )
+            .is_err());
+        }
+    }
+
+    struct SwapFixture {
+        initiator_wallet: GuardianWallet,
+        participant_wallet: GuardianWallet,
+        initiator_swap: AtomicSwap,
+        participant_swap: AtomicSwap,
+        initiator_chain: MockChain,
+        participant_chain: MockChain,
+    }
+
+    async fn swap_fixture() -> SwapFixture {
+        let initiator_wallet = GuardianWallet::generate_new().await.unwrap();
+        let participant_wallet = GuardianWallet::generate_new().await.unwrap();
+        let initiator = SwapParty {
+            key: initiator_wallet.public_key_commitment(),
+            network: Network::Regtest,
+            amount: funding(1).value,
+        };
+        let participant = SwapParty {
+            key: participant_wallet.public_key_commitment(),
+            network: Network::Regtest,
+            amount: funding(2).value,
+        };
+        let initiator_swap = AtomicSwap::initiate(
+            &[7u8; 32],
+            HashAlgorithm::Sha256,
+            initiator,
+            participant,
+            DEADLINE,
+        )
+        .unwrap();
+        let participant_swap = AtomicSwap::participate(
+            initiator_swap.hash_lock.clone(),
+            initiator,
+            participant,
+            DEADLINE,
+        )
+        .unwrap();
+
+        SwapFixture {
+            initiator_wallet,
+            participant_wallet,
+            initiator_swap,
+            participant_swap,
+            initiator_chain: MockChain::default(),
+            participant_chain: MockChain::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_swap_flow() {
+        let SwapFixture {
+            initiator_wallet,
+            participant_wallet,
+            mut initiator_swap,
+            mut participant_swap,
+            initiator_chain,
+            participant_chain,
+        } = swap_fixture().await;
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let destination = initiator_swap.initiator_locker.address.clone();
+
+        // Nothing happens until both lockers are funded
+        let state = participant_swap
+            .poll(
+                &initiator_chain,
+                &participant_chain,
+                &participant_wallet,
+                &destination,
+                fee_rate,
+            )
+            .await
+            .unwrap();
+        assert_eq!(*state, SwapState::Created);
+
+        assert!(participant_swap
+            .build_claim(&[7u8; 32], &participant_wallet, &destination, fee_rate)
+            .is_err());
+        initiator_chain.fund(&initiator_swap.initiator_locker.address, funding(1));
+        initiator_swap
+            .record_funding(funding(1).outpoint, funding(1).value, 100)
+            .unwrap();
+        participant_chain.fund(&initiator_swap.participant_locker.address, funding(2));
+        participant_swap
+            .record_funding(funding(2).outpoint, funding(2).value, 100)
+            .unwrap();
+        assert!(participant_swap
+            .record_funding(funding(2).outpoint, funding(2).value, 100)
+            .is_err());
+
+        // The initiator claims before the participant polls again, spending
+        // the participant funding out of the UTXO set
+        let state = initiator_swap
+            .poll(
+                &initiator_chain,
+                &participant_chain,
+                &initiator_wallet,
+                &destination,
+                fee_rate,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(state, SwapState::Claimed(_)));
+        assert_eq!(participant_chain.mempool.lock().unwrap().len(), 1);
+        assert!(participant_chain.unspent.lock().unwrap().is_empty());
+
+        let state = participant_swap
+            .poll(
+                &initiator_chain,
+                &participant_chain,
+                &participant_wallet,
+                &destination,
+                fee_rate,
+            )
+            .await
+            .unwrap();
+        assert!(matches!(state, SwapState::Claimed(_)));
+        assert_eq!(participant_swap.secret, Some(vec![7u8; 32]));
+        assert_eq!(participant_swap.initiator_locker.funding, Some(funding(1)));
+
+        let claim = initiator_chain.mempool.lock().unwrap()[0].clone();
+        assert_eq!(claim.input[0].previous_output, funding(1).outpoint);
+
+        // A stuck claim is replaced by one paying the estimated fee rate
+        let state = participant_swap
+            .bump_claim(
+                &initiator_chain,
+                &participant_chain,
+                &participant_wallet,
+                &destination,
+                1,
+            )
+            .await
+            .unwrap();
+        let replacement = initiator_chain.mempool.lock().unwrap()[1].clone();
+        assert_eq!(*state, SwapState::Claimed(replacement.compute_txid()));
+        assert_eq!(replacement.input[0].previous_output, funding(1).outpoint);
+        assert!(replacement.output[0].value < claim.output[0].value);
+    }
+
+    #[tokio::test]
+    async fn test_swap_ignores_underfunded_locker() {
+        let SwapFixture {
+            initiator_wallet,
+            mut initiator_swap,
+            initiator_chain,
+            participant_chain,
+            ..
+        } = swap_fixture().await;
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        let destination = initiator_swap.initiator_locker.address.clone();
+        let dust = Funding {
+            value: Amount::from_sat(1_000),
+            ..funding(2)
+        };
+
+        assert!(initiator_swap
+            .record_funding(dust.outpoint, dust.value, 100)
+            .is_err());
+        initiator_swap
+            .record_funding(funding(1).outpoint, funding(1).value, 100)
+            .unwrap();
+
+        // A dust deposit to the participant locker does not reveal the secret
+        participant_chain.fund(&initiator_swap.participant_locker.address, dust);
+        let state = initiator_swap
+            .poll(
+                &initiator_chain,
+                &participant_chain,
+                &initiator_wallet,
+                &destination,
+                fee_rate,
+            )
+            .await
+            .unwrap();
+        assert_eq!(*state, SwapState::Created);
+        assert!(participant_chain.mempool.lock().unwrap().is_empty());
+
+        initiator_swap.participant_locker.funding = Some(dust);
+        assert!(initiator_swap
+            .build_claim(&[7u8; 32], &initiator_wallet, &destination, fee_rate)
+            .is_err());
+    }
+}
@@ -0,0 +1,436 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{
+    hashes::Hash,
+    key::{Secp256k1 as BitcoinSecp256k1, XOnlyPublicKey as BitcoinXOnlyPublicKey},
+    opcodes::all::*,
+    script::{Builder, PushBytesBuf},
+    sighash::{Prevouts, SighashCache},
+    taproot::{LeafVersion, TapLeafHash, TaprootBuilder, TaprootSpendInfo},
+    Address, Amount, FeeRate, OutPoint, ScriptBuf, TapSighashType, Transaction, TxOut, Witness,
+};
+use secp256k1::{PublicKey, Scalar, Secp256k1, XOnlyPublicKey};
+
+use crate::settings::get_settings;
+
+use super::{
+    crypto,
+    signer::Signer,
+    transactions::{self, HashLock, Timelock, PREIMAGE_SIZE},
+};
+
+/// The BIP341 "nothing up my sleeve" point. Nobody knows its discrete log, so
+/// an output using it as internal key can only be spent through its scripts.
+const NUMS_POINT: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// Size of a BIP340 signature with the default sighash type.
+const SCHNORR_SIGNATURE_SIZE: usize = 64;
+
+/// Key behind the key path of a taproot locker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InternalKey {
+    /// Unspendable key, the locker can only be spent through its leaves.
+    Nums,
+
+    /// MuSig2 aggregate (BIP327 KeyAgg) of the parties keys, in the given
+    /// order. Spending through the key path requires the parties to run the
+    /// MuSig2 signing protocol outside of this crate.
+    MuSig(Vec<PublicKey>),
+
+    /// A key used as is, such as the internal key read from a descriptor.
+    XOnly(XOnlyPublicKey),
+}
+
+/// A taproot locker with the hash lock and the timelocked refund in separate
+/// tapleaves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaprootLocker {
+    pub hash_lock: HashLock,
+    pub recipient: XOnlyPublicKey,
+    pub depositor: XOnlyPublicKey,
+    pub timelock: Timelock,
+    pub internal_key: InternalKey,
+}
+
+/// BIP340 tagged hash.
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = crypto::sha256(tag.as_bytes());
+    let mut preimage = Vec::with_capacity(64 + data.len());
+    preimage.extend_from_slice(&tag_hash);
+    preimage.extend_from_slice(&tag_hash);
+    preimage.extend_from_slice(data);
+
+    crypto::sha256(&preimage)
+}
+
+/// Aggregate the keys following the BIP327 KeyAgg algorithm.
+fn musig_key_agg(keys: &[PublicKey]) -> Result<XOnlyPublicKey, Error> {
+    let Some(first) = keys.first() else {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "MuSig needs at least one key",
+        ));
+    };
+    let secp = Secp256k1::verification_only();
+    let serialized = keys
+        .iter()
+        .flat_map(|key| key.serialize())
+        .collect::<Vec<u8>>();
+    let list_hash = tagged_hash("KeyAgg list", &serialized);
+    // The first key that differs from the first one gets a coefficient of one
+    let second = keys.iter().find(|key| *key != first);
+
+    let tweaked = keys
+        .iter()
+        .map(|key| {
+            if Some(key) == second {
+                return Ok(*key);
+            }
+            let mut data = list_hash.to_vec();
+            data.extend_from_slice(&key.serialize());
+            let coefficient = Scalar::from_be_bytes(tagged_hash("KeyAgg coefficient", &data))
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+            key.mul_tweak(&secp, &coefficient)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))
+        })
+        .collect::<Result<Vec<PublicKey>, Error>>()?;
+    let aggregate = PublicKey::combine_keys(&tweaked.iter().collect::<Vec<&PublicKey>>())
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(aggregate.x_only_public_key().0)
+}
+
+/// Convert a key to the secp256k1 version used by the bitcoin crate.
+fn bitcoin_key(key: &XOnlyPublicKey) -> BitcoinXOnlyPublicKey {
+    BitcoinXOnlyPublicKey::from_slice(&key.serialize()).expect("Key is a valid x-only key")
+}
+
+impl InternalKey {
+    pub(super) fn x_only_public_key(&self) -> Result<XOnlyPublicKey, Error> {
+        match self {
+            InternalKey::Nums => XOnlyPublicKey::from_byte_array(&NUMS_POINT)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e)),
+            InternalKey::MuSig(keys) => musig_key_agg(keys),
+            InternalKey::XOnly(key) => Ok(*key),
+        }
+    }
+}
+
+impl TaprootLocker {
+    /// Leaf that lets the recipient spend with the secret, the miniscript
+    /// `and_v(v:sha256(H),pk(R))` with the hash function of the lock.
+    pub fn hash_lock_leaf(&self) -> ScriptBuf {
+        let secret_hash = PushBytesBuf::try_from(self.hash_lock.hash.clone())
+            .expect("Hash fits in a script push");
+
+        Builder::new()
+            .push_opcode(OP_SIZE)
+            .push_int(PREIMAGE_SIZE as i64)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(self.hash_lock.algorithm.opcode())
+            .push_slice(secret_hash)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_x_only_key(&bitcoin_key(&self.recipient))
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    /// Leaf that lets the depositor spend once the timelock expires, the
+    /// miniscript `and_v(v:older(N),pk(D))` or its `after` form.
+    pub fn refund_leaf(&self) -> ScriptBuf {
+        let builder = match self.timelock {
+            Timelock::Absolute(lock_time) => Builder::new()
+                .push_lock_time(lock_time)
+                .push_opcode(OP_CLTV),
+            Timelock::Relative(lock_time) => Builder::new()
+                .push_sequence(lock_time.to_sequence())
+                .push_opcode(OP_CSV),
+        };
+
+        builder
+            .push_opcode(OP_VERIFY)
+            .push_x_only_key(&bitcoin_key(&self.depositor))
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+    }
+
+    /// Commit both leaves to the internal key.
+    pub fn spend_info(&self) -> Result<TaprootSpendInfo, Error> {
+        let secp = BitcoinSecp256k1::verification_only();
+        let internal_key = bitcoin_key(&self.internal_key.x_only_public_key()?);
+
+        TaprootBuilder::new()
+            .add_leaf(1, self.hash_lock_leaf())
+            .and_then(|builder| builder.add_leaf(1, self.refund_leaf()))
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+            .finalize(&secp, internal_key)
+            .map_err(|_| Error::new(ErrorKind::InvalidInput, "Taproot tree is incomplete"))
+    }
+
+    /// Generate the pay-to-taproot address of the locker.
+    pub fn address(&self) -> Result<Address, Error> {
+        let settings = get_settings();
+        let spend_info = self.spend_info()?;
+
+        Ok(Address::p2tr_tweaked(
+            spend_info.output_key(),
+            settings.network,
+        ))
+    }
+
+    /// Witness that spends the given leaf, with the leaf inputs on top.
+    fn script_path_witness(&self, inputs: &[&[u8]], leaf: &ScriptBuf) -> Result<Witness, Error> {
+        let control_block = self
+            .spend_info()?
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Leaf is not in the locker"))?;
+
+        let mut witness = Witness::new();
+        for input in inputs {
+            witness.push(input);
+        }
+        witness.push(leaf.as_bytes());
+        witness.push(control_block.serialize());
+
+        Ok(witness)
+    }
+
+    /// Sign the locker input through the given leaf following BIP341.
+    fn sign_leaf(
+        &self,
+        tx: &Transaction,
+        value: Amount,
+        leaf: &ScriptBuf,
+        wallet: &dyn Signer,
+    ) -> Result<[u8; SCHNORR_SIGNATURE_SIZE], Error> {
+        let prevout = TxOut {
+            value,
+            script_pubkey: self.address()?.script_pubkey(),
+        };
+        let leaf_hash = TapLeafHash::from_script(leaf, LeafVersion::TapScript);
+        let sighash = SighashCache::new(tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                leaf_hash,
+                TapSighashType::Default,
+            )
+            .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+
+        Ok(wallet
+            .sign_schnorr(sighash.to_byte_array())?
+            .to_byte_array())
+    }
+
+    /// Build and sign a script path spend of the hash lock leaf.
+    ///
+    /// ### Errors
+    /// If the wallet is not the recipient, the secret does not match or is
+    /// not [`PREIMAGE_SIZE`] bytes long, or the locker value does not cover
+    /// the fee.
+    pub fn build_claim_transaction(
+        &self,
+        outpoint: OutPoint,
+        value: Amount,
+        destination: &Address,
+        fee_rate: FeeRate,
+        secret: &[u8],
+        recipient: &dyn Signer,
+    ) -> Result<Transaction, Error> {
+        if recipient.x_only_public_key() != self.recipient {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Wallet is not the locker recipient",
+            ));
+        }
+        transactions::check_preimage_size(secret)?;
+        if !self.hash_lock.is_unlocked_by(secret) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Secret does not unlock this locker",
+            ));
+        }
+        let leaf = self.hash_lock_leaf();
+
+        let mut tx = transactions::spend_transaction(
+            outpoint,
+            destination,
+            bitcoin::Sequence::ENABLE_RBF_NO_LOCKTIME,
+            bitcoin::absolute::LockTime::ZERO,
+        );
+        let placeholder =
+            self.script_path_witness(&[&[0u8; SCHNORR_SIGNATURE_SIZE], secret], &leaf)?;
+        transactions::apply_fee(&mut tx, value, fee_rate, placeholder)?;
+
+        let signature = self.sign_leaf(&tx, value, &leaf, recipient)?;
+        tx.input[0].witness = self.script_path_witness(&[&signature, secret], &leaf)?;
+
+        Ok(tx)
+    }
+
+    /// Build and sign a script path spend of the refund leaf.
+    ///
+    /// ### Errors
+    /// If the wallet is not the depositor or the locker value does not cover
+    /// the fee.
+    pub fn build_refund_transaction(
+        &self,
+        outpoint: OutPoint,
+        value: Amount,
+        destination: &Address,
+        fee_rate: FeeRate,
+        depositor: &dyn Signer,
+    ) -> Result<Transaction, Error> {
+        if depositor.x_only_public_key() != self.depositor {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Wallet is not the locker depositor",
+            ));
+        }
+        let leaf = self.refund_leaf();
+
+        let (sequence, lock_time) = self.timelock.spend_fields();
+        let mut tx = transactions::spend_transaction(outpoint, destination, sequence, lock_time);
+        let placeholder = self.script_path_witness(&[&[0u8; SCHNORR_SIGNATURE_SIZE]], &leaf)?;
+        transactions::apply_fee(&mut tx, value, fee_rate, placeholder)?;
+
+        let signature = self.sign_leaf(&tx, value, &leaf, depositor)?;
+        tx.input[0].witness = self.script_path_witness(&[&signature], &leaf)?;
+
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{hex::FromHex, relative};
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, crypto::HashAlgorithm, interpreter};
+
+    async fn locker_fixture(
+        internal_key: InternalKey,
+    ) -> (TaprootLocker, GuardianWallet, GuardianWallet) {
+        let recipient = GuardianWallet::generate_new().await.unwrap();
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let locker = TaprootLocker {
+            hash_lock: HashLock::new(HashAlgorithm::Sha256, &[7u8; 32]),
+            recipient: recipient.x_only_public_key(),
+            depositor: depositor.x_only_public_key(),
+            timelock: Timelock::Relative(relative::LockTime::from_height(144)),
+            internal_key,
+        };
+
+        (locker, recipient, depositor)
+    }
+
+    fn verify_script_path(locker: &TaprootLocker, tx: &Transaction, value: Amount) {
+        let prevout = TxOut {
+            value,
+            script_pubkey: locker.address().unwrap().script_pubkey(),
+        };
+
+        interpreter::verify_spend(tx, 0, &Prevouts::All(&[prevout])).unwrap();
+    }
+
+    #[test]
+    fn test_musig_key_agg_vector() {
+        // BIP327 KeyAgg test vector
+        let keys = [
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+        ]
+        .iter()
+        .map(|key| PublicKey::from_slice(&Vec::<u8>::from_hex(key).unwrap()).unwrap())
+        .collect::<Vec<PublicKey>>();
+
+        let aggregate = musig_key_agg(&keys).unwrap();
+        assert_eq!(
+            aggregate.serialize().to_vec(),
+            Vec::<u8>::from_hex("90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C")
+                .unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_taproot_address() {
+        let (locker, recipient, depositor) = locker_fixture(InternalKey::Nums).await;
+        let address = locker.address().unwrap();
+        assert!(address.script_pubkey().is_p2tr());
+
+        let musig = TaprootLocker {
+            internal_key: InternalKey::MuSig(vec![recipient.public_key(), depositor.public_key()]),
+            ..locker.clone()
+        };
+        assert_ne!(musig.address().unwrap(), address);
+    }
+
+    #[tokio::test]
+    async fn test_taproot_claim() {
+        let (locker, recipient, depositor) = locker_fixture(InternalKey::Nums).await;
+        let value = Amount::from_sat(100_000);
+        let destination = locker.address().unwrap();
+
+        let tx = locker
+            .build_claim_transaction(
+                OutPoint::null(),
+                value,
+                &destination,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &[7u8; 32],
+                &recipient,
+            )
+            .unwrap();
+        assert_eq!(tx.input[0].witness.len(), 4);
+        assert_eq!(tx.input[0].witness.nth(1).unwrap(), [7u8; 32]);
+        verify_script_path(&locker, &tx, value);
+
+        let wrong_wallet = locker.build_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &[7u8; 32],
+            &depositor,
+        );
+        assert!(wrong_wallet.is_err());
+
+        let wrong_secret = locker.build_claim_transaction(
+            OutPoint::null(),
+            value,
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &[8u8; 32],
+            &recipient,
+        );
+        assert!(wrong_secret.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_taproot_refund() {
+        let (locker, _, depositor) = locker_fixture(InternalKey::Nums).await;
+        let value = Amount::from_sat(100_000);
+        let destination = locker.address().unwrap();
+
+        let tx = locker
+            .build_refund_transaction(
+                OutPoint::null(),
+                value,
+                &destination,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &depositor,
+            )
+            .unwrap();
+        assert_eq!(tx.input[0].witness.len(), 3);
+        assert_eq!(
+            tx.input[0].sequence.to_relative_lock_time(),
+            Some(relative::LockTime::from_height(144))
+        );
+        verify_script_path(&locker, &tx, value);
+    }
+}
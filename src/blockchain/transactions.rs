@@ -1,50 +1,1446 @@
+use std::io::{Error, ErrorKind};
+
 use bitcoin::{
+    absolute,
+    hashes::Hash,
     opcodes::{all::*, OP_0},
-    script::Builder,
-    Address, Script,
+    relative,
+    script::{Builder, Instruction, PushBytesBuf},
+    sighash::{EcdsaSighashType, SighashCache},
+    transaction::Version,
+    Address, Amount, FeeRate, Network, OutPoint, Script, ScriptBuf, Sequence, Transaction, TxIn,
+    TxOut, Witness,
 };
 
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
 use crate::settings::get_settings;
 
 use super::{
-    crypto,
+    crypto::{self, HashAlgorithm},
+    encoding,
+    payout::{self, Payout},
+    secret,
+    signer::Signer,
     types::{HashValue, RecipientKey},
 };
 
+/// Upper bound of a DER encoded ECDSA signature with the sighash type byte,
+/// used to size the witness before the transaction is signed.
+pub(super) const MAX_SIGNATURE_SIZE: usize = 73;
+
+/// Size of a compressed public key.
+pub(super) const PUBLIC_KEY_SIZE: usize = 33;
+
+/// Size of a locker secret. Hash lock scripts check it, as miniscript
+/// requires, so a preimage cannot be made too large to relay.
+pub(super) const PREIMAGE_SIZE: usize = 32;
+
+/// When the refund path of a locker opens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Timelock {
+    /// Block height or timestamp, enforced with OP_CHECKLOCKTIMEVERIFY.
+    #[serde(with = "encoding::absolute_lock_time")]
+    Absolute(absolute::LockTime),
+
+    /// Blocks or time elapsed since the funding output confirmed, enforced
+    /// with OP_CHECKSEQUENCEVERIFY.
+    #[serde(with = "encoding::relative_lock_time")]
+    Relative(relative::LockTime),
+}
+
+impl Timelock {
+    /// The input nSequence and transaction nLockTime a spend must carry to
+    /// satisfy the timelock.
+    pub(super) fn spend_fields(&self) -> (Sequence, absolute::LockTime) {
+        match *self {
+            // Any non final sequence enables the nLockTime check
+            Timelock::Absolute(lock_time) => (Sequence::ENABLE_RBF_NO_LOCKTIME, lock_time),
+            Timelock::Relative(lock_time) => (lock_time.to_sequence(), absolute::LockTime::ZERO),
+        }
+    }
+}
+
+/// Commitment of a locker to its secret, with the hash function that
+/// produced it so the script checks the preimage with the same function.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HashLock {
+    pub algorithm: HashAlgorithm,
+    #[serde(with = "encoding::hex_bytes")]
+    pub hash: Vec<u8>,
+}
+
+impl HashLock {
+    pub fn new(algorithm: HashAlgorithm, secret: &[u8]) -> Self {
+        Self {
+            algorithm,
+            hash: algorithm.digest(secret),
+        }
+    }
+
+    /// Returns true if the preimage unlocks the hash lock.
+    pub fn is_unlocked_by(&self, preimage: &[u8]) -> bool {
+        self.algorithm.digest(preimage) == self.hash
+    }
+}
+
+/// Refund path of a locker. Once the timelock expires the depositor can
+/// take the funds back without the secret.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Refund {
+    /// Public key commitment of the depositor
+    #[serde(with = "encoding::hex_bytes")]
+    pub depositor: RecipientKey,
+
+    /// Timelock after which the refund path opens
+    pub timelock: Timelock,
+}
+
+/// Output type of a locker address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AddressType {
+    /// Native segwit output with a bech32 address
+    #[default]
+    P2wsh,
+
+    /// Segwit output nested in P2SH, for wallets that cannot pay to bech32
+    P2shP2wsh,
+}
+
+impl AddressType {
+    /// Address of the witness script on the network.
+    pub fn address(&self, witness_script: &Script, network: Network) -> Address {
+        match self {
+            AddressType::P2wsh => p2wsh_address(witness_script, network),
+            AddressType::P2shP2wsh => Address::p2shwsh(witness_script, network),
+        }
+    }
+
+    /// Output script of the type for an empty witness script. Every output of
+    /// the type has the same size, so this gives its dust threshold before
+    /// the locker keys are known.
+    pub fn template_script_pubkey(&self) -> ScriptBuf {
+        self.address(Script::new(), Network::Regtest)
+            .script_pubkey()
+    }
+
+    /// The scriptSig of a spend: empty for native segwit, otherwise a push of
+    /// the P2WSH program that the P2SH output commits to.
+    pub(super) fn script_sig(&self, witness_script: &Script) -> ScriptBuf {
+        match self {
+            AddressType::P2wsh => ScriptBuf::new(),
+            AddressType::P2shP2wsh => {
+                let redeem_script = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+                let redeem_script = PushBytesBuf::try_from(redeem_script.into_bytes())
+                    .expect("Redeem script fits in a script push");
+
+                Builder::new().push_slice(redeem_script).into_script()
+            }
+        }
+    }
+}
+
+/// Keys of the parties of a 2-of-3 escrow locker. Any two of them can release
+/// the funds: buyer and seller when the trade goes well, or the arbiter with
+/// either of them to settle a dispute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EscrowKeys {
+    pub buyer: PublicKey,
+    pub seller: PublicKey,
+    pub arbiter: PublicKey,
+}
+
+impl EscrowKeys {
+    /// Keys of the escrow parties, which must all differ. A party holding two
+    /// of the keys could release the funds alone.
+    ///
+    /// ### Errors
+    /// If two parties have the same key.
+    pub fn new(buyer: PublicKey, seller: PublicKey, arbiter: PublicKey) -> Result<Self, Error> {
+        let keys = Self {
+            buyer,
+            seller,
+            arbiter,
+        };
+        keys.check_distinct()?;
+
+        Ok(keys)
+    }
+
+    fn check_distinct(&self) -> Result<(), Error> {
+        if self.buyer == self.seller || self.buyer == self.arbiter || self.seller == self.arbiter {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Escrow parties need distinct keys",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Keys in the order they are committed in the contract.
+    pub(super) fn ordered(&self) -> [PublicKey; 3] {
+        [self.buyer, self.seller, self.arbiter]
+    }
+}
+
+/// Check that the secret has the size the hash lock scripts require, a
+/// locker made for another size could never be spent.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long.
+pub(super) fn check_preimage_size(secret: &[u8]) -> Result<(), Error> {
+    if secret.len() != PREIMAGE_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Secret is {} bytes, lockers need {} bytes",
+                secret.len(),
+                PREIMAGE_SIZE
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the refund path can be spent. The script leaves the lock time
+/// on the stack for the OP_VERIFY after OP_CHECKLOCKTIMEVERIFY or
+/// OP_CHECKSEQUENCEVERIFY, so a zero lock time would lock the depositor out.
+///
+/// ### Errors
+/// If the refund timelock is zero.
+pub(super) fn check_refund(refund: Option<&Refund>) -> Result<(), Error> {
+    let is_zero = match refund.map(|refund| refund.timelock) {
+        Some(Timelock::Absolute(lock_time)) => lock_time.to_consensus_u32() == 0,
+        Some(Timelock::Relative(lock_time)) => lock_time.to_consensus_u32() == 0,
+        None => false,
+    };
+    if is_zero {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Refund timelock cannot be zero",
+        ));
+    }
+
+    Ok(())
+}
+
 /// Create a hash lock contract that locks the funds until the secret is revealed.
-fn hash_lock_contract(secret_hash: HashValue, recipient: RecipientKey) -> Box<Script> {
+///
+/// The contract is the miniscript `and_v(v:sha256(H),pkh(R))`, with the hash
+/// function of the lock. When a refund is given it becomes
+/// `c:or_i(and_v(v:sha256(H),pk_h(R)),and_v(v:after(N),pk_h(D)))`, so the
+/// depositor can spend the funds once the refund timelock expires.
+pub(super) fn hash_lock_contract(
+    hash_lock: &HashLock,
+    recipient: RecipientKey,
+    refund: Option<Refund>,
+) -> Box<Script> {
     // Transform bytes to Push bytes to be added to script
-    let script = Builder::new()
-        .push_opcode(OP_HASH256)
+    let secret_hash =
+        PushBytesBuf::try_from(hash_lock.hash.clone()).expect("Hash fits in a script push");
+    let builder = match refund {
+        Some(_) => Builder::new().push_opcode(OP_IF),
+        None => Builder::new(),
+    };
+    let builder = builder
+        .push_opcode(OP_SIZE)
+        .push_int(PREIMAGE_SIZE as i64)
+        .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(hash_lock.algorithm.opcode())
         .push_slice(&secret_hash)
         .push_opcode(OP_EQUALVERIFY)
         .push_opcode(OP_DUP)
         .push_opcode(OP_HASH160)
-        .push_slice(&recipient)
+        .push_slice(recipient)
+        .push_opcode(OP_EQUALVERIFY);
+    let Some(refund) = refund else {
+        return builder
+            .push_opcode(OP_CHECKSIG)
+            .into_script()
+            .into_boxed_script();
+    };
+
+    let builder = builder.push_opcode(OP_ELSE);
+    let builder = match refund.timelock {
+        Timelock::Absolute(lock_time) => builder.push_lock_time(lock_time).push_opcode(OP_CLTV),
+        Timelock::Relative(lock_time) => builder
+            .push_sequence(lock_time.to_sequence())
+            .push_opcode(OP_CSV),
+    };
+    let script = builder
+        .push_opcode(OP_VERIFY)
+        .push_opcode(OP_DUP)
+        .push_opcode(OP_HASH160)
+        .push_slice(refund.depositor)
         .push_opcode(OP_EQUALVERIFY)
+        .push_opcode(OP_ENDIF)
         .push_opcode(OP_CHECKSIG)
         .into_script();
 
     script.into_boxed_script()
 }
 
+/// Create a 2-of-3 multisig contract between the escrow parties.
+fn escrow_contract(keys: &EscrowKeys) -> Box<Script> {
+    let script = keys
+        .ordered()
+        .iter()
+        .fold(Builder::new().push_opcode(OP_PUSHNUM_2), |builder, key| {
+            builder.push_slice(key.serialize())
+        })
+        .push_opcode(OP_PUSHNUM_3)
+        .push_opcode(OP_CHECKMULTISIG)
+        .into_script();
+
+    script.into_boxed_script()
+}
+
 fn pub_key_contract(script_hash: HashValue) -> Box<Script> {
     let script = Builder::new()
         .push_opcode(OP_0)
-        .push_slice(&script_hash)
+        .push_slice(script_hash)
         .into_script();
 
     script.into_boxed_script()
 }
 
+/// Generate the witness script of a locker that is unlocked by the secret.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long or the refund timelock
+/// is zero.
+pub fn generate_witness_script(
+    secret: &[u8],
+    algorithm: HashAlgorithm,
+    recipient: RecipientKey,
+    refund: Option<Refund>,
+) -> Result<ScriptBuf, Error> {
+    check_preimage_size(secret)?;
+    check_refund(refund.as_ref())?;
+    let hash_lock = HashLock::new(algorithm, secret);
+
+    Ok(hash_lock_contract(&hash_lock, recipient, refund).into_script_buf())
+}
+
+/// Pay-to-witness-script-hash address of the witness script on the network.
+pub(crate) fn p2wsh_address(witness_script: &Script, network: Network) -> Address {
+    let script_hash = crypto::sha256(witness_script.as_bytes());
+    let script_pub_key = pub_key_contract(script_hash);
+
+    Address::from_script(&script_pub_key, network).unwrap()
+}
+
 /// Generate a pay-to-witness-script-hash address.
-pub fn generate_p2wsh_address(secret: &[u8], recipient: RecipientKey) -> Address {
+///
+/// Passing a refund turns the locker into an HTLC that the depositor can
+/// reclaim once the refund timelock expires.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long or the refund timelock
+/// is zero.
+pub fn generate_p2wsh_address(
+    secret: &[u8],
+    algorithm: HashAlgorithm,
+    recipient: RecipientKey,
+    refund: Option<Refund>,
+) -> Result<Address, Error> {
+    let script = generate_witness_script(secret, algorithm, recipient, refund)?;
     let settings = get_settings();
-    let secret_hash = crypto::sha256(secret);
-    let script = hash_lock_contract(secret_hash, recipient);
 
-    let script_hash = crypto::sha256(&script.as_bytes());
-    let script_pub_key = pub_key_contract(script_hash);
+    Ok(p2wsh_address(&script, settings.network))
+}
+
+/// Generate a pay-to-witness-script-hash address nested in a
+/// pay-to-script-hash one, for wallets that cannot pay to bech32 addresses.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long or the refund timelock
+/// is zero.
+pub fn generate_p2sh_p2wsh_address(
+    secret: &[u8],
+    algorithm: HashAlgorithm,
+    recipient: RecipientKey,
+    refund: Option<Refund>,
+) -> Result<Address, Error> {
+    let script = generate_witness_script(secret, algorithm, recipient, refund)?;
+    let settings = get_settings();
+
+    Ok(AddressType::P2shP2wsh.address(&script, settings.network))
+}
+
+/// Generate the witness script of a 2-of-3 escrow locker.
+///
+/// ### Errors
+/// If two parties have the same key.
+pub fn generate_escrow_witness_script(keys: &EscrowKeys) -> Result<ScriptBuf, Error> {
+    let keys = EscrowKeys::new(keys.buyer, keys.seller, keys.arbiter)?;
+
+    Ok(escrow_contract(&keys).into_script_buf())
+}
+
+/// Generate the pay-to-witness-script-hash address of a 2-of-3 escrow locker.
+///
+/// ### Errors
+/// If two parties have the same key.
+pub fn generate_escrow_p2wsh_address(keys: &EscrowKeys) -> Result<Address, Error> {
+    let script = generate_escrow_witness_script(keys)?;
+    let settings = get_settings();
+
+    Ok(p2wsh_address(&script, settings.network))
+}
+
+/// Create an unsigned transaction that spends the locker output entirely to
+/// the destination. The output value is set once the fee is known.
+pub(super) fn spend_transaction(
+    outpoint: OutPoint,
+    destination: &Address,
+    sequence: Sequence,
+    lock_time: absolute::LockTime,
+) -> Transaction {
+    Transaction {
+        version: Version::TWO,
+        lock_time,
+        input: vec![TxIn {
+            previous_output: outpoint,
+            script_sig: ScriptBuf::new(),
+            sequence,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: destination.script_pubkey(),
+        }],
+    }
+}
+
+/// Deduct the fee for the given witness from the locker value and set it as
+/// the value of the single output.
+pub(super) fn apply_fee(
+    tx: &mut Transaction,
+    value: Amount,
+    fee_rate: FeeRate,
+    witness: Witness,
+) -> Result<(), Error> {
+    tx.input[0].witness = witness;
+    let fee = fee_rate
+        .fee_wu(tx.weight())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Fee rate is too high"))?;
+    tx.input[0].witness = Witness::new();
+
+    let output_value = value
+        .checked_sub(fee)
+        .filter(|amount| *amount >= tx.output[0].script_pubkey.minimal_non_dust())
+        .ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                "Locker value does not cover the fee",
+            )
+        })?;
+    tx.output[0].value = output_value;
+
+    Ok(())
+}
+
+/// Sign the locker input of the transaction following BIP143 and return the
+/// signature with the sighash type appended, ready to be put in the witness.
+pub(super) fn sign_locker_input(
+    tx: &Transaction,
+    witness_script: &Script,
+    value: Amount,
+    wallet: &dyn Signer,
+) -> Result<Vec<u8>, Error> {
+    let sighash = SighashCache::new(tx)
+        .p2wsh_signature_hash(0, witness_script, value, EcdsaSighashType::All)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?;
+    let signature = wallet.sign(sighash.to_byte_array())?;
+
+    let mut signature = signature.to_vec();
+    signature.push(EcdsaSighashType::All.to_u32() as u8);
+
+    Ok(signature)
+}
+
+/// Witness that spends the hash lock branch of the contract.
+pub(super) fn claim_witness(
+    signature: &[u8],
+    public_key: &[u8],
+    secret: &[u8],
+    witness_script: &Script,
+) -> Witness {
+    let mut witness = Witness::new();
+    witness.push(signature);
+    witness.push(public_key);
+    witness.push(secret);
+    if has_refund_branch(witness_script) {
+        // Selects the OP_IF branch
+        witness.push([1u8]);
+    }
+    witness.push(witness_script.as_bytes());
+
+    witness
+}
+
+/// Witness that spends the refund branch of the contract.
+pub(super) fn refund_witness(
+    signature: &[u8],
+    public_key: &[u8],
+    witness_script: &Script,
+) -> Witness {
+    let mut witness = Witness::new();
+    witness.push(signature);
+    witness.push(public_key);
+    // Selects the OP_ELSE branch
+    witness.push([]);
+    witness.push(witness_script.as_bytes());
+
+    witness
+}
+
+/// Returns true if the contract has a refund branch next to the hash lock.
+pub(super) fn has_refund_branch(witness_script: &Script) -> bool {
+    witness_script.as_bytes().first() == Some(&OP_IF.to_u8())
+}
+
+/// Public key hashes checked by the contract with `OP_DUP OP_HASH160`, the
+/// recipient first and the depositor of the refund branch second.
+pub(super) fn contract_key_hashes(witness_script: &Script) -> Vec<RecipientKey> {
+    let instructions: Vec<_> = witness_script.instructions().flatten().collect();
+
+    instructions
+        .windows(3)
+        .filter_map(|window| match window {
+            [Instruction::Op(OP_DUP), Instruction::Op(OP_HASH160), Instruction::PushBytes(data)] => {
+                data.as_bytes().try_into().ok()
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Create the unsigned transaction that claims the locker to the payouts,
+/// with the fee sized for the claim witness.
+pub(super) fn unsigned_claim_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    payouts: &[Payout],
+    fee_rate: FeeRate,
+    secret: &[u8],
+    witness_script: &Script,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    check_preimage_size(secret)?;
+    let mut tx = Transaction {
+        version: Version::TWO,
+        lock_time: absolute::LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: outpoint,
+            script_sig: address_type.script_sig(witness_script),
+            sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+            witness: Witness::new(),
+        }],
+        output: vec![],
+    };
+    let placeholder = claim_witness(
+        &[0u8; MAX_SIGNATURE_SIZE],
+        &[0u8; PUBLIC_KEY_SIZE],
+        secret,
+        witness_script,
+    );
+    payout::apply_payouts(&mut tx, value, fee_rate, placeholder, payouts)?;
+
+    Ok(tx)
+}
+
+/// Create the unsigned transaction that refunds the locker, with the timelock
+/// fields set and the fee sized for the refund witness.
+pub(super) fn unsigned_refund_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    witness_script: &Script,
+    timelock: Timelock,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    let (sequence, lock_time) = timelock.spend_fields();
+    let mut tx = spend_transaction(outpoint, destination, sequence, lock_time);
+    tx.input[0].script_sig = address_type.script_sig(witness_script);
+    let placeholder = refund_witness(
+        &[0u8; MAX_SIGNATURE_SIZE],
+        &[0u8; PUBLIC_KEY_SIZE],
+        witness_script,
+    );
+    apply_fee(&mut tx, value, fee_rate, placeholder)?;
+
+    Ok(tx)
+}
+
+/// Build and sign a transaction that claims a funded locker.
+///
+/// The secret is recovered from the locker mnemonic and revealed in the
+/// witness together with the guardian signature. The whole locker value minus
+/// the fee is sent to the destination address. P2SH lockers also get the
+/// redeem script in the scriptSig.
+///
+/// ### Errors
+/// If the mnemonic is invalid or not of a 32 byte secret, the signature fails
+/// or the locker value does not cover the fee.
+#[allow(clippy::too_many_arguments)]
+pub async fn build_claim_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    mnemonic: Vec<String>,
+    algorithm: HashAlgorithm,
+    guardian: &dyn Signer,
+    refund: Option<Refund>,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    let secret = secret::mnemonic_to_entropy(mnemonic).await?;
+
+    build_secret_claim_transaction(
+        outpoint,
+        value,
+        destination,
+        fee_rate,
+        &secret,
+        algorithm,
+        guardian,
+        refund,
+        address_type,
+    )
+}
+
+/// Build and sign a transaction that claims a funded locker with the raw
+/// secret, e.g. one extracted from the counterparty claim in a swap.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long, the signature fails or
+/// the locker value does not cover the fee.
+#[allow(clippy::too_many_arguments)]
+pub fn build_secret_claim_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    secret: &[u8],
+    algorithm: HashAlgorithm,
+    guardian: &dyn Signer,
+    refund: Option<Refund>,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    build_payout_claim_transaction(
+        outpoint,
+        value,
+        &[Payout::to(destination)],
+        fee_rate,
+        secret,
+        algorithm,
+        guardian,
+        refund,
+        address_type,
+    )
+}
+
+/// Build and sign a transaction that claims a funded locker with the raw
+/// secret and splits its value between the payouts.
+///
+/// ### Errors
+/// If the secret is not [`PREIMAGE_SIZE`] bytes long, the signature fails,
+/// the locker value does not cover the payouts and the fee or a payout would
+/// be dust.
+#[allow(clippy::too_many_arguments)]
+pub fn build_payout_claim_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    payouts: &[Payout],
+    fee_rate: FeeRate,
+    secret: &[u8],
+    algorithm: HashAlgorithm,
+    guardian: &dyn Signer,
+    refund: Option<Refund>,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    let witness_script =
+        generate_witness_script(secret, algorithm, guardian.public_key_commitment(), refund)?;
+
+    let mut tx = unsigned_claim_transaction(
+        outpoint,
+        value,
+        payouts,
+        fee_rate,
+        secret,
+        &witness_script,
+        address_type,
+    )?;
+    let signature = sign_locker_input(&tx, &witness_script, value, guardian)?;
+    tx.input[0].witness = claim_witness(
+        &signature,
+        &guardian.public_key().serialize(),
+        secret,
+        &witness_script,
+    );
+
+    Ok(tx)
+}
+
+/// Build and sign a transaction that refunds an expired locker to the depositor.
+///
+/// The nLockTime or the input nSequence is set from the refund timelock so the
+/// transaction satisfies OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY. It is
+/// only accepted by the network once the timelock has expired.
+///
+/// ### Errors
+/// If the depositor wallet does not match the refund key, the signature fails
+/// or the locker value does not cover the fee.
+#[allow(clippy::too_many_arguments)]
+pub fn build_refund_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    hash_lock: &HashLock,
+    recipient: RecipientKey,
+    refund: Refund,
+    depositor: &dyn Signer,
+    address_type: AddressType,
+) -> Result<Transaction, Error> {
+    if depositor.public_key_commitment() != refund.depositor {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Wallet is not the locker depositor",
+        ));
+    }
+    let witness_script = hash_lock_contract(hash_lock, recipient, Some(refund));
+
+    let mut tx = unsigned_refund_transaction(
+        outpoint,
+        value,
+        destination,
+        fee_rate,
+        &witness_script,
+        refund.timelock,
+        address_type,
+    )?;
+    let signature = sign_locker_input(&tx, &witness_script, value, depositor)?;
+    tx.input[0].witness = refund_witness(
+        &signature,
+        &depositor.public_key().serialize(),
+        &witness_script,
+    );
+
+    Ok(tx)
+}
+
+/// Returns the hash lock committed in the contract: the first hash opcode
+/// followed by a push of the secret hash.
+pub(super) fn committed_hash_lock(witness_script: &Script) -> Option<HashLock> {
+    let instructions = witness_script
+        .instructions()
+        .collect::<Result<Vec<Instruction>, _>>()
+        .ok()?;
+
+    instructions.windows(2).find_map(|pair| match pair {
+        [Instruction::Op(opcode), Instruction::PushBytes(hash)] => Some(HashLock {
+            algorithm: HashAlgorithm::from_opcode(*opcode)?,
+            hash: hash.as_bytes().to_vec(),
+        }),
+        _ => None,
+    })
+}
+
+/// Returns the refund timelock committed in the contract: the lock time
+/// pushed before its OP_CHECKLOCKTIMEVERIFY or OP_CHECKSEQUENCEVERIFY.
+pub(super) fn committed_timelock(witness_script: &Script) -> Option<Timelock> {
+    let instructions = witness_script
+        .instructions()
+        .collect::<Result<Vec<Instruction>, _>>()
+        .ok()?;
+
+    instructions.windows(2).find_map(|pair| {
+        let lock_time = u32::try_from(pair[0].script_num()?).ok()?;
+        match pair[1] {
+            Instruction::Op(OP_CLTV) => Some(Timelock::Absolute(
+                absolute::LockTime::from_consensus(lock_time),
+            )),
+            Instruction::Op(OP_CSV) => relative::LockTime::from_sequence(Sequence(lock_time))
+                .ok()
+                .map(Timelock::Relative),
+            _ => None,
+        }
+    })
+}
+
+/// Extract the secret revealed by a transaction that claims the locker.
+///
+/// The locker input is found by its witness script and must have spent the
+/// hash lock branch, the preimage is checked against the committed hash.
+///
+/// ### Errors
+/// If the transaction does not spend the locker or it spent the refund branch.
+pub fn extract_preimage(tx: &Transaction, witness_script: &Script) -> Result<Vec<u8>, Error> {
+    let hash_lock = committed_hash_lock(witness_script)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Script is not a hash lock"))?;
+    let witness = tx
+        .input
+        .iter()
+        .map(|input| &input.witness)
+        .find(|witness| witness.last() == Some(witness_script.as_bytes()))
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "Transaction does not spend the locker"))?;
+
+    // Witness items below the script: signature, public key, preimage and,
+    // for lockers with a refund, the branch selector
+    let items = witness.iter().collect::<Vec<&[u8]>>();
+    let preimage = if has_refund_branch(witness_script) {
+        match items.as_slice() {
+            [_, _, preimage, [1], _] => *preimage,
+            _ => return Err(Error::new(ErrorKind::NotFound, "Locker was refunded")),
+        }
+    } else {
+        match items.as_slice() {
+            [_, _, preimage, _] => *preimage,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Unexpected claim witness",
+                ))
+            }
+        }
+    };
+    if !hash_lock.is_unlocked_by(preimage) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Preimage does not match the locker hash",
+        ));
+    }
+
+    Ok(preimage.to_vec())
+}
+
+/// Extract the secret revealed by a locker claim and rebuild the locker
+/// mnemonic from it.
+///
+/// ### Errors
+/// If no valid preimage is found or it is not a valid mnemonic entropy.
+pub async fn extract_mnemonic(
+    tx: &Transaction,
+    witness_script: &Script,
+) -> Result<Vec<String>, Error> {
+    let preimage = extract_preimage(tx, witness_script)?;
+
+    secret::generate_secret(&preimage).await
+}
+
+/// Witness that spends the escrow contract. OP_CHECKMULTISIG pops one extra
+/// element and expects the signatures in the order of the keys.
+fn escrow_witness(signatures: &[Vec<u8>], witness_script: &Script) -> Witness {
+    let mut witness = Witness::new();
+    witness.push([]);
+    for signature in signatures {
+        witness.push(signature);
+    }
+    witness.push(witness_script.as_bytes());
+
+    witness
+}
+
+/// Build and sign a transaction that releases an escrow locker with the
+/// signatures of any two of its parties.
+///
+/// ### Errors
+/// If two parties have the same key, the signers are not two distinct escrow
+/// parties, a signature fails or the locker value does not cover the fee.
+pub fn build_escrow_transaction(
+    outpoint: OutPoint,
+    value: Amount,
+    destination: &Address,
+    fee_rate: FeeRate,
+    keys: &EscrowKeys,
+    signers: [&dyn Signer; 2],
+) -> Result<Transaction, Error> {
+    keys.check_distinct()?;
+    let ordered = keys.ordered();
+    let mut positions = signers
+        .iter()
+        .map(|signer| {
+            ordered
+                .iter()
+                .position(|key| *key == signer.public_key())
+                .map(|position| (position, *signer))
+        })
+        .collect::<Option<Vec<(usize, &dyn Signer)>>>()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Signer is not an escrow party"))?;
+    if positions[0].0 == positions[1].0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "Escrow needs two distinct signers",
+        ));
+    }
+    positions.sort_by_key(|(position, _)| *position);
+    let witness_script = escrow_contract(keys);
+
+    let mut tx = spend_transaction(
+        outpoint,
+        destination,
+        Sequence::ENABLE_RBF_NO_LOCKTIME,
+        absolute::LockTime::ZERO,
+    );
+    let placeholder = escrow_witness(
+        &[vec![0u8; MAX_SIGNATURE_SIZE], vec![0u8; MAX_SIGNATURE_SIZE]],
+        &witness_script,
+    );
+    apply_fee(&mut tx, value, fee_rate, placeholder)?;
+
+    let signatures = positions
+        .iter()
+        .map(|(_, signer)| sign_locker_input(&tx, &witness_script, value, *signer))
+        .collect::<Result<Vec<Vec<u8>>, Error>>()?;
+    tx.input[0].witness = escrow_witness(&signatures, &witness_script);
+
+    Ok(tx)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::script::Instruction;
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, fees, payout::PayoutShare};
+
+    fn hash_lock() -> HashLock {
+        HashLock::new(HashAlgorithm::Sha256, &[1u8; 32])
+    }
+
+    #[test]
+    fn test_hash_lock_contract_without_refund() {
+        let hash_lock = HashLock::new(HashAlgorithm::Hash256, &[1u8; 32]);
+        let script = hash_lock_contract(&hash_lock, [2u8; 20], None);
+        let ops = script
+            .instructions()
+            .map(|i| i.unwrap())
+            .collect::<Vec<Instruction>>();
+        assert_eq!(ops[0], Instruction::Op(OP_SIZE));
+        assert_eq!(ops[1].script_num(), Some(PREIMAGE_SIZE as i64));
+        assert_eq!(ops[3], Instruction::Op(OP_HASH256));
+        assert!(!script.as_bytes().contains(&OP_CLTV.to_u8()));
+        assert_eq!(committed_timelock(&script), None);
+    }
+
+    #[test]
+    fn test_hash_lock_contract_with_refund() {
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Absolute(absolute::LockTime::from_height(800_000).unwrap()),
+        };
+        let script = hash_lock_contract(&hash_lock(), [2u8; 20], Some(refund));
+        let ops = script
+            .instructions()
+            .map(|i| i.unwrap())
+            .collect::<Vec<Instruction>>();
+
+        assert_eq!(ops[0], Instruction::Op(OP_IF));
+        assert!(ops.contains(&Instruction::Op(OP_ELSE)));
+        assert!(ops.contains(&Instruction::Op(OP_CLTV)));
+        assert_eq!(ops[ops.len() - 1], Instruction::Op(OP_CHECKSIG));
+        assert_eq!(script.instructions_minimal().count(), ops.len());
+        assert_eq!(committed_timelock(&script), Some(refund.timelock));
+    }
+
+    #[test]
+    fn test_hash_lock_contract_with_relative_refund() {
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Relative(relative::LockTime::from_height(144)),
+        };
+        let script = hash_lock_contract(&hash_lock(), [2u8; 20], Some(refund));
+        let ops = script
+            .instructions()
+            .map(|i| i.unwrap())
+            .collect::<Vec<Instruction>>();
+
+        let csv = ops
+            .iter()
+            .position(|op| *op == Instruction::Op(OP_CSV))
+            .unwrap();
+        assert_eq!(ops[csv - 1].script_num(), Some(144));
+        assert!(!ops.contains(&Instruction::Op(OP_CLTV)));
+        assert_eq!(committed_timelock(&script), Some(refund.timelock));
+    }
+
+    async fn claim_fixture(refund: Option<Refund>) -> (Transaction, GuardianWallet, Vec<u8>) {
+        let entropy = secret::token_bytes::<32>();
+        let mnemonic = secret::generate_secret(&entropy).await.unwrap();
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        let tx = build_claim_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            mnemonic,
+            HashAlgorithm::Sha256,
+            &guardian,
+            refund,
+            AddressType::P2wsh,
+        )
+        .await
+        .unwrap();
+
+        (tx, guardian, entropy.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_build_claim_transaction() {
+        let (tx, guardian, secret) = claim_fixture(None).await;
+        let witness = &tx.input[0].witness;
+
+        assert_eq!(witness.len(), 4);
+        assert_eq!(witness.nth(1).unwrap(), guardian.public_key().serialize());
+        assert_eq!(witness.nth(2).unwrap(), secret.as_slice());
+        assert!(tx.output[0].value < Amount::from_sat(100_000));
+        assert!(tx.output[0].value > Amount::from_sat(99_000));
+
+        let witness_script = Script::from_bytes(witness.last().unwrap());
+        let sighash = SighashCache::new(&tx)
+            .p2wsh_signature_hash(
+                0,
+                witness_script,
+                Amount::from_sat(100_000),
+                EcdsaSighashType::All,
+            )
+            .unwrap();
+        let signature = witness.nth(0).unwrap();
+        let signature =
+            secp256k1::ecdsa::Signature::from_der(&signature[..signature.len() - 1]).unwrap();
+        let message = secp256k1::Message::from_digest(sighash.to_byte_array());
+        assert!(secp256k1::Secp256k1::new()
+            .verify_ecdsa(&message, &signature, &guardian.public_key())
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_build_claim_transaction_with_refund() {
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Relative(relative::LockTime::from_height(10)),
+        };
+        let (tx, _, _) = claim_fixture(Some(refund)).await;
+
+        assert_eq!(tx.input[0].witness.len(), 5);
+        assert_eq!(tx.input[0].witness.nth(3).unwrap(), [1u8]);
+    }
+
+    #[tokio::test]
+    async fn test_build_claim_transaction_below_fee() {
+        let entropy = secret::token_bytes::<32>();
+        let mnemonic = secret::generate_secret(&entropy).await.unwrap();
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        let tx = build_claim_transaction(
+            OutPoint::null(),
+            Amount::from_sat(500),
+            &destination,
+            FeeRate::from_sat_per_vb(10).unwrap(),
+            mnemonic,
+            HashAlgorithm::Sha256,
+            &guardian,
+            None,
+            AddressType::P2wsh,
+        )
+        .await;
+        assert!(tx.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_claim_transaction_secret_size() {
+        // 12 word mnemonics hold 16 bytes, the script only takes 32 bytes
+        let entropy = secret::token_bytes::<16>();
+        let mnemonic = secret::generate_secret(&entropy).await.unwrap();
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        let script = generate_witness_script(&entropy, HashAlgorithm::Sha256, [2u8; 20], None);
+        assert_eq!(script.unwrap_err().kind(), ErrorKind::InvalidInput);
+        // A zero refund timelock would fail the OP_VERIFY of the refund path
+        for timelock in [
+            Timelock::Absolute(absolute::LockTime::ZERO),
+            Timelock::Relative(relative::LockTime::ZERO),
+        ] {
+            let refund = Refund {
+                depositor: [3u8; 20],
+                timelock,
+            };
+            let script =
+                generate_witness_script(&[9u8; 32], HashAlgorithm::Sha256, [2u8; 20], Some(refund));
+            assert_eq!(script.unwrap_err().kind(), ErrorKind::InvalidInput);
+        }
+        let tx = build_claim_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            mnemonic,
+            HashAlgorithm::Sha256,
+            &guardian,
+            None,
+            AddressType::P2wsh,
+        )
+        .await;
+        assert_eq!(tx.unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn test_build_payout_claim_transaction() {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let value = Amount::from_sat(100_000);
+        let payout = |seed: u8, share| Payout {
+            address: generate_p2wsh_address(&[seed; 32], HashAlgorithm::Sha256, [8u8; 20], None)
+                .unwrap()
+                .as_unchecked()
+                .clone(),
+            share,
+        };
+        let payouts = [
+            payout(1, PayoutShare::Fixed(20_000)),
+            payout(2, PayoutShare::BasisPoints(7_000)),
+            payout(3, PayoutShare::BasisPoints(3_000)),
+        ];
+        let claim = |payouts: &[Payout]| {
+            build_payout_claim_transaction(
+                OutPoint::null(),
+                value,
+                payouts,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &[7u8; 32],
+                HashAlgorithm::Sha256,
+                &guardian,
+                None,
+                AddressType::P2wsh,
+            )
+        };
+
+        let tx = claim(&payouts).unwrap();
+        assert_eq!(tx.output.len(), 3);
+        assert_eq!(tx.output[0].value, Amount::from_sat(20_000));
+        assert_eq!(
+            tx.output[1].script_pubkey,
+            payouts[1].address.assume_checked_ref().script_pubkey()
+        );
+        // The fee is sized for the largest signature, so never below the rate
+        let fee = fees::transaction_fee(&tx, value).unwrap();
+        let fee_rate = FeeRate::from_sat_per_vb(2).unwrap();
+        assert!(fee >= fee_rate.fee_wu(tx.weight()).unwrap());
+
+        // A payout too small to relay fails the claim
+        let dust = [
+            payout(1, PayoutShare::BasisPoints(9_990)),
+            payout(2, PayoutShare::BasisPoints(10)),
+        ];
+        assert!(claim(&dust).is_err());
+    }
+
+    async fn refund_fixture(timelock: Timelock) -> Result<Transaction, Error> {
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let refund = Refund {
+            depositor: depositor.public_key_commitment(),
+            timelock,
+        };
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        build_refund_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &hash_lock(),
+            [2u8; 20],
+            refund,
+            &depositor,
+            AddressType::P2wsh,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_build_refund_transaction_absolute() {
+        let lock_time = absolute::LockTime::from_height(850_000).unwrap();
+        let tx = refund_fixture(Timelock::Absolute(lock_time)).await.unwrap();
+
+        assert_eq!(tx.lock_time, lock_time);
+        assert!(tx.input[0].sequence.enables_absolute_lock_time());
+        assert_eq!(tx.input[0].witness.len(), 4);
+        assert!(tx.input[0].witness.nth(2).unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_build_refund_transaction_relative() {
+        let lock_time = relative::LockTime::from_height(144);
+        let tx = refund_fixture(Timelock::Relative(lock_time)).await.unwrap();
+
+        assert_eq!(tx.lock_time, absolute::LockTime::ZERO);
+        assert_eq!(tx.version, Version::TWO);
+        assert_eq!(
+            tx.input[0].sequence.to_relative_lock_time(),
+            Some(lock_time)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_build_refund_transaction_wrong_depositor() {
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Relative(relative::LockTime::from_height(1)),
+        };
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        let tx = build_refund_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &hash_lock(),
+            [2u8; 20],
+            refund,
+            &depositor,
+            AddressType::P2wsh,
+        );
+        assert!(tx.is_err());
+    }
+
+    async fn escrow_fixture() -> (EscrowKeys, [GuardianWallet; 3]) {
+        let buyer = GuardianWallet::generate_new().await.unwrap();
+        let seller = GuardianWallet::generate_new().await.unwrap();
+        let arbiter = GuardianWallet::generate_new().await.unwrap();
+        let keys = EscrowKeys::new(
+            buyer.public_key(),
+            seller.public_key(),
+            arbiter.public_key(),
+        )
+        .unwrap();
+
+        (keys, [buyer, seller, arbiter])
+    }
+
+    #[tokio::test]
+    async fn test_escrow_contract() {
+        let (keys, _) = escrow_fixture().await;
+        let script = generate_escrow_witness_script(&keys).unwrap();
+        assert!(script.is_multisig());
+        assert_eq!(script.as_bytes()[0], OP_PUSHNUM_2.to_u8());
+        assert!(generate_escrow_p2wsh_address(&keys)
+            .unwrap()
+            .script_pubkey()
+            .is_p2wsh());
+
+        // One party holding two keys could release the funds alone
+        let error = EscrowKeys::new(keys.buyer, keys.seller, keys.buyer).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        let duplicated = EscrowKeys {
+            arbiter: keys.buyer,
+            ..keys
+        };
+        assert!(generate_escrow_witness_script(&duplicated).is_err());
+        assert!(generate_escrow_p2wsh_address(&duplicated).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_build_escrow_transaction_pairs() {
+        let (keys, [buyer, seller, arbiter]) = escrow_fixture().await;
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+        let witness_script = generate_escrow_witness_script(&keys).unwrap();
+        let secp = secp256k1::Secp256k1::new();
+
+        // Signers are given out of order on purpose
+        for (signers, expected) in [
+            ([&seller, &buyer], [&buyer, &seller]),
+            ([&arbiter, &buyer], [&buyer, &arbiter]),
+            ([&seller, &arbiter], [&seller, &arbiter]),
+        ] {
+            let tx = build_escrow_transaction(
+                OutPoint::null(),
+                Amount::from_sat(100_000),
+                &destination,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &keys,
+                signers.map(|signer| signer as &dyn Signer),
+            )
+            .unwrap();
+            let witness = &tx.input[0].witness;
+            assert_eq!(witness.len(), 4);
+            assert!(witness.nth(0).unwrap().is_empty());
+
+            let sighash = SighashCache::new(&tx)
+                .p2wsh_signature_hash(
+                    0,
+                    &witness_script,
+                    Amount::from_sat(100_000),
+                    EcdsaSighashType::All,
+                )
+                .unwrap();
+            let message = secp256k1::Message::from_digest(sighash.to_byte_array());
+            for (index, signer) in expected.iter().enumerate() {
+                let signature = witness.nth(index + 1).unwrap();
+                let signature =
+                    secp256k1::ecdsa::Signature::from_der(&signature[..signature.len() - 1])
+                        .unwrap();
+                assert!(secp
+                    .verify_ecdsa(&message, &signature, &signer.public_key())
+                    .is_ok());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_build_escrow_transaction_invalid_signers() {
+        let (keys, [buyer, _, _]) = escrow_fixture().await;
+        let outsider = GuardianWallet::generate_new().await.unwrap();
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        for signers in [[&buyer, &buyer], [&buyer, &outsider]] {
+            let tx = build_escrow_transaction(
+                OutPoint::null(),
+                Amount::from_sat(100_000),
+                &destination,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                &keys,
+                signers.map(|signer| signer as &dyn Signer),
+            );
+            assert!(tx.is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_preimage() {
+        let entropy = secret::token_bytes::<32>();
+        let mnemonic = secret::generate_secret(&entropy).await.unwrap();
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+
+        for refund in [
+            None,
+            Some(Refund {
+                depositor: [3u8; 20],
+                timelock: Timelock::Relative(relative::LockTime::from_height(10)),
+            }),
+        ] {
+            let tx = build_claim_transaction(
+                OutPoint::null(),
+                Amount::from_sat(100_000),
+                &destination,
+                FeeRate::from_sat_per_vb(2).unwrap(),
+                mnemonic.clone(),
+                HashAlgorithm::Sha256,
+                &guardian,
+                refund,
+                AddressType::P2wsh,
+            )
+            .await
+            .unwrap();
+            let witness_script = generate_witness_script(
+                &entropy,
+                HashAlgorithm::Sha256,
+                guardian.public_key_commitment(),
+                refund,
+            )
+            .unwrap();
+
+            assert_eq!(
+                extract_preimage(&tx, &witness_script).unwrap(),
+                entropy.to_vec()
+            );
+            assert_eq!(
+                extract_mnemonic(&tx, &witness_script).await.unwrap(),
+                mnemonic
+            );
+
+            let other_script =
+                generate_witness_script(&[1u8; 32], HashAlgorithm::Sha256, [2u8; 20], refund)
+                    .unwrap();
+            assert!(extract_preimage(&tx, &other_script).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extract_preimage_from_refund() {
+        let depositor = GuardianWallet::generate_new().await.unwrap();
+        let refund = Refund {
+            depositor: depositor.public_key_commitment(),
+            timelock: Timelock::Relative(relative::LockTime::from_height(10)),
+        };
+        let destination =
+            generate_p2wsh_address(&[9u8; 32], HashAlgorithm::Sha256, [8u8; 20], None).unwrap();
+        let tx = build_refund_transaction(
+            OutPoint::null(),
+            Amount::from_sat(100_000),
+            &destination,
+            FeeRate::from_sat_per_vb(2).unwrap(),
+            &hash_lock(),
+            [2u8; 20],
+            refund,
+            &depositor,
+            AddressType::P2wsh,
+        )
+        .unwrap();
+        let witness_script =
+            generate_witness_script(&[1u8; 32], HashAlgorithm::Sha256, [2u8; 20], Some(refund))
+                .unwrap();
+
+        let preimage = extract_preimage(&tx, &witness_script);
+        assert_eq!(preimage.unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_refund_changes_address() {
+        let refund = Refund {
+            depositor: [3u8; 20],
+            timelock: Timelock::Absolute(absolute::LockTime::from_consensus(1_700_000_000)),
+        };
+        let plain =
+            generate_p2wsh_address(&[5u8; 32], HashAlgorithm::Sha256, [2u8; 20], None).unwrap();
+        let htlc =
+            generate_p2wsh_address(&[5u8; 32], HashAlgorithm::Sha256, [2u8; 20], Some(refund))
+                .unwrap();
+        assert_ne!(plain, htlc);
+        assert!(htlc.script_pubkey().is_p2wsh());
+    }
+
+    #[test]
+    fn test_p2sh_p2wsh_address() {
+        let witness_script =
+            generate_witness_script(&[5u8; 32], HashAlgorithm::Sha256, [2u8; 20], None).unwrap();
+        let nested =
+            generate_p2sh_p2wsh_address(&[5u8; 32], HashAlgorithm::Sha256, [2u8; 20], None)
+                .unwrap();
+        assert!(nested.script_pubkey().is_p2sh());
+        assert_eq!(
+            nested,
+            AddressType::P2shP2wsh.address(&witness_script, get_settings().network)
+        );
 
-    Address::from_script(&script_pub_key, settings.network).unwrap()
+        // The scriptSig reveals the P2WSH program committed in the address
+        let script_sig = AddressType::P2shP2wsh.script_sig(&witness_script);
+        let redeem_script = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+        assert_eq!(&script_sig.as_bytes()[1..], redeem_script.as_bytes());
+        assert_eq!(
+            nested.script_pubkey(),
+            ScriptBuf::new_p2sh(&redeem_script.script_hash())
+        );
+        assert!(AddressType::P2wsh.script_sig(&witness_script).is_empty());
+    }
 }
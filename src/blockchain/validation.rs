@@ -0,0 +1,105 @@
+use std::{
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+
+use bitcoin::{Address, Amount, Network, Script};
+
+/// Check a locker amount against the dust threshold of its output script and
+/// the configured limits.
+///
+/// ### Errors
+/// If the amount is dust for the script or outside of `min..=max`.
+pub fn validate_amount(
+    amount: Amount,
+    script_pubkey: &Script,
+    min: Amount,
+    max: Amount,
+) -> Result<(), Error> {
+    let dust_threshold = script_pubkey.minimal_non_dust();
+    if amount < dust_threshold {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Amount of {} sat is below the dust threshold of {} sat",
+                amount.to_sat(),
+                dust_threshold.to_sat()
+            ),
+        ));
+    }
+    if amount < min || amount > max {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "Amount of {} sat is outside of the {} to {} sat limits",
+                amount.to_sat(),
+                min.to_sat(),
+                max.to_sat()
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Parse a user supplied address, which must be for the given network.
+///
+/// ### Errors
+/// If the address is invalid or for another network.
+pub fn parse_address(address: &str, network: Network) -> Result<Address, Error> {
+    Address::from_str(address)
+        .and_then(|address| address.require_network(network))
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{
+        crypto::HashAlgorithm,
+        transactions::{self, AddressType},
+    };
+
+    #[test]
+    fn test_validate_amount() {
+        let witness_script = transactions::generate_witness_script(
+            &[1u8; 32],
+            HashAlgorithm::Sha256,
+            [2u8; 20],
+            None,
+        )
+        .unwrap();
+        let p2wsh = AddressType::P2wsh
+            .address(&witness_script, Network::Regtest)
+            .script_pubkey();
+        let p2sh = AddressType::P2shP2wsh
+            .address(&witness_script, Network::Regtest)
+            .script_pubkey();
+        let (min, max) = (Amount::ZERO, Amount::from_sat(1_000_000));
+
+        // Nested outputs are larger, so their dust threshold is higher
+        assert!(validate_amount(Amount::from_sat(400), &p2wsh, min, max).is_ok());
+        assert!(validate_amount(Amount::from_sat(400), &p2sh, min, max).is_err());
+        assert!(validate_amount(Amount::from_sat(329), &p2wsh, min, max).is_err());
+
+        let min = Amount::from_sat(10_000);
+        assert!(validate_amount(Amount::from_sat(9_999), &p2wsh, min, max).is_err());
+        assert!(validate_amount(Amount::from_sat(10_000), &p2wsh, min, max).is_ok());
+        assert!(validate_amount(max, &p2wsh, min, max).is_ok());
+        let error = validate_amount(max + Amount::ONE_SAT, &p2wsh, min, max).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_parse_address() {
+        let testnet = "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx";
+        assert!(parse_address(testnet, Network::Testnet).is_ok());
+        assert!(parse_address(testnet, Network::Bitcoin).is_err());
+        assert!(parse_address(testnet, Network::Regtest).is_err());
+
+        let mainnet = "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4";
+        assert!(parse_address(mainnet, Network::Bitcoin).is_ok());
+        assert!(parse_address(mainnet, Network::Testnet).is_err());
+        assert!(parse_address("not an address", Network::Bitcoin).is_err());
+    }
+}
@@ -2,6 +2,8 @@ use bitcoin::Network;
 use std::{env, sync::{Mutex, MutexGuard}};
 use once_cell::sync::Lazy;
 
+use crate::blockchain::address;
+
 pub struct Settings {
 
     // Default settings
@@ -10,9 +12,27 @@ pub struct Settings {
     // Network settings
     pub network: Network,
     pub rpc_hostname: String,
+    pub rpc_port: String,
     pub rpc_username: String,
     pub rpc_password: String,
 
+    // Locker settings, amounts in satoshis
+    pub min_locker_amount: u64,
+    pub max_locker_amount: u64,
+
+    // Guardian settings, BIP32 account path and BIP39 passphrase of the
+    // guardian keys
+    pub guardian_account_path: String,
+    pub guardian_passphrase: Option<String>,
+
+    // Keystore settings, guardian keys are only persisted when a path is set
+    pub keystore_path: Option<String>,
+    pub keystore_passphrase: Option<String>,
+
+    // Signer settings, guardian keys are held by the external signer
+    // listening on the socket when it is set
+    pub signer_socket: Option<String>,
+
     // Cache settings
     pub url: String,
     pub port: String,
@@ -24,23 +44,43 @@ impl Settings {
         let env = env::var("ENVIRONMENT").unwrap_or_else(|_| "development".into());
         let network = env::var("NETWORK").unwrap_or_else(|_| "regtest".into());
         let rpc_hostname = env::var("RPC_HOSTNAME").unwrap_or_else(|_| "localhost".into());
+        let rpc_port = env::var("RPC_PORT").unwrap_or_else(|_| "18443".into());
         let rpc_username = env::var("RPC_USERNAME").unwrap_or_else(|_| "user".into());
         let rpc_password = env::var("RPC_PASSWORD").unwrap_or_else(|_| "password".into());
+        let min_locker_amount = env::var("MIN_LOCKER_AMOUNT").unwrap_or_else(|_| "1000".into()).parse().unwrap();
+        let max_locker_amount = env::var("MAX_LOCKER_AMOUNT").unwrap_or_else(|_| "100000000".into()).parse().unwrap();
+        let guardian_account_path = env::var("GUARDIAN_ACCOUNT_PATH").ok();
+        let guardian_passphrase = env::var("GUARDIAN_PASSPHRASE").ok();
+        let keystore_path = env::var("KEYSTORE_PATH").ok();
+        let keystore_passphrase = env::var("KEYSTORE_PASSPHRASE").ok();
+        let signer_socket = env::var("SIGNER_SOCKET").ok();
         let url = env::var("REDIS_URL").unwrap_or_else(|_| "localhost".into());
         let port = env::var("REDIS_PORT").unwrap_or_else(|_| "6379".into());
         let ttl = env::var("REDIS_TTL").unwrap_or_else(|_| "60".into()).parse().unwrap();
 
+        let network = match network.as_str() {
+            "mainnet" => Network::Bitcoin,
+            "testnet" => Network::Testnet,
+            "regtest" => Network::Regtest,
+            _ => Network::Regtest,
+        };
+        let guardian_account_path = guardian_account_path
+            .unwrap_or_else(|| format!("m/{}", address::default_account_path(network)));
+
         return Settings {
             environment: env,
-            network: match network.as_str() {
-                "mainnet" => Network::Bitcoin,
-                "testnet" => Network::Testnet,
-                "regtest" => Network::Regtest,
-                _ => Network::Regtest,
-            },
+            network,
             rpc_hostname,
+            rpc_port,
             rpc_username,
             rpc_password,
+            min_locker_amount,
+            max_locker_amount,
+            guardian_account_path,
+            guardian_passphrase,
+            keystore_path,
+            keystore_passphrase,
+            signer_socket,
             url,
             port,
             ttl,
@@ -19,6 +19,12 @@ impl CacheClient {
         con.set_ex(key, value, self.ttl).await
     }
 
+    /// Set the value without the cache TTL, for records that must outlive it.
+    pub async fn persist(&self, key: &str, value: &str) -> RedisResult<()> {
+        let mut con = self.client.get_multiplexed_async_connection().await?;
+        con.set(key, value).await
+    }
+
     pub async fn get(&self, key: &str) -> RedisResult<Vec<u8>> {
         let mut con = self.client.get_multiplexed_async_connection().await?;
         con.get(key).await
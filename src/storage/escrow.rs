@@ -0,0 +1,156 @@
+use std::io::{Error, ErrorKind};
+
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{
+    encoding::string, milestone::MilestoneEscrow, secret, signer::Signer, transactions,
+};
+
+use super::cache::CacheClient;
+
+/// Version of the escrow record written by this build.
+pub const ESCROW_VERSION: u32 = 1;
+
+/// A milestone escrow with the guardian key that signs its releases, stored
+/// so the progress of the escrow survives between requests.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Escrow {
+    /// Version of the record layout, see [`ESCROW_VERSION`]
+    pub version: u32,
+    pub escrow_id: String,
+    #[serde(with = "string")]
+    pub guardian_public_key: PublicKey,
+    /// BIP32 origin of the guardian key, `[fingerprint/path]`, or `None` when
+    /// the key is held by an external signer
+    pub guardian_key_origin: Option<String>,
+    #[serde(flatten)]
+    pub escrow: MilestoneEscrow,
+}
+
+impl Escrow {
+    pub fn new(
+        escrow: MilestoneEscrow,
+        guardian: &dyn Signer,
+        guardian_key_origin: Option<String>,
+    ) -> Self {
+        // Milestone addresses are unique to the master secret of the escrow
+        let first_address = escrow
+            .milestones
+            .first()
+            .map(|milestone| milestone.address.to_string())
+            .unwrap_or_default();
+
+        Self {
+            version: ESCROW_VERSION,
+            escrow_id: secret::hash_id(first_address),
+            guardian_public_key: guardian.public_key(),
+            guardian_key_origin,
+            escrow,
+        }
+    }
+
+    /// Decode a stored escrow record.
+    ///
+    /// ### Errors
+    /// If the record is malformed, was written with an unknown version or a
+    /// milestone address does not match its script on the escrow network.
+    pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+        let escrow = serde_json::from_slice::<Escrow>(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if escrow.version != ESCROW_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported escrow version {}", escrow.version),
+            ));
+        }
+        let network = escrow.escrow.network;
+        if escrow.escrow.milestones.iter().any(|milestone| {
+            milestone.address != transactions::p2wsh_address(&milestone.witness_script, network)
+        }) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Milestone address does not match its script",
+            ));
+        }
+
+        Ok(escrow)
+    }
+}
+
+fn escrow_key(escrow_id: &str) -> String {
+    format!("escrow:{}", escrow_id)
+}
+
+/// Store the escrow without expiry, its milestones can be released at any
+/// time later.
+///
+/// ### Errors
+/// If the record cannot be written to the cache.
+pub async fn save_escrow(cache: &CacheClient, escrow: &Escrow) -> Result<(), Error> {
+    let data = serde_json::to_string(escrow).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    cache
+        .persist(&escrow_key(&escrow.escrow_id), &data)
+        .await
+        .map_err(Error::other)
+}
+
+/// Load a stored escrow, or `None` if there is no escrow with the id.
+///
+/// ### Errors
+/// If the cache cannot be read or the record cannot be decoded.
+pub async fn load_escrow(cache: &CacheClient, escrow_id: &str) -> Result<Option<Escrow>, Error> {
+    let data = cache
+        .get(&escrow_key(escrow_id))
+        .await
+        .map_err(Error::other)?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    Escrow::from_slice(&data).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::{Amount, Network};
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, crypto::HashAlgorithm};
+
+    async fn escrow() -> Escrow {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let master = secret::generate_secret(&[3u8; 32]).await.unwrap();
+        let escrow = MilestoneEscrow::new(
+            master,
+            HashAlgorithm::Sha256,
+            guardian.public_key_commitment(),
+            None,
+            &[Amount::from_sat(40_000), Amount::from_sat(60_000)],
+            Network::Regtest,
+        )
+        .await
+        .unwrap();
+
+        let key_origin = guardian.key_origin();
+        Escrow::new(escrow, &guardian, Some(key_origin))
+    }
+
+    #[tokio::test]
+    async fn test_escrow_round_trip() {
+        let escrow = escrow().await;
+        let data = serde_json::to_vec(&escrow).unwrap();
+        assert_eq!(Escrow::from_slice(&data).unwrap(), escrow);
+
+        let mut record = serde_json::to_value(&escrow).unwrap();
+        record["version"] = (ESCROW_VERSION + 1).into();
+        let error = Escrow::from_slice(&serde_json::to_vec(&record).unwrap()).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // An address of another network is not taken for the milestone one
+        let mut record = serde_json::to_value(&escrow).unwrap();
+        record["network"] = "bitcoin".into();
+        assert!(Escrow::from_slice(&serde_json::to_vec(&record).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,694 @@
+use std::{
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use bitcoin::{
+    bip32::{ChildNumber, Xpriv},
+    hex::{DisplayHex, FromHex},
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use tokio::{fs, io::AsyncWriteExt, sync::Mutex, task::spawn_blocking};
+
+use crate::{
+    blockchain::{
+        address::{self, GuardianWallet},
+        secret,
+        signer::Signer,
+    },
+    settings::get_settings,
+};
+
+/// Version of the key files written by this build.
+pub const KEYSTORE_VERSION: u32 = 1;
+
+/// Extension of the key files in the keystore directory.
+const KEY_FILE_EXTENSION: &str = "json";
+
+/// File holding the key id of the guardian master key, the one new lockers
+/// derive their keys from.
+const GUARDIAN_FILE: &str = "guardian";
+
+/// Serialises the reservation of guardian key indexes, so two lockers never
+/// get the same key.
+static GUARDIAN_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Argon2id parameters used to derive the encryption key of a key file. They
+/// are stored with the file, so they can be raised without breaking older
+/// files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct KdfParams {
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Guardian key file, the secret encrypted with ChaCha20-Poly1305 under a key
+/// derived from the keystore passphrase. The key id is authenticated with the
+/// secret, so a file cannot be swapped for another key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    key_id: String,
+    /// First receive index not handed out to a locker yet
+    #[serde(default)]
+    next_index: u32,
+    kdf: KdfParams,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// Secret stored in a key file.
+#[derive(Serialize, Deserialize)]
+struct KeySecret {
+    master_key: String,
+    account_path: String,
+}
+
+/// Derive the file encryption key. Argon2id is slow by design, it runs on
+/// the blocking pool so it does not stall the async workers.
+async fn derive_key(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<Key, Error> {
+    let passphrase = passphrase.to_string();
+    let salt = salt.to_vec();
+
+    spawn_blocking(move || derive_key_blocking(&passphrase, &salt, kdf))
+        .await
+        .map_err(Error::other)
+        .and_then(|key| key)
+}
+
+fn derive_key_blocking(passphrase: &str, salt: &[u8], kdf: KdfParams) -> Result<Key, Error> {
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+
+    Ok(key)
+}
+
+fn invalid_key_file(e: impl ToString) -> Error {
+    Error::new(ErrorKind::InvalidData, e.to_string())
+}
+
+/// Write the file aside, to be renamed over `path` once complete. Returns the
+/// path of the staged file.
+async fn stage_file(path: &Path, data: &[u8]) -> Result<PathBuf, Error> {
+    let tmp_path = path.with_extension("tmp");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&tmp_path).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+
+    Ok(tmp_path)
+}
+
+/// Write the file aside and rename it, so it is never left half written.
+async fn write_file(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp_path = stage_file(path, data).await?;
+
+    fs::rename(&tmp_path, path).await
+}
+
+impl KeyFile {
+    async fn seal(
+        wallet: &GuardianWallet,
+        passphrase: &str,
+        next_index: u32,
+    ) -> Result<Self, Error> {
+        let key_id = wallet.fingerprint().to_string();
+        let (master_key, account_path) = wallet.master_key();
+        let secret = serde_json::to_vec(&KeySecret {
+            master_key: master_key.to_string(),
+            account_path: format!("m/{}", account_path),
+        })
+        .map_err(invalid_key_file)?;
+
+        let kdf = KdfParams::default();
+        let salt = secret::token_bytes::<16>();
+        let nonce = secret::token_bytes::<12>();
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, kdf).await?);
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &secret,
+                    aad: key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::other("Key encryption failed"))?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            key_id,
+            next_index,
+            kdf,
+            salt: salt.to_lower_hex_string(),
+            nonce: nonce.to_lower_hex_string(),
+            ciphertext: ciphertext.to_lower_hex_string(),
+        })
+    }
+
+    async fn open(&self, passphrase: &str) -> Result<GuardianWallet, Error> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(invalid_key_file(format!(
+                "Unsupported key file version {}",
+                self.version
+            )));
+        }
+        let salt = Vec::<u8>::from_hex(&self.salt).map_err(invalid_key_file)?;
+        let nonce = <[u8; 12]>::from_hex(&self.nonce).map_err(invalid_key_file)?;
+        let ciphertext = Vec::<u8>::from_hex(&self.ciphertext).map_err(invalid_key_file)?;
+
+        let cipher = ChaCha20Poly1305::new(&derive_key(passphrase, &salt, self.kdf).await?);
+        let secret = cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &ciphertext,
+                    aad: self.key_id.as_bytes(),
+                },
+            )
+            .map_err(|_| {
+                Error::new(
+                    ErrorKind::PermissionDenied,
+                    "Wrong keystore passphrase or corrupted key file",
+                )
+            })?;
+        let secret = serde_json::from_slice::<KeySecret>(&secret).map_err(invalid_key_file)?;
+
+        let master_key = Xpriv::from_str(&secret.master_key).map_err(invalid_key_file)?;
+        let account_path = address::parse_derivation_path(&secret.account_path)?;
+        GuardianWallet::from_master_key(master_key, account_path)
+    }
+}
+
+/// Directory of guardian key files encrypted with the keystore passphrase.
+///
+/// Keys are identified by the fingerprint of their master key, the same one
+/// recorded in the key origin of the lockers they guard. One of them is the
+/// guardian master, every new locker gets the key at the next unused index
+/// of its account.
+pub struct Keystore {
+    dir: PathBuf,
+    passphrase: String,
+}
+
+impl Keystore {
+    pub fn new(dir: impl Into<PathBuf>, passphrase: impl Into<String>) -> Self {
+        Self {
+            dir: dir.into(),
+            passphrase: passphrase.into(),
+        }
+    }
+
+    /// The keystore of the settings, or `None` if no keystore path is set.
+    ///
+    /// ### Errors
+    /// If a keystore path is set without a passphrase.
+    pub fn from_settings() -> Result<Option<Self>, Error> {
+        let settings = get_settings();
+        let Some(dir) = settings.keystore_path.clone() else {
+            return Ok(None);
+        };
+        let passphrase = settings
+            .keystore_passphrase
+            .clone()
+            .filter(|passphrase| !passphrase.is_empty())
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Keystore needs a passphrase"))?;
+
+        Ok(Some(Self::new(dir, passphrase)))
+    }
+
+    fn key_path(&self, key_id: &str) -> Result<PathBuf, Error> {
+        // Key ids are fingerprints, anything else could escape the directory
+        if key_id.len() != 8 || !key_id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::new(ErrorKind::InvalidInput, "Invalid key id"));
+        }
+
+        Ok(self.dir.join(key_id).with_extension(KEY_FILE_EXTENSION))
+    }
+
+    async fn write_key_file(&self, key_file: &KeyFile) -> Result<(), Error> {
+        let path = self.key_path(&key_file.key_id)?;
+        let data = serde_json::to_vec(key_file).map_err(invalid_key_file)?;
+
+        write_file(&path, &data).await
+    }
+
+    /// Encrypt and store the wallet, replacing a stored key of the same seed.
+    /// Returns the key id.
+    ///
+    /// ### Errors
+    /// If the key cannot be encrypted or written, or its key id is taken by
+    /// another master key.
+    pub async fn save(&self, wallet: &GuardianWallet) -> Result<String, Error> {
+        let _guard = GUARDIAN_LOCK.lock().await;
+
+        self.store(wallet).await
+    }
+
+    /// Store the wallet, the caller holds the guardian lock
+    async fn store(&self, wallet: &GuardianWallet) -> Result<String, Error> {
+        fs::create_dir_all(&self.dir).await?;
+        let key_id = wallet.fingerprint().to_string();
+
+        // Fingerprints are only 32 bits, a key file of another master key with
+        // the same fingerprint is never replaced. The same key keeps the
+        // indexes it already handed out.
+        let next_index = match self.read_key_file(&key_id).await? {
+            Some(key_file) => {
+                if key_file.open(&self.passphrase).await?.master_key() != wallet.master_key() {
+                    return Err(Error::new(
+                        ErrorKind::AlreadyExists,
+                        format!("Key id {} is taken by another master key", key_id),
+                    ));
+                }
+                key_file.next_index
+            }
+            None => 0,
+        };
+        let key_file = KeyFile::seal(wallet, &self.passphrase, next_index).await?;
+        self.write_key_file(&key_file).await?;
+
+        Ok(key_file.key_id)
+    }
+
+    async fn read_key_file(&self, key_id: &str) -> Result<Option<KeyFile>, Error> {
+        let data = match fs::read(self.key_path(key_id)?).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let key_file = serde_json::from_slice::<KeyFile>(&data).map_err(invalid_key_file)?;
+        if key_file.key_id != key_id {
+            return Err(invalid_key_file("Key file does not match its key id"));
+        }
+
+        Ok(Some(key_file))
+    }
+
+    /// Load and decrypt a stored key, or `None` if there is no key with the
+    /// id.
+    ///
+    /// ### Errors
+    /// If the key file cannot be read or decrypted with the passphrase.
+    pub async fn load(&self, key_id: &str) -> Result<Option<GuardianWallet>, Error> {
+        match self.read_key_file(key_id).await? {
+            Some(key_file) => key_file.open(&self.passphrase).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Load the guardian key with the given `[fingerprint/path]` origin,
+    /// derived at the index of its path, or `None` if the keystore does not
+    /// hold its master key.
+    ///
+    /// ### Errors
+    /// If the key origin is malformed, the key file cannot be decrypted or
+    /// the derived key is not the expected public key.
+    pub async fn load_guardian(
+        &self,
+        key_origin: &str,
+        public_key: &PublicKey,
+    ) -> Result<Option<GuardianWallet>, Error> {
+        let (key_id, path) = key_origin
+            .strip_prefix('[')
+            .and_then(|origin| origin.strip_suffix(']'))
+            .and_then(|origin| origin.split_once('/'))
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "Invalid key origin"))?;
+        let path = address::parse_derivation_path(&format!("m/{}", path))?;
+        let Some(&ChildNumber::Normal { index }) = path.into_iter().last() else {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Key origin does not end with a receive index",
+            ));
+        };
+
+        let Some(wallet) = self.load(key_id).await? else {
+            return Ok(None);
+        };
+        let wallet = wallet.derive(index)?;
+        if wallet.key_origin() != key_origin || wallet.public_key() != *public_key {
+            return Err(invalid_key_file("Stored key does not match the key origin"));
+        }
+
+        Ok(Some(wallet))
+    }
+
+    /// Store the wallet and make it the guardian master, the key new lockers
+    /// derive their keys from. Returns the key id.
+    ///
+    /// ### Errors
+    /// If the key cannot be encrypted or written, or its key id is taken by
+    /// another master key.
+    pub async fn set_guardian(&self, wallet: &GuardianWallet) -> Result<String, Error> {
+        let _guard = GUARDIAN_LOCK.lock().await;
+        let key_id = self.store(wallet).await?;
+        write_file(&self.dir.join(GUARDIAN_FILE), key_id.as_bytes()).await?;
+
+        Ok(key_id)
+    }
+
+    async fn guardian_key_id(&self) -> Result<Option<String>, Error> {
+        match fs::read_to_string(self.dir.join(GUARDIAN_FILE)).await {
+            Ok(key_id) => Ok(Some(key_id.trim().to_string())),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn guardian_key_file(&self) -> Result<Option<KeyFile>, Error> {
+        let Some(key_id) = self.guardian_key_id().await? else {
+            return Ok(None);
+        };
+
+        self.read_key_file(&key_id)
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "Guardian key file was removed"))
+            .map(Some)
+    }
+
+    /// The guardian master with the next index it hands out, or `None` if no
+    /// guardian was set.
+    ///
+    /// ### Errors
+    /// If the guardian key file cannot be read or decrypted.
+    pub async fn guardian(&self) -> Result<Option<(GuardianWallet, u32)>, Error> {
+        match self.guardian_key_file().await? {
+            Some(key_file) => Ok(Some((
+                key_file.open(&self.passphrase).await?,
+                key_file.next_index,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Reserve the next unused index of the guardian master and return its
+    /// key, or `None` if no guardian was set. The index is recorded before
+    /// the key is returned, so it is never handed out twice.
+    ///
+    /// ### Errors
+    /// If the guardian key file cannot be read, decrypted or updated, or the
+    /// receive chain is exhausted.
+    pub async fn next_guardian(&self) -> Result<Option<GuardianWallet>, Error> {
+        let _guard = GUARDIAN_LOCK.lock().await;
+        let Some(mut key_file) = self.guardian_key_file().await? else {
+            return Ok(None);
+        };
+        let wallet = key_file
+            .open(&self.passphrase)
+            .await?
+            .derive(key_file.next_index)?;
+
+        key_file.next_index += 1;
+        self.write_key_file(&key_file).await?;
+
+        Ok(Some(wallet))
+    }
+
+    /// Ids of the stored keys, sorted.
+    ///
+    /// ### Errors
+    /// If the keystore directory cannot be read.
+    pub async fn list(&self) -> Result<Vec<String>, Error> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut key_ids = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|ext| ext == KEY_FILE_EXTENSION)
+            {
+                if let Some(key_id) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    key_ids.push(key_id.to_string());
+                }
+            }
+        }
+        key_ids.sort();
+
+        Ok(key_ids)
+    }
+
+    /// Delete a stored key. Deleting the guardian master also unsets the
+    /// guardian, new lockers need another one to be set.
+    ///
+    /// ### Errors
+    /// If there is no key with the id or it cannot be removed.
+    pub async fn delete(&self, key_id: &str) -> Result<(), Error> {
+        let _guard = GUARDIAN_LOCK.lock().await;
+        fs::remove_file(self.key_path(key_id)?).await?;
+        if self.guardian_key_id().await?.as_deref() == Some(key_id) {
+            fs::remove_file(self.dir.join(GUARDIAN_FILE)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt every stored key again under the new passphrase.
+    ///
+    /// All keys are decrypted and their new files written aside before any
+    /// is swapped in, so a key that cannot be opened or written leaves the
+    /// keystore untouched. If a file cannot be swapped in, the ones already
+    /// swapped are written back, and the keystore keeps the current
+    /// passphrase.
+    ///
+    /// ### Errors
+    /// If a key cannot be decrypted with the current passphrase or written.
+    /// The error names the key files left under the new passphrase if they
+    /// could not be written back.
+    pub async fn rotate_passphrase(&mut self, new_passphrase: &str) -> Result<(), Error> {
+        let _guard = GUARDIAN_LOCK.lock().await;
+        // New key files with the contents they replace
+        let mut key_files = Vec::new();
+        for key_id in self.list().await? {
+            let key_file = self
+                .read_key_file(&key_id)
+                .await?
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "Key file was removed"))?;
+            let wallet = key_file.open(&self.passphrase).await?;
+            let old_data = serde_json::to_vec(&key_file).map_err(invalid_key_file)?;
+            key_files.push((
+                KeyFile::seal(&wallet, new_passphrase, key_file.next_index).await?,
+                old_data,
+            ));
+        }
+
+        let mut staged = Vec::new();
+        for (key_file, old_data) in &key_files {
+            let path = self.key_path(&key_file.key_id)?;
+            let result = match serde_json::to_vec(key_file) {
+                Ok(data) => stage_file(&path, &data).await,
+                Err(e) => Err(invalid_key_file(e)),
+            };
+            match result {
+                Ok(tmp_path) => staged.push((tmp_path, path, old_data)),
+                Err(e) => {
+                    for (tmp_path, _, _) in &staged {
+                        let _ = fs::remove_file(tmp_path).await;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        for (renamed, (tmp_path, path, _)) in staged.iter().enumerate() {
+            if let Err(e) = fs::rename(tmp_path, path).await {
+                for (tmp_path, _, _) in &staged[renamed..] {
+                    let _ = fs::remove_file(tmp_path).await;
+                }
+                let mut rotated = Vec::new();
+                for (_, path, old_data) in &staged[..renamed] {
+                    if write_file(path, old_data).await.is_err() {
+                        rotated.push(path.display().to_string());
+                    }
+                }
+                if rotated.is_empty() {
+                    return Err(e);
+                }
+                return Err(Error::new(
+                    e.kind(),
+                    format!(
+                        "{}, key files left under the new passphrase: {}",
+                        e,
+                        rotated.join(", ")
+                    ),
+                ));
+            }
+        }
+        // Only switched once every key file is under the new passphrase
+        self.passphrase = new_passphrase.to_string();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystore(passphrase: &str) -> Keystore {
+        let dir = std::env::temp_dir().join(format!(
+            "coinslock-keystore-{}",
+            secret::token_bytes::<8>().to_lower_hex_string()
+        ));
+
+        Keystore::new(dir, passphrase)
+    }
+
+    #[tokio::test]
+    async fn test_keystore_round_trip() {
+        let keystore = keystore("correct horse");
+        assert!(keystore.list().await.unwrap().is_empty());
+
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let key_id = keystore.save(&wallet).await.unwrap();
+        assert_eq!(key_id, wallet.fingerprint().to_string());
+        assert_eq!(keystore.list().await.unwrap(), vec![key_id.clone()]);
+
+        let loaded = keystore.load(&key_id).await.unwrap().unwrap();
+        assert_eq!(loaded.public_key(), wallet.public_key());
+        assert_eq!(loaded.key_origin(), wallet.key_origin());
+        assert!(keystore.load("00000000").await.unwrap().is_none());
+        assert!(keystore.load("../secret").await.is_err());
+
+        let derived = wallet.derive(3).unwrap();
+        let guardian = keystore
+            .load_guardian(&derived.key_origin(), &derived.public_key())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(guardian.public_key(), derived.public_key());
+        assert!(keystore
+            .load_guardian(&derived.key_origin(), &wallet.public_key())
+            .await
+            .is_err());
+        assert!(keystore
+            .load_guardian("00000000/84'/1'/0'/0/0", &wallet.public_key())
+            .await
+            .is_err());
+
+        let wrong = Keystore::new(keystore.dir.clone(), "wrong horse");
+        let error = wrong.load(&key_id).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+
+        keystore.delete(&key_id).await.unwrap();
+        assert!(keystore.list().await.unwrap().is_empty());
+        assert!(keystore.delete(&key_id).await.is_err());
+
+        fs::remove_dir_all(&keystore.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keystore_guardian() {
+        let keystore = keystore("correct horse");
+        assert!(keystore.guardian().await.unwrap().is_none());
+        assert!(keystore.next_guardian().await.unwrap().is_none());
+
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let key_id = keystore.set_guardian(&wallet).await.unwrap();
+        assert_eq!(key_id, wallet.fingerprint().to_string());
+
+        // Every locker gets the key at the next index of the same seed
+        let first = keystore.next_guardian().await.unwrap().unwrap();
+        let second = keystore.next_guardian().await.unwrap().unwrap();
+        assert_eq!(first.public_key(), wallet.derive(0).unwrap().public_key());
+        assert_eq!(second.public_key(), wallet.derive(1).unwrap().public_key());
+        assert_eq!(second.index(), 1);
+        assert_eq!(second.fingerprint(), wallet.fingerprint());
+
+        let (guardian, next_index) = keystore.guardian().await.unwrap().unwrap();
+        assert_eq!(
+            guardian.account_xpub().unwrap(),
+            wallet.account_xpub().unwrap()
+        );
+        assert_eq!(next_index, 2);
+
+        // Deleting the guardian master unsets the guardian
+        keystore.delete(&key_id).await.unwrap();
+        assert!(keystore.guardian().await.unwrap().is_none());
+        assert!(keystore.next_guardian().await.unwrap().is_none());
+
+        fs::remove_dir_all(&keystore.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keystore_key_id_collision() {
+        let keystore = keystore("correct horse");
+        let mut wallet = GuardianWallet::generate_new().await.unwrap();
+        let mnemonic = wallet.take_mnemonic().unwrap();
+        let key_id = keystore.set_guardian(&wallet).await.unwrap();
+        keystore.next_guardian().await.unwrap().unwrap();
+
+        // Saving the same key again keeps its handed out indexes
+        keystore.save(&wallet).await.unwrap();
+        assert_eq!(keystore.guardian().await.unwrap().unwrap().1, 1);
+
+        // Another key with the same key id never replaces the stored one
+        let other = GuardianWallet::from_mnemonic(
+            mnemonic,
+            None,
+            bitcoin::Network::Testnet,
+            &address::parse_derivation_path("m/84'/1'/1'").unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(other.fingerprint(), wallet.fingerprint());
+        let error = keystore.save(&other).await.unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert!(keystore.set_guardian(&other).await.is_err());
+        let loaded = keystore.load(&key_id).await.unwrap().unwrap();
+        assert_eq!(
+            loaded.account_xpub().unwrap(),
+            wallet.account_xpub().unwrap()
+        );
+
+        fs::remove_dir_all(&keystore.dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_keystore_rotate_passphrase() {
+        let mut keystore = keystore("old passphrase");
+        let wallet = GuardianWallet::generate_new().await.unwrap();
+        let key_id = keystore.set_guardian(&wallet).await.unwrap();
+        keystore.next_guardian().await.unwrap().unwrap();
+
+        keystore.rotate_passphrase("new passphrase").await.unwrap();
+        let loaded = keystore.load(&key_id).await.unwrap().unwrap();
+        assert_eq!(loaded.public_key(), wallet.public_key());
+        let next = keystore.next_guardian().await.unwrap().unwrap();
+        assert_eq!(next.index(), 1);
+
+        let old = Keystore::new(keystore.dir.clone(), "old passphrase");
+        assert!(old.load(&key_id).await.is_err());
+
+        fs::remove_dir_all(&keystore.dir).await.unwrap();
+    }
+}
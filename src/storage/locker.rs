@@ -0,0 +1,212 @@
+use std::io::{Error, ErrorKind};
+
+use bitcoin::{Address, Amount, Network, ScriptBuf};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+
+use crate::blockchain::{
+    crypto::HashAlgorithm,
+    descriptor::LockerDescriptor,
+    encoding::{hex_bytes, sat, string},
+    payout::Payout,
+    secret,
+    signer::Signer,
+    transactions::{AddressType, HashLock},
+};
+
+use super::cache::CacheClient;
+
+/// Version of the locker record written by this build.
+pub const LOCKER_VERSION: u32 = 1;
+
+/// Everything needed to rebuild and spend a locker contract after it was
+/// created. The secret itself is never stored, only its hash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Locker {
+    /// Version of the record layout, see [`LOCKER_VERSION`]
+    pub version: u32,
+    pub locker_id: String,
+    #[serde(with = "string")]
+    pub network: Network,
+    pub address_type: AddressType,
+    /// Amount the locker is expected to be funded with
+    #[serde(with = "sat")]
+    pub amount: Amount,
+    pub hash_algorithm: HashAlgorithm,
+    #[serde(with = "hex_bytes")]
+    pub secret_hash: Vec<u8>,
+    #[serde(with = "string")]
+    pub guardian_public_key: PublicKey,
+    /// BIP32 origin of the guardian key, `[fingerprint/path]`, or `None` when
+    /// the key is held by an external signer
+    #[serde(deserialize_with = "Option::deserialize")]
+    pub guardian_key_origin: Option<String>,
+    #[serde(with = "hex_bytes")]
+    pub witness_script: ScriptBuf,
+    pub descriptor: String,
+    /// Outputs the claim pays, empty when the claimer picks the destination
+    pub payouts: Vec<Payout>,
+}
+
+impl Locker {
+    pub fn new(
+        network: Network,
+        address_type: AddressType,
+        amount: Amount,
+        hash_lock: &HashLock,
+        guardian: &dyn Signer,
+        guardian_key_origin: Option<String>,
+        witness_script: ScriptBuf,
+    ) -> Self {
+        let address = address_type.address(&witness_script, network);
+
+        Self {
+            version: LOCKER_VERSION,
+            locker_id: secret::hash_id(address.to_string()),
+            network,
+            address_type,
+            amount,
+            hash_algorithm: hash_lock.algorithm,
+            secret_hash: hash_lock.hash.clone(),
+            guardian_public_key: guardian.public_key(),
+            guardian_key_origin,
+            witness_script,
+            descriptor: LockerDescriptor::hash_lock(hash_lock, guardian.public_key(), address_type)
+                .to_string(),
+            payouts: Vec::new(),
+        }
+    }
+
+    /// The address the locker is funded at.
+    pub fn address(&self) -> Address {
+        self.address_type
+            .address(&self.witness_script, self.network)
+    }
+
+    pub fn hash_lock(&self) -> HashLock {
+        HashLock {
+            algorithm: self.hash_algorithm,
+            hash: self.secret_hash.clone(),
+        }
+    }
+
+    /// Decode a stored locker record.
+    ///
+    /// ### Errors
+    /// If the record is malformed or was written with an unknown version.
+    pub fn from_slice(data: &[u8]) -> Result<Self, Error> {
+        let locker = serde_json::from_slice::<Locker>(data)
+            .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        if locker.version != LOCKER_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported locker version {}", locker.version),
+            ));
+        }
+
+        Ok(locker)
+    }
+}
+
+fn locker_key(locker_id: &str) -> String {
+    format!("locker:{}", locker_id)
+}
+
+/// Store the locker without expiry, so it can be spent at any time later.
+///
+/// ### Errors
+/// If the record cannot be written to the cache.
+pub async fn save_locker(cache: &CacheClient, locker: &Locker) -> Result<(), Error> {
+    let data = serde_json::to_string(locker).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    cache
+        .persist(&locker_key(&locker.locker_id), &data)
+        .await
+        .map_err(Error::other)
+}
+
+/// Load a stored locker, or `None` if there is no locker with the id.
+///
+/// ### Errors
+/// If the cache cannot be read or the record cannot be decoded.
+pub async fn load_locker(cache: &CacheClient, locker_id: &str) -> Result<Option<Locker>, Error> {
+    let data = cache
+        .get(&locker_key(locker_id))
+        .await
+        .map_err(Error::other)?;
+    if data.is_empty() {
+        return Ok(None);
+    }
+
+    Locker::from_slice(&data).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::blockchain::{address::GuardianWallet, transactions};
+
+    async fn locker(address_type: AddressType) -> Locker {
+        let guardian = GuardianWallet::generate_new().await.unwrap();
+        let witness_script = transactions::generate_witness_script(
+            &[7u8; 32],
+            HashAlgorithm::Hash160,
+            guardian.public_key_commitment(),
+            None,
+        )
+        .unwrap();
+
+        Locker::new(
+            Network::Regtest,
+            address_type,
+            Amount::from_sat(50_000),
+            &HashLock::new(HashAlgorithm::Hash160, &[7u8; 32]),
+            &guardian,
+            Some(guardian.key_origin()),
+            witness_script,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_locker_round_trip() {
+        for address_type in [AddressType::P2wsh, AddressType::P2shP2wsh] {
+            let locker = locker(address_type).await;
+            let data = serde_json::to_vec(&locker).unwrap();
+            let decoded = Locker::from_slice(&data).unwrap();
+            assert_eq!(decoded, locker);
+
+            let address = decoded.address();
+            assert_eq!(decoded.locker_id, secret::hash_id(address.to_string()));
+            assert_eq!(
+                LockerDescriptor::from_str(&decoded.descriptor)
+                    .unwrap()
+                    .address(Network::Regtest)
+                    .unwrap(),
+                address
+            );
+            assert!(decoded.hash_lock().is_unlocked_by(&[7u8; 32]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_locker_rejects_unknown_version() {
+        let mut locker = locker(AddressType::P2wsh).await;
+        locker.version = LOCKER_VERSION + 1;
+        let data = serde_json::to_vec(&locker).unwrap();
+
+        let error = Locker::from_slice(&data).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        // The expected amount and the guardian key origin are required
+        locker.version = LOCKER_VERSION;
+        for field in ["amount", "guardian_key_origin"] {
+            let mut record = serde_json::to_value(&locker).unwrap();
+            record.as_object_mut().unwrap().remove(field);
+            let error = Locker::from_slice(record.to_string().as_bytes()).unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::InvalidData);
+        }
+        assert!(Locker::from_slice(b"{\"locker_id\":\"abc\"}").is_err());
+    }
+}
@@ -1 +1,4 @@
 pub mod cache;
+pub mod escrow;
+pub mod keystore;
+pub mod locker;
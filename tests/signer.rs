@@ -0,0 +1,88 @@
+use std::{
+    path::PathBuf,
+    process::{Child, Command},
+    thread,
+    time::{Duration, Instant},
+};
+
+use bitcoin::{hex::DisplayHex, sighash::Prevouts, Amount, FeeRate, Network, OutPoint};
+use coinslock_rust::blockchain::{
+    address::GuardianWallet,
+    crypto::HashAlgorithm,
+    interpreter,
+    payout::Payout,
+    psbt, secret,
+    signer::{ExternalSigner, Signer},
+    transactions::{self, AddressType},
+};
+
+/// The signer binary serving the mnemonic on a new Unix socket.
+fn start_signer(mnemonic: &[String]) -> (Child, PathBuf) {
+    let path = std::env::temp_dir().join(format!(
+        "coinslock-signer-{}.sock",
+        secret::token_bytes::<8>().to_lower_hex_string()
+    ));
+    let child = Command::new(env!("CARGO_BIN_EXE_coinslock-signer"))
+        .env("SIGNER_MNEMONIC", mnemonic.join(" "))
+        .env_remove("SIGNER_PASSPHRASE")
+        .arg("--socket")
+        .arg(&path)
+        .spawn()
+        .unwrap();
+
+    let started = Instant::now();
+    while !path.exists() {
+        assert!(
+            started.elapsed() < Duration::from_secs(10),
+            "Signer did not listen"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    (child, path)
+}
+
+#[tokio::test]
+async fn test_signer_binary_signs_claim() {
+    let mnemonic = secret::generate_secret(&secret::token_bytes::<32>())
+        .await
+        .unwrap();
+    let wallet = GuardianWallet::restore(mnemonic.clone(), None)
+        .await
+        .unwrap();
+    let (mut child, path) = start_signer(&mnemonic);
+
+    let signer = ExternalSigner::connect(&path).unwrap();
+    assert_eq!(signer.public_key(), wallet.public_key());
+
+    // The claim is signed by the signer process and spends the locker
+    let secret = secret::token_bytes::<32>();
+    let witness_script = transactions::generate_witness_script(
+        &secret,
+        HashAlgorithm::Sha256,
+        signer.public_key_commitment(),
+        None,
+    )
+    .unwrap();
+    let mut claim = psbt::create_claim_psbt(
+        OutPoint::null(),
+        Amount::from_sat(50_000),
+        &[Payout::to(&wallet.p2wpkh_address(Network::Regtest))],
+        FeeRate::from_sat_per_vb(2).unwrap(),
+        &secret,
+        witness_script,
+        AddressType::P2wsh,
+    )
+    .unwrap();
+    signer.sign_psbt(&mut claim).unwrap();
+    psbt::finalize_psbt(&mut claim).unwrap();
+
+    let prevout = claim.inputs[0].witness_utxo.clone().unwrap();
+    let tx = claim.extract_tx().unwrap();
+    interpreter::verify_spend(&tx, 0, &Prevouts::One(0, prevout)).unwrap();
+
+    drop(signer);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_file(path).unwrap();
+}